@@ -5,6 +5,39 @@ use tango_matchmaking::server;
 struct Config {
     #[envconfig(from = "LISTEN_ADDR", default = "[::]:1984")]
     pub listen_addr: String,
+
+    /// Serves metrics and the list of live sessions, if set. Keep this private.
+    #[envconfig(from = "ADMIN_LISTEN_ADDR")]
+    pub admin_listen_addr: Option<String>,
+
+    /// Shares sessions with every other node pointed at the same Redis, if set.
+    #[envconfig(from = "REDIS_URL")]
+    pub redis_url: Option<String>,
+
+    #[envconfig(from = "SESSION_TTL_SECS", default = "600")]
+    pub session_ttl_secs: u64,
+
+    #[envconfig(from = "HANDSHAKE_TIMEOUT_SECS", default = "10")]
+    pub handshake_timeout_secs: u64,
+
+    #[envconfig(from = "IDLE_TIMEOUT_SECS", default = "60")]
+    pub idle_timeout_secs: u64,
+
+    #[envconfig(from = "MAX_SESSIONS", default = "10000")]
+    pub max_sessions: usize,
+
+    #[envconfig(from = "MAX_SPECTATORS_PER_SESSION", default = "16")]
+    pub max_spectators_per_session: usize,
+
+    #[envconfig(from = "MAX_CONNECTIONS_PER_IP", default = "30")]
+    pub max_connections_per_ip: usize,
+
+    #[envconfig(from = "RATE_LIMIT_WINDOW_SECS", default = "60")]
+    pub rate_limit_window_secs: u64,
+
+    /// Must be the same on every node sharing sessions. A random one is made up if not set.
+    #[envconfig(from = "SPECTATOR_HOST_SECRET")]
+    pub spectator_host_secret: Option<String>,
 }
 
 #[tokio::main]
@@ -20,7 +53,47 @@ async fn main() -> anyhow::Result<()> {
     );
     let config = Config::init_from_env().unwrap();
     let listener = tokio::net::TcpListener::bind(config.listen_addr).await?;
-    let mut server = server::Server::new(listener);
+    let spectator_host_secret = match config.spectator_host_secret {
+        Some(secret) => secret.into_bytes(),
+        None => {
+            if config.redis_url.is_some() {
+                log::warn!("SPECTATOR_HOST_SECRET is not set, so players may only host spectators through the node they joined through");
+            }
+            rand::random::<[u8; 32]>().to_vec()
+        }
+    };
+    let settings = server::Settings {
+        session_ttl: std::time::Duration::from_secs(config.session_ttl_secs),
+        handshake_timeout: std::time::Duration::from_secs(config.handshake_timeout_secs),
+        idle_timeout: std::time::Duration::from_secs(config.idle_timeout_secs),
+        max_sessions: config.max_sessions,
+        max_spectators_per_session: config.max_spectators_per_session,
+        max_connections_per_ip: config.max_connections_per_ip,
+        rate_limit_window: std::time::Duration::from_secs(config.rate_limit_window_secs),
+        spectator_host_secret,
+    };
+    let mut server = if let Some(redis_url) = config.redis_url {
+        let broker = server::store::RedisBroker::connect(&redis_url).await?;
+        log::info!("sharing sessions through redis");
+        let store = server::store::BrokerStore::new(
+            std::sync::Arc::new(broker),
+            settings.max_sessions,
+            settings.session_ttl,
+        );
+        server::Server::with_store(listener, settings, std::sync::Arc::new(store))
+    } else {
+        server::Server::new(listener, settings)
+    };
+    if let Some(admin_listen_addr) = config.admin_listen_addr {
+        let admin_listen_addr: std::net::SocketAddr = admin_listen_addr.parse()?;
+        let admin = server.admin();
+        log::info!("serving admin endpoints on {}", admin_listen_addr);
+        tokio::spawn(async move {
+            if let Err(e) = admin.serve(&admin_listen_addr).await {
+                log::error!("admin server failed: {}", e);
+            }
+        });
+    }
     server.run().await;
     Ok(())
 }
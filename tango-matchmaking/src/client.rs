@@ -1,5 +1,6 @@
 use super::protocol;
 use futures_util::SinkExt;
+use futures_util::StreamExt;
 use futures_util::TryStreamExt;
 
 #[derive(Eq, PartialEq, Clone, Copy)]
@@ -8,15 +9,134 @@ pub enum ConnectionSide {
     Impolite,
 }
 
+/// The matchmaking server turned us away.
+#[derive(Debug)]
+pub struct ServerError {
+    pub code: protocol::ErrorCode,
+    pub message: String,
+}
+
+impl From<protocol::Error> for ServerError {
+    fn from(error: protocol::Error) -> Self {
+        Self {
+            code: error.code,
+            message: error.message,
+        }
+    }
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "matchmaking server closed the connection: {:?}: {}",
+            self.code, self.message
+        )
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+type Stream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Connects to the matchmaking server and checks that we're both on the same protocol version.
+async fn connect_to_server(addr: &str) -> anyhow::Result<Stream> {
+    let (mut stream, _) = tokio_tungstenite::connect_async(addr).await?;
+
+    stream
+        .send(tokio_tungstenite::tungstenite::Message::Binary(
+            protocol::Hello {
+                protocol_version: protocol::VERSION,
+            }
+            .serialize()?,
+        ))
+        .await?;
+
+    let hello = match stream.try_next().await? {
+        Some(tokio_tungstenite::tungstenite::Message::Binary(d)) => {
+            protocol::Hello::deserialize(&d)?
+        }
+        Some(_) => anyhow::bail!("unexpected message format"),
+        None => anyhow::bail!("stream ended early"),
+    };
+
+    if hello.protocol_version != protocol::VERSION {
+        return Err(ServerError {
+            code: protocol::ErrorCode::ProtocolVersionMismatch,
+            message: format!(
+                "server is on protocol version {:02x}, we're on {:02x}",
+                hello.protocol_version,
+                protocol::VERSION
+            ),
+        }
+        .into());
+    }
+
+    Ok(stream)
+}
+
+/// Forwards local ICE candidates as they're gathered, JSON-encoded. An empty string means gathering is complete.
+async fn trickle_local_candidates(
+    peer_conn: &webrtc::peer_connection::RTCPeerConnection,
+) -> tokio::sync::mpsc::UnboundedReceiver<String> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    peer_conn
+        .on_ice_candidate(Box::new(
+            move |candidate: Option<webrtc::ice_transport::ice_candidate::RTCIceCandidate>| {
+                let tx = tx.clone();
+                Box::pin(async move {
+                    let candidate = match candidate {
+                        Some(candidate) => match candidate
+                            .to_json()
+                            .map_err(anyhow::Error::from)
+                            .and_then(|init| Ok(serde_json::to_string(&init)?))
+                        {
+                            Ok(candidate) => candidate,
+                            Err(e) => {
+                                log::warn!("failed to encode ice candidate: {}", e);
+                                return;
+                            }
+                        },
+                        None => "".to_string(),
+                    };
+                    let _ = tx.send(candidate);
+                })
+            },
+        ))
+        .await;
+    rx
+}
+
+async fn watch_connection_state(
+    peer_conn: &webrtc::peer_connection::RTCPeerConnection,
+) -> tokio::sync::watch::Receiver<
+    webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState,
+> {
+    let (tx, rx) = tokio::sync::watch::channel(
+        webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState::Unspecified,
+    );
+    peer_conn
+        .on_peer_connection_state_change(Box::new(move |state| {
+            let _ = tx.send(state);
+            Box::pin(async {})
+        }))
+        .await;
+    rx
+}
+
+/// Also returns the token to pass to `host_spectators` for this session.
 pub async fn connect<T, F, Fut>(
     addr: &str,
     make_peer_conn: F,
     session_id: &str,
+    advertisement: Option<protocol::Advertisement>,
 ) -> Result<
     (
         webrtc::peer_connection::RTCPeerConnection,
         T,
         ConnectionSide,
+        String,
     ),
     anyhow::Error,
 >
@@ -26,7 +146,8 @@ where
     >,
     F: Fn() -> Fut,
 {
-    let (mut stream, _) = tokio_tungstenite::connect_async(addr).await?;
+    let stream = connect_to_server(addr).await?;
+    let (mut sink, mut stream) = stream.split();
 
     let mut side = ConnectionSide::Polite;
 
@@ -34,40 +155,50 @@ where
 
     let (mut peer_conn, mut r) = make_peer_conn().await?;
 
-    let mut gather_complete = peer_conn.gathering_complete_promise().await;
+    // Candidates are sent as they're gathered rather than waiting for gathering to complete, so we don't get stuck behind a slow STUN server. The server holds on to them until the other side shows up.
+    let mut local_candidates = trickle_local_candidates(&peer_conn).await;
     let offer = peer_conn.create_offer(None).await?;
     peer_conn.set_local_description(offer).await?;
-    gather_complete.recv().await;
 
-    stream
-        .send(tokio_tungstenite::tungstenite::Message::Binary(
-            protocol::Packet::Start(protocol::Start {
-                protocol_version: protocol::VERSION,
-                session_id: session_id.to_string(),
-                offer_sdp: peer_conn.local_description().await.expect("local sdp").sdp,
-            })
-            .serialize()?,
-        ))
-        .await?;
+    sink.send(tokio_tungstenite::tungstenite::Message::Binary(
+        protocol::Packet::Start(protocol::Start {
+            session_id: session_id.to_string(),
+            offer_sdp: peer_conn.local_description().await.expect("local sdp").sdp,
+            advertisement,
+        })
+        .serialize()?,
+    ))
+    .await?;
     log::info!("negotiation start sent");
 
-    match match stream
-        .try_next()
+    let spectator_host_token = match receive_packet(&mut stream)
+        .await?
+        .ok_or(anyhow::format_err!("stream ended early"))?
+    {
+        protocol::Packet::Joined(joined) => joined.spectator_host_token,
+        protocol::Packet::Error(error) => {
+            return Err(ServerError::from(error).into());
+        }
+        p => {
+            anyhow::bail!("unexpected packet: {:?}", p);
+        }
+    };
+
+    match receive_packet(&mut stream)
         .await?
         .ok_or(anyhow::format_err!("stream ended early"))?
     {
-        tokio_tungstenite::tungstenite::Message::Binary(d) => protocol::Packet::deserialize(&d)?,
-        _ => anyhow::bail!("unexpected message format"),
-    } {
         protocol::Packet::Start(_) => {
             anyhow::bail!("unexpected start");
         }
         protocol::Packet::Offer(offer) => {
             log::info!("received an offer, this is the polite side");
 
+            // Any candidates we've sent for the old peer connection are dropped by the server, since we haven't answered yet.
             let (peer_conn2, r2) = make_peer_conn().await?;
             peer_conn = peer_conn2;
             r = r2;
+            local_candidates = trickle_local_candidates(&peer_conn).await;
 
             {
                 let mut sdp = webrtc::peer_connection::sdp::session_description::RTCSessionDescription::default();
@@ -76,19 +207,16 @@ where
                 peer_conn.set_remote_description(sdp).await?;
             }
 
-            let mut gather_complete = peer_conn.gathering_complete_promise().await;
             let offer = peer_conn.create_answer(None).await?;
             peer_conn.set_local_description(offer).await?;
-            gather_complete.recv().await;
-
-            stream
-                .send(tokio_tungstenite::tungstenite::Message::Binary(
-                    protocol::Packet::Answer(protocol::Answer {
-                        sdp: peer_conn.local_description().await.expect("remote sdp").sdp,
-                    })
-                    .serialize()?,
-                ))
-                .await?;
+
+            sink.send(tokio_tungstenite::tungstenite::Message::Binary(
+                protocol::Packet::Answer(protocol::Answer {
+                    sdp: peer_conn.local_description().await.expect("remote sdp").sdp,
+                })
+                .serialize()?,
+            ))
+            .await?;
             log::info!("sent answer to impolite side");
         }
         protocol::Packet::Answer(answer) => {
@@ -104,9 +232,274 @@ where
         protocol::Packet::ICECandidate(_) => {
             anyhow::bail!("unexpected ice candidate");
         }
+        protocol::Packet::Error(error) => {
+            return Err(ServerError::from(error).into());
+        }
+        p => {
+            anyhow::bail!("unexpected packet: {:?}", p);
+        }
+    }
+
+    // Keep trickling until we're connected, or until neither side has any candidates left to send.
+    let mut connection_state = watch_connection_state(&peer_conn).await;
+    let mut local_done = false;
+    let mut remote_done = false;
+    while !local_done || !remote_done {
+        tokio::select! {
+            candidate = local_candidates.recv(), if !local_done => {
+                let candidate = candidate.unwrap_or_default();
+                local_done = candidate.is_empty();
+                if let Err(e) = sink
+                    .send(tokio_tungstenite::tungstenite::Message::Binary(
+                        protocol::Packet::ICECandidate(protocol::ICECandidate {
+                            ice_candidate: candidate,
+                        })
+                        .serialize()?,
+                    ))
+                    .await
+                {
+                    log::warn!("failed to send ice candidate, giving up on trickling: {}", e);
+                    break;
+                }
+            }
+            p = receive_packet(&mut stream), if !remote_done => {
+                match p? {
+                    Some(protocol::Packet::ICECandidate(ice_candidate)) => {
+                        if ice_candidate.ice_candidate.is_empty() {
+                            remote_done = true;
+                            continue;
+                        }
+                        let candidate = serde_json::from_str::<
+                            webrtc::ice_transport::ice_candidate::RTCIceCandidateInit,
+                        >(&ice_candidate.ice_candidate)?;
+                        if let Err(e) = peer_conn.add_ice_candidate(candidate).await {
+                            log::warn!("failed to add ice candidate: {}", e);
+                        }
+                    }
+                    Some(protocol::Packet::Error(error)) => {
+                        return Err(ServerError::from(error).into());
+                    }
+                    Some(p) => {
+                        anyhow::bail!("unexpected packet: {:?}", p);
+                    }
+                    None => {
+                        // The other side may have hung up because it's already connected.
+                        log::info!("signaling ended before all candidates were exchanged");
+                        break;
+                    }
+                }
+            }
+            changed = connection_state.changed() => {
+                if changed.is_err() {
+                    continue;
+                }
+                match *connection_state.borrow() {
+                    webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState::Connected => {
+                        break;
+                    }
+                    webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState::Failed => {
+                        anyhow::bail!("peer connection failed");
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    sink.close().await?;
+
+    Ok((peer_conn, r, side, spectator_host_token))
+}
+
+async fn receive_packet<S>(stream: &mut S) -> anyhow::Result<Option<protocol::Packet>>
+where
+    S: futures_util::Stream<
+            Item = Result<
+                tokio_tungstenite::tungstenite::Message,
+                tokio_tungstenite::tungstenite::Error,
+            >,
+        > + Unpin,
+{
+    Ok(match stream.try_next().await? {
+        Some(tokio_tungstenite::tungstenite::Message::Binary(d)) => {
+            Some(protocol::Packet::deserialize(&d)?)
+        }
+        Some(_) => anyhow::bail!("unexpected message format"),
+        None => None,
+    })
+}
+
+/// Connects to the spectator host for a session as a spectator. The spectator always makes the offer.
+pub async fn spectate<T, Fut>(
+    addr: &str,
+    make_peer_conn: impl FnOnce() -> Fut,
+    session_id: &str,
+) -> anyhow::Result<(webrtc::peer_connection::RTCPeerConnection, T)>
+where
+    Fut: std::future::Future<
+        Output = anyhow::Result<(webrtc::peer_connection::RTCPeerConnection, T)>,
+    >,
+{
+    let mut stream = connect_to_server(addr).await?;
+
+    let (peer_conn, r) = make_peer_conn().await?;
+
+    let mut gather_complete = peer_conn.gathering_complete_promise().await;
+    let offer = peer_conn.create_offer(None).await?;
+    peer_conn.set_local_description(offer).await?;
+    gather_complete.recv().await;
+
+    stream
+        .send(tokio_tungstenite::tungstenite::Message::Binary(
+            protocol::Packet::Spectate(protocol::Spectate {
+                session_id: session_id.to_string(),
+                offer_sdp: peer_conn.local_description().await.expect("local sdp").sdp,
+            })
+            .serialize()?,
+        ))
+        .await?;
+    log::info!("spectate request sent");
+
+    match receive_packet(&mut stream)
+        .await?
+        .ok_or(anyhow::format_err!("stream ended early"))?
+    {
+        protocol::Packet::Answer(answer) => {
+            let mut sdp =
+                webrtc::peer_connection::sdp::session_description::RTCSessionDescription::default();
+            sdp.sdp_type = webrtc::peer_connection::sdp::sdp_type::RTCSdpType::Answer;
+            sdp.sdp = answer.sdp;
+            peer_conn.set_remote_description(sdp).await?;
+        }
+        protocol::Packet::Error(error) => {
+            return Err(ServerError::from(error).into());
+        }
+        p => {
+            anyhow::bail!("unexpected packet: {:?}", p);
+        }
     }
 
     stream.close(None).await?;
 
-    Ok((peer_conn, r, side))
+    Ok((peer_conn, r))
+}
+
+/// Registers as the spectator host for a session. Every spectator offer is passed to `accept`, which should set up a peer connection and return the answer SDP.
+///
+/// This only returns once the signaling connection is closed.
+pub async fn host_spectators<F, Fut>(
+    addr: &str,
+    session_id: &str,
+    spectator_host_token: &str,
+    accept: F,
+) -> anyhow::Result<()>
+where
+    Fut: std::future::Future<Output = anyhow::Result<String>>,
+    F: Fn(String) -> Fut,
+{
+    let mut stream = connect_to_server(addr).await?;
+
+    stream
+        .send(tokio_tungstenite::tungstenite::Message::Binary(
+            protocol::Packet::HostSpectators(protocol::HostSpectators {
+                session_id: session_id.to_string(),
+                spectator_host_token: spectator_host_token.to_string(),
+            })
+            .serialize()?,
+        ))
+        .await?;
+    log::info!("hosting spectators for {}", session_id);
+
+    while let Some(p) = receive_packet(&mut stream).await? {
+        let offer = match p {
+            protocol::Packet::SpectatorOffer(offer) => offer,
+            protocol::Packet::Error(error) => {
+                return Err(ServerError::from(error).into());
+            }
+            p => {
+                anyhow::bail!("unexpected packet: {:?}", p);
+            }
+        };
+
+        let sdp = match accept(offer.sdp).await {
+            Ok(sdp) => sdp,
+            Err(e) => {
+                log::warn!("failed to accept spectator {}: {:?}", offer.spectator_id, e);
+                continue;
+            }
+        };
+
+        stream
+            .send(tokio_tungstenite::tungstenite::Message::Binary(
+                protocol::Packet::SpectatorAnswer(protocol::SpectatorAnswer {
+                    spectator_id: offer.spectator_id,
+                    sdp,
+                })
+                .serialize()?,
+            ))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Lists advertised sessions that are still waiting for a second player.
+pub async fn list_rooms(addr: &str) -> anyhow::Result<Vec<protocol::Room>> {
+    let mut stream = connect_to_server(addr).await?;
+
+    stream
+        .send(tokio_tungstenite::tungstenite::Message::Binary(
+            protocol::Packet::ListRooms(protocol::ListRooms {}).serialize()?,
+        ))
+        .await?;
+
+    let rooms = match receive_packet(&mut stream)
+        .await?
+        .ok_or(anyhow::format_err!("stream ended early"))?
+    {
+        protocol::Packet::Rooms(rooms) => rooms.rooms,
+        protocol::Packet::Error(error) => {
+            return Err(ServerError::from(error).into());
+        }
+        p => {
+            anyhow::bail!("unexpected packet: {:?}", p);
+        }
+    };
+
+    stream.close(None).await?;
+
+    Ok(rooms)
+}
+
+/// Waits in the queue for a random opponent, and returns the session ID to connect with once we have one.
+pub async fn find_opponent(
+    addr: &str,
+    advertisement: protocol::Advertisement,
+) -> anyhow::Result<String> {
+    let mut stream = connect_to_server(addr).await?;
+
+    stream
+        .send(tokio_tungstenite::tungstenite::Message::Binary(
+            protocol::Packet::FindOpponent(protocol::FindOpponent { advertisement }).serialize()?,
+        ))
+        .await?;
+    log::info!("waiting for a random opponent");
+
+    let session_id = match receive_packet(&mut stream)
+        .await?
+        .ok_or(anyhow::format_err!("stream ended early"))?
+    {
+        protocol::Packet::Matched(matched) => matched.session_id,
+        protocol::Packet::Error(error) => {
+            return Err(ServerError::from(error).into());
+        }
+        p => {
+            anyhow::bail!("unexpected packet: {:?}", p);
+        }
+    };
+
+    // The server closes the connection as soon as it sends this, so it's fine if we can't close it cleanly.
+    let _ = stream.close(None).await;
+
+    Ok(session_id)
 }
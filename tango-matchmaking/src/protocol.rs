@@ -1,6 +1,6 @@
 use bincode::Options;
 
-pub const VERSION: u8 = 0x0d;
+pub const VERSION: u8 = 0x13;
 
 lazy_static! {
     static ref BINCODE_OPTIONS: bincode::config::WithOtherLimit<
@@ -14,12 +14,38 @@ lazy_static! {
         .with_limit(128 * 1024);
 }
 
+/// The first message each side sends on a new connection, before any `Packet`. Its shape never changes, so both sides can always tell if they're speaking different versions of everything else.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Hello {
+    pub protocol_version: u8,
+}
+
+impl Hello {
+    pub fn serialize(&self) -> bincode::Result<Vec<u8>> {
+        BINCODE_OPTIONS.serialize(self)
+    }
+
+    pub fn deserialize(d: &[u8]) -> bincode::Result<Self> {
+        BINCODE_OPTIONS.deserialize(d)
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub enum Packet {
     Start(Start),
     Offer(Offer),
     Answer(Answer),
     ICECandidate(ICECandidate),
+    HostSpectators(HostSpectators),
+    Spectate(Spectate),
+    SpectatorOffer(SpectatorOffer),
+    SpectatorAnswer(SpectatorAnswer),
+    Error(Error),
+    ListRooms(ListRooms),
+    Rooms(Rooms),
+    FindOpponent(FindOpponent),
+    Matched(Matched),
+    Joined(Joined),
 }
 
 impl Packet {
@@ -34,9 +60,21 @@ impl Packet {
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Start {
-    pub protocol_version: u8,
     pub session_id: String,
     pub offer_sdp: String,
+    /// If set, the session is listed in the lobby until someone joins it.
+    pub advertisement: Option<Advertisement>,
+}
+
+/// What a player is looking for in an opponent.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Advertisement {
+    /// The first game ID, in sorted order, of the player's compatibility group in games.toml.
+    pub compat_group: String,
+    pub match_type: u16,
+    /// None if the input delay is picked from latency.
+    pub input_delay: Option<u32>,
+    pub nickname: String,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
@@ -49,7 +87,90 @@ pub struct Answer {
     pub sdp: String,
 }
 
+/// A JSON-encoded `RTCIceCandidateInit`, or an empty string once there are no more candidates.
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct ICECandidate {
     pub ice_candidate: String,
 }
+
+/// Sent by the server to each player right after they join a session, before anything else.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Joined {
+    /// Proves we're a player in the session when hosting its spectators later, once we've left it.
+    pub spectator_host_token: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct HostSpectators {
+    pub session_id: String,
+    /// From `Joined`: only the session's players may host its spectators.
+    pub spectator_host_token: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Spectate {
+    pub session_id: String,
+    pub offer_sdp: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SpectatorOffer {
+    pub spectator_id: u32,
+    pub sdp: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SpectatorAnswer {
+    pub spectator_id: u32,
+    pub sdp: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorCode {
+    /// Never sent as a packet, since the other side couldn't read it: the client reports it after comparing hellos.
+    ProtocolVersionMismatch,
+    /// The client sent a packet that doesn't make sense at this point, e.g. an answer without having joined a session.
+    BadRequest,
+    NoSuchSession,
+    /// Both players are already in the session, or it has as many spectators as the server allows.
+    SessionFull,
+    TooManySessions,
+    RateLimited,
+    TimedOut,
+}
+
+/// Sent by the server right before it closes the connection.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Error {
+    pub code: ErrorCode,
+    /// Human-readable details, for logs.
+    pub message: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ListRooms {}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Room {
+    pub session_id: String,
+    pub advertisement: Advertisement,
+    pub age_secs: u64,
+}
+
+/// Advertised sessions that are still waiting for a second player, newest first.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Rooms {
+    pub rooms: Vec<Room>,
+}
+
+/// Waits in the queue for anyone with the same compatibility group and match type.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct FindOpponent {
+    pub advertisement: Advertisement,
+}
+
+/// Sent to both players once they've been paired up. Both should then connect with this session ID as usual.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Matched {
+    pub session_id: String,
+}
@@ -1,47 +1,376 @@
 use super::protocol;
 use futures_util::{SinkExt, StreamExt, TryStreamExt};
+use sha3::Digest;
 
-struct Session {
-    num_clients: usize,
-    offer_sdp: String,
-    sinks: Vec<
-        futures_util::stream::SplitSink<
-            tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
-            tokio_tungstenite::tungstenite::Message,
+mod admin;
+pub mod metrics;
+pub mod store;
+
+pub use admin::Admin;
+
+/// One side makes the offer and the other answers it, so there's no room for anyone else.
+const MAX_CLIENTS_PER_SESSION: usize = 2;
+
+const MAX_ROOMS_LISTED: usize = 100;
+
+/// How many packets may be waiting for someone in a session, e.g. candidates the offering side has trickled before anyone showed up to answer.
+const MAX_PENDING_PACKETS: usize = 64;
+
+pub struct Settings {
+    /// How long a session may wait for its second player before it's dropped.
+    pub session_ttl: std::time::Duration,
+    /// How long a client has to finish the WebSocket handshake and send its first packet.
+    pub handshake_timeout: std::time::Duration,
+    /// How long a player or spectator may go without sending anything once it's paired up. Spectator hosts are exempt, since they only ever answer offers.
+    pub idle_timeout: std::time::Duration,
+    /// Applies to sessions and spectator hosts separately.
+    pub max_sessions: usize,
+    pub max_spectators_per_session: usize,
+    /// How many connections a single IP may open per `rate_limit_window`.
+    pub max_connections_per_ip: usize,
+    pub rate_limit_window: std::time::Duration,
+    /// Signs the tokens that let players host their session's spectators. Nodes sharing sessions need the same one, or players will be turned away when hosting through a different node than they joined through.
+    pub spectator_host_secret: Vec<u8>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            session_ttl: std::time::Duration::from_secs(10 * 60),
+            handshake_timeout: std::time::Duration::from_secs(10),
+            idle_timeout: std::time::Duration::from_secs(60),
+            max_sessions: 10000,
+            max_spectators_per_session: 16,
+            max_connections_per_ip: 30,
+            rate_limit_window: std::time::Duration::from_secs(60),
+            spectator_host_secret: rand::random::<[u8; 32]>().to_vec(),
+        }
+    }
+}
+
+/// What a player in the session gets to host its spectators with, which no one else can come up with without the secret.
+///
+/// It only depends on the session ID, so the store holds on to the IDs of sessions that were played for a while after they end, rather than hand the same token to whoever starts them next.
+fn make_spectator_host_token(secret: &[u8], session_id: &str) -> String {
+    let mut hasher = sha3::Sha3_256::new();
+    hasher.update((secret.len() as u32).to_le_bytes());
+    hasher.update(secret);
+    hasher.update(session_id.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+type Sink = futures_util::stream::SplitSink<
+    tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+    tokio_tungstenite::tungstenite::Message,
+>;
+
+struct SpectatorHost {
+    created_at: std::time::Instant,
+    sink: Sink,
+    next_spectator_id: u32,
+    spectators: std::collections::HashMap<u32, Sink>,
+}
+
+/// A connection that has joined a session as one of its players.
+struct Player {
+    session_id: String,
+    slot: usize,
+    sink: Sink,
+    inbox: tokio::sync::mpsc::Receiver<protocol::Packet>,
+    expires_at: tokio::time::Instant,
+    /// Whether both sides are in the session, as far as we can tell. The offering side finds out when it gets the answer.
+    paired: bool,
+    answered: bool,
+}
+
+enum Event {
+    /// None if the client went quiet for too long.
+    Client(
+        Option<
+            Result<
+                Option<tokio_tungstenite::tungstenite::Message>,
+                tokio_tungstenite::tungstenite::Error,
+            >,
         >,
+    ),
+    /// A packet from the other side of the session, or None once the session is gone.
+    Peer(Option<protocol::Packet>),
+}
+
+struct Waiter {
+    addr: std::net::SocketAddr,
+    matched: tokio::sync::oneshot::Sender<String>,
+}
+
+/// Players waiting for a random opponent, by compatibility group and match type.
+type Queue = std::sync::Arc<
+    tokio::sync::Mutex<
+        std::collections::HashMap<(String, u16), std::collections::VecDeque<Waiter>>,
     >,
+>;
+
+fn make_session_id() -> String {
+    use rand::Rng;
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(20)
+        .map(char::from)
+        .collect::<String>()
+        .to_lowercase()
+}
+
+type SpectatorHosts = std::sync::Arc<
+    tokio::sync::Mutex<
+        std::collections::HashMap<String, std::sync::Arc<tokio::sync::Mutex<SpectatorHost>>>,
+    >,
+>;
+
+struct RateLimiter {
+    window: std::time::Duration,
+    max_connections: usize,
+    connections:
+        std::collections::HashMap<std::net::IpAddr, std::collections::VecDeque<std::time::Instant>>,
+    last_pruned: std::time::Instant,
+}
+
+impl RateLimiter {
+    fn new(window: std::time::Duration, max_connections: usize) -> Self {
+        Self {
+            window,
+            max_connections,
+            connections: std::collections::HashMap::new(),
+            last_pruned: std::time::Instant::now(),
+        }
+    }
+
+    fn allow(&mut self, ip: std::net::IpAddr) -> bool {
+        let now = std::time::Instant::now();
+        let window = self.window;
+
+        if now - self.last_pruned > window {
+            self.connections.retain(|_, connections| {
+                connections
+                    .back()
+                    .map(|t| now - *t <= window)
+                    .unwrap_or(false)
+            });
+            self.last_pruned = now;
+        }
+
+        let connections = self.connections.entry(ip).or_default();
+        while connections
+            .front()
+            .map(|t| now - *t > window)
+            .unwrap_or(false)
+        {
+            connections.pop_front();
+        }
+        if connections.len() >= self.max_connections {
+            return false;
+        }
+        connections.push_back(now);
+        true
+    }
+}
+
+#[derive(Debug)]
+struct Rejected(protocol::ErrorCode);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rejected: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Tells the client why we're closing the connection.
+async fn send_error(sink: &mut Sink, code: protocol::ErrorCode, message: String) {
+    if let Err(e) = async {
+        sink.send(tokio_tungstenite::tungstenite::Message::Binary(
+            protocol::Packet::Error(protocol::Error { code, message }).serialize()?,
+        ))
+        .await?;
+        sink.close().await?;
+        anyhow::Result::<()>::Ok(())
+    }
+    .await
+    {
+        log::warn!("failed to send error: {}", e);
+    }
+}
+
+/// Like `send_error`, but returns the error to end the connection with.
+async fn reject(sink: &mut Sink, code: protocol::ErrorCode, message: &str) -> anyhow::Error {
+    send_error(sink, code, message.to_string()).await;
+    Rejected(code).into()
 }
 
 pub struct Server {
     listener: tokio::net::TcpListener,
-    sessions: std::sync::Arc<
-        tokio::sync::Mutex<
-            std::collections::HashMap<String, std::sync::Arc<tokio::sync::Mutex<Session>>>,
-        >,
-    >,
+    settings: std::sync::Arc<Settings>,
+    rate_limiter: RateLimiter,
+    store: std::sync::Arc<dyn store::SessionStore>,
+    spectator_hosts: SpectatorHosts,
+    queue: Queue,
+    metrics: std::sync::Arc<metrics::Metrics>,
 }
 
 async fn handle_connection(
-    sessions: std::sync::Arc<
-        tokio::sync::Mutex<
-            std::collections::HashMap<String, std::sync::Arc<tokio::sync::Mutex<Session>>>,
-        >,
-    >,
+    store: std::sync::Arc<dyn store::SessionStore>,
+    spectator_hosts: SpectatorHosts,
+    queue: Queue,
+    settings: std::sync::Arc<Settings>,
+    metrics: std::sync::Arc<metrics::Metrics>,
     raw_stream: tokio::net::TcpStream,
     addr: std::net::SocketAddr,
+    rate_limited: bool,
 ) -> anyhow::Result<()> {
-    let (tx, mut rx) = tokio_tungstenite::accept_async(raw_stream).await?.split();
+    let (mut tx, mut rx) = tokio::time::timeout(
+        settings.handshake_timeout,
+        tokio_tungstenite::accept_async(raw_stream),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("timed out during websocket handshake"))??
+    .split();
+
+    let hello = match tokio::time::timeout(settings.handshake_timeout, rx.try_next()).await {
+        Ok(msg) => match msg? {
+            Some(tokio_tungstenite::tungstenite::Message::Binary(d)) => {
+                protocol::Hello::deserialize(&d)?
+            }
+            Some(_) => {
+                anyhow::bail!("unexpected message");
+            }
+            None => {
+                return Ok(());
+            }
+        },
+        Err(_) => {
+            let _ = tx.close().await;
+            return Err(Rejected(protocol::ErrorCode::TimedOut).into());
+        }
+    };
+
+    // Always say hello back, even if the versions don't match: the client can't read anything else we'd send it.
+    tx.send(tokio_tungstenite::tungstenite::Message::Binary(
+        protocol::Hello {
+            protocol_version: protocol::VERSION,
+        }
+        .serialize()?,
+    ))
+    .await?;
+    if !metrics.check_protocol_version(hello.protocol_version) {
+        let _ = tx.close().await;
+        return Err(Rejected(protocol::ErrorCode::ProtocolVersionMismatch).into());
+    }
+
+    if rate_limited {
+        return Err(reject(
+            &mut tx,
+            protocol::ErrorCode::RateLimited,
+            "too many connections from this address",
+        )
+        .await);
+    }
+
     let mut tx = Some(tx);
     let session_id = std::sync::Arc::new(tokio::sync::Mutex::new(None));
-    let mut session = None;
-    let mut me: usize = 0;
+    let mut player: Option<Player> = None;
+    let spectator_host = std::sync::Arc::new(tokio::sync::Mutex::new(None));
+    let spectator_id = std::sync::Arc::new(tokio::sync::Mutex::new(None));
 
     let r = {
-        let sessions = sessions.clone();
+        let tx = &mut tx;
+        let store = store.clone();
         let session_id = session_id.clone();
+        let spectator_hosts = spectator_hosts.clone();
+        let spectator_host = spectator_host.clone();
+        let spectator_id = spectator_id.clone();
         (move || async move {
             loop {
-                let msg = match rx.try_next().await? {
+                let timeout = if let Some(player) = player.as_ref() {
+                    if player.paired {
+                        Some(settings.idle_timeout)
+                    } else {
+                        Some(
+                            player
+                                .expires_at
+                                .saturating_duration_since(tokio::time::Instant::now()),
+                        )
+                    }
+                } else if spectator_host.lock().await.is_some() {
+                    None
+                } else if spectator_id.lock().await.is_some() {
+                    Some(settings.idle_timeout)
+                } else {
+                    Some(settings.handshake_timeout)
+                };
+
+                let event = tokio::select! {
+                    msg = async {
+                        match timeout {
+                            Some(timeout) => {
+                                tokio::time::timeout(timeout, rx.try_next()).await.ok()
+                            }
+                            None => Some(rx.try_next().await),
+                        }
+                    } => Event::Client(msg),
+                    packet = async { player.as_mut().unwrap().inbox.recv().await },
+                        if player.is_some() => Event::Peer(packet),
+                };
+
+                let msg = match event {
+                    Event::Client(Some(msg)) => msg,
+                    Event::Client(None) => {
+                        if let Some(tx) = tx.as_mut() {
+                            return Err(reject(
+                                tx,
+                                protocol::ErrorCode::TimedOut,
+                                "timed out waiting for a packet",
+                            )
+                            .await);
+                        }
+                        if let Some(player) = player.as_mut() {
+                            let message = if player.paired {
+                                "timed out waiting for a packet"
+                            } else {
+                                "timed out waiting for an opponent"
+                            };
+                            return Err(reject(
+                                &mut player.sink,
+                                protocol::ErrorCode::TimedOut,
+                                message,
+                            )
+                            .await);
+                        }
+                        return Err(Rejected(protocol::ErrorCode::TimedOut).into());
+                    }
+                    Event::Peer(Some(packet)) => {
+                        if let Some(player) = player.as_mut() {
+                            if let protocol::Packet::Answer(_) = &packet {
+                                player.paired = true;
+                            }
+                            player
+                                .sink
+                                .send(tokio_tungstenite::tungstenite::Message::Binary(
+                                    packet.serialize()?,
+                                ))
+                                .await?;
+                        }
+                        continue;
+                    }
+                    Event::Peer(None) => {
+                        // The other side left, so there's nothing more to relay.
+                        break;
+                    }
+                };
+
+                let msg = match msg? {
                     Some(tokio_tungstenite::tungstenite::Message::Binary(d)) => {
                         protocol::Packet::deserialize(&d)?
                     }
@@ -55,37 +384,76 @@ async fn handle_connection(
                 log::debug!("received message from {}: {:?}", addr, msg);
                 match msg {
                     protocol::Packet::Start(start) => {
-                        let mut sessions = sessions.lock().await;
-                        session = Some(
-                            sessions
-                                .entry(start.session_id.clone())
-                                .or_insert_with(|| {
-                                    std::sync::Arc::new(tokio::sync::Mutex::new(Session {
-                                        num_clients: 0,
-                                        offer_sdp: start.offer_sdp.clone(),
-                                        sinks: vec![],
-                                    }))
-                                })
-                                .clone(),
-                        );
+                        let mut sink = match tx.take() {
+                            Some(sink) => sink,
+                            None => {
+                                anyhow::bail!("connection is already in use");
+                            }
+                        };
 
-                        let session = session.as_ref().unwrap();
-                        let mut session = session.lock().await;
-                        session.num_clients += 1;
+                        let joined = match store
+                            .join(&start.session_id, &start.offer_sdp, start.advertisement)
+                            .await?
+                        {
+                            Ok(joined) => joined,
+                            Err(store::JoinError::TooManySessions) => {
+                                return Err(reject(
+                                    &mut sink,
+                                    protocol::ErrorCode::TooManySessions,
+                                    "the server has too many sessions",
+                                )
+                                .await);
+                            }
+                            Err(store::JoinError::SessionFull) => {
+                                return Err(reject(
+                                    &mut sink,
+                                    protocol::ErrorCode::SessionFull,
+                                    "both players are already in this session",
+                                )
+                                .await);
+                            }
+                            Err(store::JoinError::SessionEnded) => {
+                                return Err(reject(
+                                    &mut sink,
+                                    protocol::ErrorCode::SessionFull,
+                                    "this session has already been played",
+                                )
+                                .await);
+                            }
+                        };
                         *session_id.lock().await = Some(start.session_id.clone());
-                        let offer_sdp = session.offer_sdp.to_string();
 
-                        me = session.sinks.len();
-                        session.sinks.push(tx.take().unwrap());
+                        sink.send(tokio_tungstenite::tungstenite::Message::Binary(
+                            protocol::Packet::Joined(protocol::Joined {
+                                spectator_host_token: make_spectator_host_token(
+                                    &settings.spectator_host_secret,
+                                    &start.session_id,
+                                ),
+                            })
+                            .serialize()?,
+                        ))
+                        .await?;
 
-                        if me == 1 {
-                            session.sinks[me]
-                                .send(tokio_tungstenite::tungstenite::Message::Binary(
-                                    protocol::Packet::Offer(protocol::Offer { sdp: offer_sdp })
-                                        .serialize()?,
-                                ))
-                                .await?;
+                        // Anything the offering side has already trickled is waiting in our inbox, and gets relayed after this.
+                        if joined.slot == 1 {
+                            sink.send(tokio_tungstenite::tungstenite::Message::Binary(
+                                protocol::Packet::Offer(protocol::Offer {
+                                    sdp: joined.offer_sdp,
+                                })
+                                .serialize()?,
+                            ))
+                            .await?;
                         }
+
+                        player = Some(Player {
+                            session_id: start.session_id,
+                            slot: joined.slot,
+                            sink,
+                            inbox: joined.inbox,
+                            expires_at: tokio::time::Instant::now() + settings.session_ttl,
+                            paired: joined.slot == 1,
+                            answered: false,
+                        });
                     }
                     protocol::Packet::Offer(_) => {
                         anyhow::bail!(
@@ -93,37 +461,298 @@ async fn handle_connection(
                         );
                     }
                     protocol::Packet::Answer(answer) => {
-                        let session = match session.as_ref() {
-                            Some(session) => session,
+                        let player = match player.as_mut() {
+                            Some(player) => player,
                             None => {
                                 anyhow::bail!("no session active");
                             }
                         };
-                        let mut session = session.lock().await;
-                        session.sinks[0]
-                            .send(tokio_tungstenite::tungstenite::Message::Binary(
-                                protocol::Packet::Answer(protocol::Answer { sdp: answer.sdp })
-                                    .serialize()?,
-                            ))
+                        if player.slot != 1 {
+                            anyhow::bail!("received answer from the offering side");
+                        }
+                        store
+                            .send(
+                                &player.session_id,
+                                0,
+                                &protocol::Packet::Answer(protocol::Answer { sdp: answer.sdp }),
+                            )
                             .await?;
+                        player.answered = true;
+                        metrics.exchange_completed();
                     }
                     protocol::Packet::ICECandidate(ice_candidate) => {
-                        let session = match session.as_ref() {
-                            Some(session) => session,
+                        let player = match player.as_ref() {
+                            Some(player) => player,
                             None => {
                                 anyhow::bail!("no session active");
                             }
                         };
-                        let mut session = session.lock().await;
-                        session.sinks[1 - me]
+                        if player.slot == 1 && !player.answered {
+                            // These are for the peer connection the answering side made before it knew it was answering, which it has thrown away.
+                            continue;
+                        }
+                        store
+                            .send(
+                                &player.session_id,
+                                1 - player.slot,
+                                &protocol::Packet::ICECandidate(ice_candidate),
+                            )
+                            .await?;
+                    }
+                    protocol::Packet::HostSpectators(host_spectators) => {
+                        if host_spectators.spectator_host_token
+                            != make_spectator_host_token(
+                                &settings.spectator_host_secret,
+                                &host_spectators.session_id,
+                            )
+                        {
+                            let sink = match tx.as_mut() {
+                                Some(sink) => sink,
+                                None => {
+                                    anyhow::bail!("connection is already in use");
+                                }
+                            };
+                            return Err(reject(
+                                sink,
+                                protocol::ErrorCode::BadRequest,
+                                "only players in this session may host its spectators",
+                            )
+                            .await);
+                        }
+                        let mut spectator_hosts = spectator_hosts.lock().await;
+                        if spectator_hosts.contains_key(&host_spectators.session_id) {
+                            anyhow::bail!("session already has a spectator host");
+                        }
+                        let mut sink = match tx.take() {
+                            Some(sink) => sink,
+                            None => {
+                                anyhow::bail!("connection is already in use");
+                            }
+                        };
+                        if spectator_hosts.len() >= settings.max_sessions {
+                            drop(spectator_hosts);
+                            return Err(reject(
+                                &mut sink,
+                                protocol::ErrorCode::TooManySessions,
+                                "the server has too many sessions",
+                            )
+                            .await);
+                        }
+                        spectator_hosts.insert(
+                            host_spectators.session_id.clone(),
+                            std::sync::Arc::new(tokio::sync::Mutex::new(SpectatorHost {
+                                created_at: std::time::Instant::now(),
+                                sink,
+                                next_spectator_id: 0,
+                                spectators: std::collections::HashMap::new(),
+                            })),
+                        );
+                        *spectator_host.lock().await = Some(host_spectators.session_id);
+                    }
+                    protocol::Packet::Spectate(spectate) => {
+                        let host = match spectator_hosts.lock().await.get(&spectate.session_id) {
+                            Some(host) => host.clone(),
+                            None => {
+                                let sink = match tx.as_mut() {
+                                    Some(sink) => sink,
+                                    None => {
+                                        anyhow::bail!("connection is already in use");
+                                    }
+                                };
+                                return Err(reject(
+                                    sink,
+                                    protocol::ErrorCode::NoSuchSession,
+                                    "no one is hosting spectators for this session",
+                                )
+                                .await);
+                            }
+                        };
+                        let mut sink = match tx.take() {
+                            Some(sink) => sink,
+                            None => {
+                                anyhow::bail!("connection is already in use");
+                            }
+                        };
+                        let mut host = host.lock().await;
+                        if host.spectators.len() >= settings.max_spectators_per_session {
+                            drop(host);
+                            return Err(reject(
+                                &mut sink,
+                                protocol::ErrorCode::SessionFull,
+                                "this session has too many spectators",
+                            )
+                            .await);
+                        }
+                        let id = host.next_spectator_id;
+                        host.next_spectator_id += 1;
+                        host.spectators.insert(id, sink);
+                        *spectator_id.lock().await = Some((spectate.session_id, id));
+                        host.sink
                             .send(tokio_tungstenite::tungstenite::Message::Binary(
-                                protocol::Packet::ICECandidate(protocol::ICECandidate {
-                                    ice_candidate: ice_candidate.ice_candidate,
+                                protocol::Packet::SpectatorOffer(protocol::SpectatorOffer {
+                                    spectator_id: id,
+                                    sdp: spectate.offer_sdp,
                                 })
                                 .serialize()?,
                             ))
                             .await?;
                     }
+                    protocol::Packet::SpectatorAnswer(answer) => {
+                        let host = match &*spectator_host.lock().await {
+                            Some(session_id) => {
+                                spectator_hosts.lock().await.get(session_id).cloned()
+                            }
+                            None => None,
+                        };
+                        let host = match host {
+                            Some(host) => host,
+                            None => {
+                                anyhow::bail!("not hosting spectators");
+                            }
+                        };
+                        let mut host = host.lock().await;
+                        let sink = match host.spectators.get_mut(&answer.spectator_id) {
+                            Some(sink) => sink,
+                            None => {
+                                log::warn!("spectator {} is gone", answer.spectator_id);
+                                continue;
+                            }
+                        };
+                        sink.send(tokio_tungstenite::tungstenite::Message::Binary(
+                            protocol::Packet::Answer(protocol::Answer { sdp: answer.sdp })
+                                .serialize()?,
+                        ))
+                        .await?;
+                        metrics.spectator_exchange_completed();
+                    }
+                    protocol::Packet::SpectatorOffer(_) => {
+                        anyhow::bail!("received spectator offer from client");
+                    }
+                    protocol::Packet::Error(_) => {
+                        anyhow::bail!("received error from client");
+                    }
+                    protocol::Packet::ListRooms(_) => {
+                        let sink = match tx.as_mut() {
+                            Some(sink) => sink,
+                            None => {
+                                anyhow::bail!("connection is already in use");
+                            }
+                        };
+
+                        let mut rooms = store
+                            .sessions()
+                            .await?
+                            .into_iter()
+                            .filter(|session| session.num_clients < MAX_CLIENTS_PER_SESSION)
+                            .filter_map(|session| {
+                                Some(protocol::Room {
+                                    advertisement: session.advertisement?,
+                                    age_secs: session.age.as_secs(),
+                                    session_id: session.session_id,
+                                })
+                            })
+                            .collect::<Vec<_>>();
+                        rooms.sort_by_key(|room| room.age_secs);
+                        rooms.truncate(MAX_ROOMS_LISTED);
+
+                        sink.send(tokio_tungstenite::tungstenite::Message::Binary(
+                            protocol::Packet::Rooms(protocol::Rooms { rooms }).serialize()?,
+                        ))
+                        .await?;
+                    }
+                    protocol::Packet::Rooms(_) => {
+                        anyhow::bail!("received rooms from client");
+                    }
+                    protocol::Packet::FindOpponent(find_opponent) => {
+                        let mut sink = match tx.take() {
+                            Some(sink) => sink,
+                            None => {
+                                anyhow::bail!("connection is already in use");
+                            }
+                        };
+
+                        let key = (
+                            find_opponent.advertisement.compat_group.clone(),
+                            find_opponent.advertisement.match_type,
+                        );
+
+                        let matched_rx = {
+                            let mut queue = queue.lock().await;
+                            let waiters = queue.entry(key.clone()).or_default();
+                            let mut session_id = None;
+                            // Anyone whose receiver is gone has already left the queue.
+                            while let Some(waiter) = waiters.pop_front() {
+                                let id = make_session_id();
+                                if waiter.matched.send(id.clone()).is_ok() {
+                                    session_id = Some(id);
+                                    break;
+                                }
+                            }
+                            match session_id {
+                                Some(session_id) => {
+                                    if waiters.is_empty() {
+                                        queue.remove(&key);
+                                    }
+                                    metrics.random_match();
+                                    Ok(session_id)
+                                }
+                                None => {
+                                    let (matched_tx, matched_rx) =
+                                        tokio::sync::oneshot::channel();
+                                    waiters.push_back(Waiter {
+                                        addr,
+                                        matched: matched_tx,
+                                    });
+                                    Err(matched_rx)
+                                }
+                            }
+                        };
+
+                        let session_id = match matched_rx {
+                            Ok(session_id) => session_id,
+                            Err(matched_rx) => {
+                                let r = tokio::select! {
+                                    r = tokio::time::timeout(settings.session_ttl, matched_rx) => r.ok().and_then(|r| r.ok()),
+                                    // Clients shouldn't send anything while they're queued, so this only returns when the client leaves.
+                                    _ = rx.try_next() => None,
+                                };
+                                if r.is_none() {
+                                    let mut queue = queue.lock().await;
+                                    if let Some(waiters) = queue.get_mut(&key) {
+                                        waiters.retain(|waiter| waiter.addr != addr);
+                                        if waiters.is_empty() {
+                                            queue.remove(&key);
+                                        }
+                                    }
+                                }
+                                match r {
+                                    Some(session_id) => session_id,
+                                    None => {
+                                        return Err(reject(
+                                            &mut sink,
+                                            protocol::ErrorCode::TimedOut,
+                                            "timed out waiting for an opponent",
+                                        )
+                                        .await);
+                                    }
+                                }
+                            }
+                        };
+
+                        sink.send(tokio_tungstenite::tungstenite::Message::Binary(
+                            protocol::Packet::Matched(protocol::Matched { session_id })
+                                .serialize()?,
+                        ))
+                        .await?;
+                        sink.close().await?;
+                        break;
+                    }
+                    protocol::Packet::Matched(_) => {
+                        anyhow::bail!("received matched from client");
+                    }
+                    protocol::Packet::Joined(_) => {
+                        anyhow::bail!("received joined from client");
+                    }
                 }
             }
             Ok(())
@@ -131,20 +760,27 @@ async fn handle_connection(
         .await
     };
 
+    // Anything else that went wrong before the connection was handed off was the client's fault, so let it know why.
+    if let (Err(e), Some(sink)) = (&r, tx.as_mut()) {
+        if e.downcast_ref::<Rejected>().is_none() {
+            send_error(sink, protocol::ErrorCode::BadRequest, e.to_string()).await;
+        }
+    }
+
     if let Some(session_id) = &*session_id.lock().await {
-        let mut sessions = sessions.lock().await;
-        let should_delete = {
-            if let Some(session) = sessions.get(session_id) {
-                let mut session = session.lock().await;
-                session.num_clients -= 1;
-                true
-            } else {
-                false
-            }
-        };
+        if let Err(e) = store.remove(session_id).await {
+            log::warn!("failed to remove session {}: {}", session_id, e);
+        }
+    }
+
+    if let Some(session_id) = &*spectator_host.lock().await {
+        spectator_hosts.lock().await.remove(session_id);
+    }
 
-        if should_delete {
-            sessions.remove(session_id);
+    if let Some((session_id, id)) = &*spectator_id.lock().await {
+        let host = spectator_hosts.lock().await.get(session_id).cloned();
+        if let Some(host) = host {
+            host.lock().await.spectators.remove(id);
         }
     }
 
@@ -152,23 +788,326 @@ async fn handle_connection(
 }
 
 impl Server {
-    pub fn new(listener: tokio::net::TcpListener) -> Server {
+    pub fn new(listener: tokio::net::TcpListener, settings: Settings) -> Server {
+        let store = std::sync::Arc::new(store::MemoryStore::new(settings.max_sessions));
+        Server::with_store(listener, settings, store)
+    }
+
+    /// Keeps sessions in the given store instead of in memory, e.g. so they can be shared with other nodes.
+    pub fn with_store(
+        listener: tokio::net::TcpListener,
+        settings: Settings,
+        store: std::sync::Arc<dyn store::SessionStore>,
+    ) -> Server {
         Server {
             listener,
-            sessions: std::sync::Arc::new(
-                tokio::sync::Mutex::new(std::collections::HashMap::new()),
+            rate_limiter: RateLimiter::new(
+                settings.rate_limit_window,
+                settings.max_connections_per_ip,
             ),
+            settings: std::sync::Arc::new(settings),
+            store,
+            spectator_hosts: std::sync::Arc::new(tokio::sync::Mutex::new(
+                std::collections::HashMap::new(),
+            )),
+            queue: std::sync::Arc::new(tokio::sync::Mutex::new(std::collections::HashMap::new())),
+            metrics: std::sync::Arc::new(metrics::Metrics::new()),
         }
     }
 
+    /// A handle for serving metrics and the list of live sessions, e.g. on a separate admin address.
+    pub fn admin(&self) -> Admin {
+        Admin::new(
+            self.store.clone(),
+            self.spectator_hosts.clone(),
+            self.metrics.clone(),
+        )
+    }
+
     pub async fn run(&mut self) {
         while let Ok((stream, addr)) = self.listener.accept().await {
-            let sessions = self.sessions.clone();
+            let store = self.store.clone();
+            let spectator_hosts = self.spectator_hosts.clone();
+            let queue = self.queue.clone();
+            let settings = self.settings.clone();
+            let metrics = self.metrics.clone();
+            let rate_limited = !self.rate_limiter.allow(addr.ip());
             tokio::spawn(async move {
-                if let Err(e) = handle_connection(sessions, stream, addr).await {
+                metrics.connection_opened();
+                let r = handle_connection(
+                    store,
+                    spectator_hosts,
+                    queue,
+                    settings,
+                    metrics.clone(),
+                    stream,
+                    addr,
+                    rate_limited,
+                )
+                .await;
+                metrics.connection_closed();
+                if let Err(e) = r {
+                    metrics.failure(match e.downcast_ref::<Rejected>() {
+                        Some(Rejected(code)) => metrics::Failure::Rejected(*code),
+                        None => metrics::Failure::Error,
+                    });
                     log::warn!("client {} disconnected with error: {}", addr, e);
                 }
             });
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type ClientStream = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    const SPECTATOR_HOST_SECRET: &[u8] = b"secret";
+
+    /// Starts a node on a free port, returning the address to connect to.
+    async fn start_node(store: std::sync::Arc<dyn store::SessionStore>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = Server::with_store(
+            listener,
+            Settings {
+                spectator_host_secret: SPECTATOR_HOST_SECRET.to_vec(),
+                ..Settings::default()
+            },
+            store,
+        );
+        tokio::spawn(async move { server.run().await });
+        format!("ws://{}", addr)
+    }
+
+    async fn connect(addr: &str) -> ClientStream {
+        let (mut stream, _) = tokio_tungstenite::connect_async(addr).await.unwrap();
+        stream
+            .send(tokio_tungstenite::tungstenite::Message::Binary(
+                protocol::Hello {
+                    protocol_version: protocol::VERSION,
+                }
+                .serialize()
+                .unwrap(),
+            ))
+            .await
+            .unwrap();
+        match stream.try_next().await.unwrap() {
+            Some(tokio_tungstenite::tungstenite::Message::Binary(d)) => {
+                assert_eq!(
+                    protocol::Hello::deserialize(&d).unwrap().protocol_version,
+                    protocol::VERSION
+                );
+            }
+            msg => panic!("expected hello, got {:?}", msg),
+        }
+        stream
+    }
+
+    async fn send(stream: &mut ClientStream, packet: protocol::Packet) {
+        stream
+            .send(tokio_tungstenite::tungstenite::Message::Binary(
+                packet.serialize().unwrap(),
+            ))
+            .await
+            .unwrap();
+    }
+
+    async fn receive(stream: &mut ClientStream) -> protocol::Packet {
+        match tokio::time::timeout(std::time::Duration::from_secs(5), stream.try_next())
+            .await
+            .expect("timed out waiting for a packet")
+            .unwrap()
+        {
+            Some(tokio_tungstenite::tungstenite::Message::Binary(d)) => {
+                protocol::Packet::deserialize(&d).unwrap()
+            }
+            msg => panic!("expected packet, got {:?}", msg),
+        }
+    }
+
+    fn ice_candidate(ice_candidate: &str) -> protocol::Packet {
+        protocol::Packet::ICECandidate(protocol::ICECandidate {
+            ice_candidate: ice_candidate.to_string(),
+        })
+    }
+
+    fn expect_ice_candidate(packet: protocol::Packet, expected: &str) {
+        match packet {
+            protocol::Packet::ICECandidate(ice_candidate) => {
+                assert_eq!(ice_candidate.ice_candidate, expected);
+            }
+            packet => panic!("expected ice candidate, got {:?}", packet),
+        }
+    }
+
+    fn expect_joined(packet: protocol::Packet, session_id: &str) {
+        match packet {
+            protocol::Packet::Joined(joined) => {
+                assert_eq!(
+                    joined.spectator_host_token,
+                    make_spectator_host_token(SPECTATOR_HOST_SECRET, session_id)
+                );
+            }
+            packet => panic!("expected joined, got {:?}", packet),
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_between_nodes() {
+        let store: std::sync::Arc<dyn store::SessionStore> =
+            std::sync::Arc::new(store::BrokerStore::new(
+                std::sync::Arc::new(store::LocalBroker::new()),
+                100,
+                std::time::Duration::from_secs(60),
+            ));
+        let node1 = start_node(store.clone()).await;
+        let node2 = start_node(store).await;
+
+        let mut offerer = connect(&node1).await;
+        send(
+            &mut offerer,
+            protocol::Packet::Start(protocol::Start {
+                session_id: "test".to_string(),
+                offer_sdp: "offer".to_string(),
+                advertisement: None,
+            }),
+        )
+        .await;
+        expect_joined(receive(&mut offerer).await, "test");
+        // Trickled before anyone is there to answer, so it has to wait in the store.
+        send(&mut offerer, ice_candidate("offerer 1")).await;
+
+        let mut answerer = connect(&node2).await;
+        send(
+            &mut answerer,
+            protocol::Packet::Start(protocol::Start {
+                session_id: "test".to_string(),
+                offer_sdp: "unused".to_string(),
+                advertisement: None,
+            }),
+        )
+        .await;
+        expect_joined(receive(&mut answerer).await, "test");
+        match receive(&mut answerer).await {
+            protocol::Packet::Offer(offer) => {
+                assert_eq!(offer.sdp, "offer");
+            }
+            packet => panic!("expected offer, got {:?}", packet),
+        }
+        expect_ice_candidate(receive(&mut answerer).await, "offerer 1");
+
+        send(
+            &mut answerer,
+            protocol::Packet::Answer(protocol::Answer {
+                sdp: "answer".to_string(),
+            }),
+        )
+        .await;
+        match receive(&mut offerer).await {
+            protocol::Packet::Answer(answer) => {
+                assert_eq!(answer.sdp, "answer");
+            }
+            packet => panic!("expected answer, got {:?}", packet),
+        }
+
+        send(&mut answerer, ice_candidate("answerer 1")).await;
+        expect_ice_candidate(receive(&mut offerer).await, "answerer 1");
+
+        send(&mut offerer, ice_candidate("offerer 2")).await;
+        expect_ice_candidate(receive(&mut answerer).await, "offerer 2");
+    }
+
+    #[tokio::test]
+    async fn rejects_start_for_ended_session() {
+        let store: std::sync::Arc<dyn store::SessionStore> =
+            std::sync::Arc::new(store::BrokerStore::new(
+                std::sync::Arc::new(store::LocalBroker::new()),
+                100,
+                std::time::Duration::from_secs(60),
+            ));
+        let node = start_node(store).await;
+
+        let mut offerer = connect(&node).await;
+        send(
+            &mut offerer,
+            protocol::Packet::Start(protocol::Start {
+                session_id: "test".to_string(),
+                offer_sdp: "offer".to_string(),
+                advertisement: None,
+            }),
+        )
+        .await;
+        expect_joined(receive(&mut offerer).await, "test");
+
+        let mut answerer = connect(&node).await;
+        send(
+            &mut answerer,
+            protocol::Packet::Start(protocol::Start {
+                session_id: "test".to_string(),
+                offer_sdp: "unused".to_string(),
+                advertisement: None,
+            }),
+        )
+        .await;
+        expect_joined(receive(&mut answerer).await, "test");
+        match receive(&mut answerer).await {
+            protocol::Packet::Offer(_) => {}
+            packet => panic!("expected offer, got {:?}", packet),
+        }
+
+        // Leaving removes the session, which the answerer hears about.
+        drop(offerer);
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_secs(5), answerer.try_next())
+                .await
+                .expect("timed out waiting for the session to end")
+                .map_or(true, |msg| !matches!(
+                    msg,
+                    Some(tokio_tungstenite::tungstenite::Message::Binary(_))
+                ))
+        );
+
+        let mut latecomer = connect(&node).await;
+        send(
+            &mut latecomer,
+            protocol::Packet::Start(protocol::Start {
+                session_id: "test".to_string(),
+                offer_sdp: "offer".to_string(),
+                advertisement: None,
+            }),
+        )
+        .await;
+        match receive(&mut latecomer).await {
+            protocol::Packet::Error(error) => {
+                assert_eq!(error.code, protocol::ErrorCode::SessionFull);
+            }
+            packet => panic!("expected error, got {:?}", packet),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_spectator_hosts_without_token() {
+        let node = start_node(std::sync::Arc::new(store::MemoryStore::new(100))).await;
+
+        let mut host = connect(&node).await;
+        send(
+            &mut host,
+            protocol::Packet::HostSpectators(protocol::HostSpectators {
+                session_id: "test".to_string(),
+                spectator_host_token: make_spectator_host_token(b"wrong", "test"),
+            }),
+        )
+        .await;
+        match receive(&mut host).await {
+            protocol::Packet::Error(error) => {
+                assert_eq!(error.code, protocol::ErrorCode::BadRequest);
+            }
+            packet => panic!("expected error, got {:?}", packet),
+        }
+    }
+}
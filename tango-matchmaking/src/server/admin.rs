@@ -0,0 +1,135 @@
+use super::metrics;
+use sha3::Digest;
+
+/// Serves `/metrics` for Prometheus and `/sessions` for humans. This should still never be exposed publicly.
+pub struct Admin {
+    store: std::sync::Arc<dyn super::store::SessionStore>,
+    spectator_hosts: super::SpectatorHosts,
+    metrics: std::sync::Arc<metrics::Metrics>,
+    /// Keys the hashes that stand in for session IDs, so they can't be reversed by trying likely link codes.
+    session_hash_key: [u8; 32],
+}
+
+impl Admin {
+    pub(super) fn new(
+        store: std::sync::Arc<dyn super::store::SessionStore>,
+        spectator_hosts: super::SpectatorHosts,
+        metrics: std::sync::Arc<metrics::Metrics>,
+    ) -> Self {
+        Self {
+            store,
+            spectator_hosts,
+            metrics,
+            session_hash_key: rand::random(),
+        }
+    }
+
+    /// Session IDs are as good as passwords, so they're only ever shown as this: enough to tell sessions apart, and to match a session with its spectator host.
+    fn session_hash(&self, session_id: &str) -> String {
+        let mut hasher = sha3::Sha3_256::new();
+        hasher.update(self.session_hash_key);
+        hasher.update(session_id.as_bytes());
+        hasher.finalize()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub async fn serve(self, addr: &std::net::SocketAddr) -> anyhow::Result<()> {
+        let admin = std::sync::Arc::new(self);
+        hyper::Server::try_bind(addr)?
+            .serve(hyper::service::make_service_fn(move |_| {
+                let admin = admin.clone();
+                async move {
+                    Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req| {
+                        let admin = admin.clone();
+                        async move { Ok::<_, std::convert::Infallible>(admin.handle(req).await) }
+                    }))
+                }
+            }))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle(&self, req: hyper::Request<hyper::Body>) -> hyper::Response<hyper::Body> {
+        let r = match (req.method(), req.uri().path()) {
+            (&hyper::Method::GET, "/metrics") => self
+                .render_metrics()
+                .await
+                .map(|body| ("text/plain; version=0.0.4", body)),
+            (&hyper::Method::GET, "/sessions") => self
+                .render_sessions()
+                .await
+                .map(|body| ("text/plain", body)),
+            _ => {
+                return hyper::Response::builder()
+                    .status(hyper::StatusCode::NOT_FOUND)
+                    .body(hyper::Body::empty())
+                    .unwrap();
+            }
+        };
+        let (content_type, body) = match r {
+            Ok(r) => r,
+            Err(e) => {
+                return hyper::Response::builder()
+                    .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(hyper::Body::from(e.to_string()))
+                    .unwrap();
+            }
+        };
+        hyper::Response::builder()
+            .header(hyper::header::CONTENT_TYPE, content_type)
+            .body(hyper::Body::from(body))
+            .unwrap()
+    }
+
+    async fn render_metrics(&self) -> anyhow::Result<String> {
+        let active_sessions = self.store.sessions().await?.len();
+        let active_spectator_hosts = self.spectator_hosts.lock().await.len();
+        Ok(self.metrics.render(active_sessions, active_spectator_hosts))
+    }
+
+    /// One line per session, oldest first.
+    async fn render_sessions(&self) -> anyhow::Result<String> {
+        let sessions = self.store.sessions().await?;
+        let spectator_hosts = self
+            .spectator_hosts
+            .lock()
+            .await
+            .iter()
+            .map(|(id, host)| (id.clone(), host.clone()))
+            .collect::<Vec<_>>();
+
+        let mut rows = vec![];
+        for session in sessions {
+            rows.push((
+                session.age,
+                "session",
+                self.session_hash(&session.session_id),
+                session.num_clients,
+            ));
+        }
+        for (id, host) in spectator_hosts {
+            let host = host.lock().await;
+            rows.push((
+                host.created_at.elapsed(),
+                "spectator_host",
+                self.session_hash(&id),
+                host.spectators.len(),
+            ));
+        }
+        rows.sort_by(|a, b| b.0.cmp(&a.0));
+
+        let mut buf = "kind\tsession_hash\tage_secs\tclients\n".to_string();
+        for (age, kind, id, clients) in rows {
+            buf.push_str(&format!(
+                "{}\t{}\t{}\t{}\n",
+                kind,
+                id,
+                age.as_secs(),
+                clients
+            ));
+        }
+        Ok(buf)
+    }
+}
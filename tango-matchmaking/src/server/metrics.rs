@@ -0,0 +1,193 @@
+use crate::protocol;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+#[derive(Clone, Copy)]
+pub enum Failure {
+    Rejected(protocol::ErrorCode),
+    /// The client sent something it shouldn't have, or the connection broke.
+    Error,
+}
+
+impl Failure {
+    fn label(&self) -> &'static str {
+        match self {
+            Failure::Rejected(protocol::ErrorCode::ProtocolVersionMismatch) => {
+                "protocol_version_mismatch"
+            }
+            Failure::Rejected(protocol::ErrorCode::BadRequest) => "bad_request",
+            Failure::Rejected(protocol::ErrorCode::NoSuchSession) => "no_such_session",
+            Failure::Rejected(protocol::ErrorCode::SessionFull) => "session_full",
+            Failure::Rejected(protocol::ErrorCode::TooManySessions) => "too_many_sessions",
+            Failure::Rejected(protocol::ErrorCode::RateLimited) => "rate_limited",
+            Failure::Rejected(protocol::ErrorCode::TimedOut) => "timed_out",
+            Failure::Error => "error",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    connections_total: AtomicU64,
+    active_connections: AtomicI64,
+    exchanges_completed_total: AtomicU64,
+    spectator_exchanges_completed_total: AtomicU64,
+    random_matches_total: AtomicU64,
+    protocol_version_mismatches_total: AtomicU64,
+    failures_total: std::sync::Mutex<std::collections::BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// An answer made it back to whoever made the offer.
+    pub fn exchange_completed(&self) {
+        self.exchanges_completed_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn spectator_exchange_completed(&self) {
+        self.spectator_exchanges_completed_total
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn random_match(&self) {
+        self.random_matches_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns whether the client is on the same protocol version as us.
+    pub fn check_protocol_version(&self, protocol_version: u8) -> bool {
+        if protocol_version != protocol::VERSION {
+            log::warn!(
+                "client is on protocol version {:02x}, we're on {:02x}",
+                protocol_version,
+                protocol::VERSION
+            );
+            self.protocol_version_mismatches_total
+                .fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    pub fn failure(&self, failure: Failure) {
+        *self
+            .failures_total
+            .lock()
+            .unwrap()
+            .entry(failure.label())
+            .or_insert(0) += 1;
+    }
+
+    /// Renders everything in the Prometheus text exposition format.
+    pub fn render(&self, active_sessions: usize, active_spectator_hosts: usize) -> String {
+        let mut buf = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(String, String)]| {
+            writeln!(buf, "# HELP tango_matchmaking_{} {}", name, help).unwrap();
+            writeln!(buf, "# TYPE tango_matchmaking_{} {}", name, kind).unwrap();
+            for (labels, value) in values {
+                writeln!(buf, "tango_matchmaking_{}{} {}", name, labels, value).unwrap();
+            }
+        };
+
+        metric(
+            "active_sessions",
+            "gauge",
+            "Sessions that are waiting for or pairing up players.",
+            &[("".to_string(), active_sessions.to_string())],
+        );
+        metric(
+            "active_spectator_hosts",
+            "gauge",
+            "Players currently hosting spectators.",
+            &[("".to_string(), active_spectator_hosts.to_string())],
+        );
+        metric(
+            "active_connections",
+            "gauge",
+            "Open signaling connections.",
+            &[(
+                "".to_string(),
+                self.active_connections.load(Ordering::Relaxed).to_string(),
+            )],
+        );
+        metric(
+            "connections_total",
+            "counter",
+            "Signaling connections accepted.",
+            &[(
+                "".to_string(),
+                self.connections_total.load(Ordering::Relaxed).to_string(),
+            )],
+        );
+        metric(
+            "exchanges_completed_total",
+            "counter",
+            "Offer/answer exchanges between two players that completed.",
+            &[(
+                "".to_string(),
+                self.exchanges_completed_total
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            )],
+        );
+        metric(
+            "spectator_exchanges_completed_total",
+            "counter",
+            "Offer/answer exchanges between a spectator and its host that completed.",
+            &[(
+                "".to_string(),
+                self.spectator_exchanges_completed_total
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            )],
+        );
+        metric(
+            "random_matches_total",
+            "counter",
+            "Pairs of players matched from the random opponent queue.",
+            &[(
+                "".to_string(),
+                self.random_matches_total
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            )],
+        );
+        metric(
+            "protocol_version_mismatches_total",
+            "counter",
+            "Clients that connected with a different protocol version than ours.",
+            &[(
+                "".to_string(),
+                self.protocol_version_mismatches_total
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            )],
+        );
+        metric(
+            "failures_total",
+            "counter",
+            "Connections that ended with an error, by reason.",
+            &self
+                .failures_total
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(reason, n)| (format!("{{reason=\"{}\"}}", reason), n.to_string()))
+                .collect::<Vec<_>>(),
+        );
+
+        buf
+    }
+}
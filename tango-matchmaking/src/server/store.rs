@@ -0,0 +1,60 @@
+//! Where player sessions live.
+//!
+//! Only player sessions go through the store. Spectator hosts and the random opponent queue are still kept by each node, so spectators need to reach the same node as their host, and random opponents are only matched within a node.
+
+use crate::protocol;
+
+mod broker;
+mod memory;
+
+pub use broker::{Broker, BrokerStore, LocalBroker, RedisBroker};
+pub use memory::MemoryStore;
+
+/// How long the ID of a session that both players joined stays taken after it ends. Its players hold spectator host tokens for it, so no one else should be able to start it again and be handed one too while they might still be hosting.
+pub const ENDED_SESSION_TTL: std::time::Duration = std::time::Duration::from_secs(2 * 60 * 60);
+
+pub struct Joined {
+    /// 0 for whoever made the offer, 1 for whoever answers it.
+    pub slot: usize,
+    pub offer_sdp: String,
+    /// Packets the other side has sent us, including any sent before we joined. This ends once the session is removed.
+    pub inbox: tokio::sync::mpsc::Receiver<protocol::Packet>,
+}
+
+pub enum JoinError {
+    SessionFull,
+    /// Both players were in the session and it has since been removed.
+    SessionEnded,
+    TooManySessions,
+}
+
+pub struct SessionInfo {
+    pub session_id: String,
+    pub age: std::time::Duration,
+    pub num_clients: usize,
+    pub advertisement: Option<protocol::Advertisement>,
+}
+
+#[async_trait::async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Takes the next free slot in the session, creating it if it doesn't exist yet. `offer_sdp` and `advertisement` are ignored if it already exists.
+    async fn join(
+        &self,
+        session_id: &str,
+        offer_sdp: &str,
+        advertisement: Option<protocol::Advertisement>,
+    ) -> anyhow::Result<Result<Joined, JoinError>>;
+
+    /// Delivers a packet to whoever holds `slot`, or will once they join. Packets for sessions that are gone are dropped.
+    async fn send(
+        &self,
+        session_id: &str,
+        slot: usize,
+        packet: &protocol::Packet,
+    ) -> anyhow::Result<()>;
+
+    /// Ends the session for everyone in it.
+    async fn remove(&self, session_id: &str) -> anyhow::Result<()>;
+
+    async fn sessions(&self) -> anyhow::Result<Vec<SessionInfo>>;
+}
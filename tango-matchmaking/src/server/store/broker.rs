@@ -0,0 +1,321 @@
+use crate::protocol;
+
+mod local;
+mod redis;
+
+pub use self::redis::RedisBroker;
+pub use local::LocalBroker;
+
+/// A key-value store with pub/sub that all nodes share, shaped after what Redis offers.
+#[async_trait::async_trait]
+pub trait Broker: Send + Sync {
+    /// Sets a key only if it doesn't exist yet, returning whether it was set.
+    async fn set_nx(
+        &self,
+        key: &str,
+        value: &[u8],
+        ttl: std::time::Duration,
+    ) -> anyhow::Result<bool>;
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Adds one to a counter that starts at zero, and returns the new value.
+    async fn incr(&self, key: &str, ttl: std::time::Duration) -> anyhow::Result<i64>;
+
+    /// Takes one off a counter that starts at zero, and returns the new value.
+    async fn decr(&self, key: &str) -> anyhow::Result<i64>;
+
+    async fn del(&self, key: &str) -> anyhow::Result<()>;
+
+    /// Appends to a list, and returns its new length.
+    async fn push(
+        &self,
+        key: &str,
+        value: &[u8],
+        ttl: std::time::Duration,
+    ) -> anyhow::Result<usize>;
+
+    /// Removes and returns everything in a list.
+    async fn drain(&self, key: &str) -> anyhow::Result<Vec<Vec<u8>>>;
+
+    /// Adds to a set, where each member expires on its own once `ttl` is up.
+    async fn add_member(
+        &self,
+        key: &str,
+        member: &str,
+        ttl: std::time::Duration,
+    ) -> anyhow::Result<()>;
+
+    async fn remove_member(&self, key: &str, member: &str) -> anyhow::Result<()>;
+
+    async fn members(&self, key: &str) -> anyhow::Result<Vec<String>>;
+
+    /// How many members a set has, without listing them.
+    async fn count_members(&self, key: &str) -> anyhow::Result<usize>;
+
+    async fn publish(&self, channel: &str, payload: &[u8]) -> anyhow::Result<()>;
+
+    /// Receives everything published to the channel after this returns, until the receiver is dropped.
+    async fn subscribe(
+        &self,
+        channel: &str,
+    ) -> anyhow::Result<tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>>;
+}
+
+const SESSIONS_KEY: &str = "tango:sessions";
+
+/// Published on a slot's channel when the session is removed. Anything else means there's something in the slot's inbox.
+const GONE: &[u8] = b"gone";
+
+fn session_key(session_id: &str) -> String {
+    format!("tango:session:{}", session_id)
+}
+
+fn answerers_key(session_id: &str) -> String {
+    format!("tango:session:{}:answerers", session_id)
+}
+
+/// Set when a session that both players joined is removed, so its ID can't be started again for a while.
+fn ended_key(session_id: &str) -> String {
+    format!("tango:session:{}:ended", session_id)
+}
+
+fn inbox_key(session_id: &str, slot: usize) -> String {
+    format!("tango:session:{}:inbox:{}", session_id, slot)
+}
+
+fn notify_channel(session_id: &str, slot: usize) -> String {
+    format!("tango:session:{}:notify:{}", session_id, slot)
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Record {
+    offer_sdp: String,
+    advertisement: Option<protocol::Advertisement>,
+    created_at_millis: u64,
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Keeps sessions in a `Broker`, so the two sides of a session can be connected to different nodes.
+///
+/// Each slot has an inbox list that the other side pushes to, followed by a notification on the slot's channel. Whoever holds the slot drains the inbox whenever they're notified, so nothing is lost if it's sent before they've joined.
+pub struct BrokerStore {
+    broker: std::sync::Arc<dyn Broker>,
+    max_sessions: usize,
+    session_ttl: std::time::Duration,
+}
+
+impl BrokerStore {
+    pub fn new(
+        broker: std::sync::Arc<dyn Broker>,
+        max_sessions: usize,
+        session_ttl: std::time::Duration,
+    ) -> Self {
+        Self {
+            broker,
+            max_sessions,
+            session_ttl,
+        }
+    }
+
+    /// Drops sessions from the index whose records are gone, e.g. because they were removed while being listed. Returns the ones that are left.
+    async fn live_sessions(&self) -> anyhow::Result<Vec<(String, Record)>> {
+        let mut sessions = vec![];
+        for session_id in self.broker.members(SESSIONS_KEY).await? {
+            match self.broker.get(&session_key(&session_id)).await? {
+                Some(record) => {
+                    sessions.push((session_id, serde_json::from_slice(&record)?));
+                }
+                None => {
+                    self.broker.remove_member(SESSIONS_KEY, &session_id).await?;
+                }
+            }
+        }
+        Ok(sessions)
+    }
+}
+
+async fn pump(
+    broker: std::sync::Arc<dyn Broker>,
+    inbox_key: String,
+    mut notifications: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+    inbox: tokio::sync::mpsc::Sender<protocol::Packet>,
+) -> anyhow::Result<()> {
+    loop {
+        for d in broker.drain(&inbox_key).await? {
+            if inbox
+                .send(protocol::Packet::deserialize(&d)?)
+                .await
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+
+        tokio::select! {
+            notification = notifications.recv() => {
+                match notification {
+                    Some(payload) if payload != GONE => {}
+                    _ => {
+                        return Ok(());
+                    }
+                }
+            }
+            _ = inbox.closed() => {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl super::SessionStore for BrokerStore {
+    async fn join(
+        &self,
+        session_id: &str,
+        offer_sdp: &str,
+        advertisement: Option<protocol::Advertisement>,
+    ) -> anyhow::Result<Result<super::Joined, super::JoinError>> {
+        let key = session_key(session_id);
+
+        if self.broker.get(&ended_key(session_id)).await?.is_some() {
+            return Ok(Err(super::JoinError::SessionEnded));
+        }
+
+        // This can let a few too many sessions through if they're created at the same time, which is fine.
+        if self.broker.get(&key).await?.is_none()
+            && self.broker.count_members(SESSIONS_KEY).await? >= self.max_sessions
+        {
+            return Ok(Err(super::JoinError::TooManySessions));
+        }
+
+        let created = self
+            .broker
+            .set_nx(
+                &key,
+                &serde_json::to_vec(&Record {
+                    offer_sdp: offer_sdp.to_string(),
+                    advertisement,
+                    created_at_millis: now_millis(),
+                })?,
+                self.session_ttl,
+            )
+            .await?;
+
+        let (slot, offer_sdp) = if created {
+            // The member expires along with the record, so sessions on nodes that went away don't count against the limit forever.
+            self.broker
+                .add_member(SESSIONS_KEY, session_id, self.session_ttl)
+                .await?;
+            (0, offer_sdp.to_string())
+        } else {
+            let answerers = self
+                .broker
+                .incr(&answerers_key(session_id), self.session_ttl)
+                .await?;
+            if answerers as usize >= crate::server::MAX_CLIENTS_PER_SESSION {
+                // We didn't get a slot, so don't count against the session.
+                self.broker.decr(&answerers_key(session_id)).await?;
+                return Ok(Err(super::JoinError::SessionFull));
+            }
+            let record: Record = match self.broker.get(&key).await? {
+                Some(record) => serde_json::from_slice(&record)?,
+                None => {
+                    anyhow::bail!("session went away while joining");
+                }
+            };
+            (answerers as usize, record.offer_sdp)
+        };
+
+        // Subscribe before draining for the first time, so we can't miss a notification in between.
+        let notifications = self
+            .broker
+            .subscribe(&notify_channel(session_id, slot))
+            .await?;
+        let (tx, rx) = tokio::sync::mpsc::channel(crate::server::MAX_PENDING_PACKETS);
+        let broker = self.broker.clone();
+        let inbox_key = inbox_key(session_id, slot);
+        tokio::spawn(async move {
+            if let Err(e) = pump(broker, inbox_key.clone(), notifications, tx).await {
+                log::warn!("failed to receive packets for {}: {}", inbox_key, e);
+            }
+        });
+
+        Ok(Ok(super::Joined {
+            slot,
+            offer_sdp,
+            inbox: rx,
+        }))
+    }
+
+    async fn send(
+        &self,
+        session_id: &str,
+        slot: usize,
+        packet: &protocol::Packet,
+    ) -> anyhow::Result<()> {
+        let pending = self
+            .broker
+            .push(
+                &inbox_key(session_id, slot),
+                &packet.serialize()?,
+                self.session_ttl,
+            )
+            .await?;
+        if pending > crate::server::MAX_PENDING_PACKETS {
+            anyhow::bail!("too many pending packets");
+        }
+        self.broker
+            .publish(&notify_channel(session_id, slot), b"")
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, session_id: &str) -> anyhow::Result<()> {
+        // Mark it ended before the record goes, so no one can start it again in between.
+        let answered = match self.broker.get(&answerers_key(session_id)).await? {
+            Some(answerers) => String::from_utf8(answerers)?.parse::<i64>()? > 0,
+            None => false,
+        };
+        if answered {
+            self.broker
+                .set_nx(&ended_key(session_id), b"", super::ENDED_SESSION_TTL)
+                .await?;
+        }
+        self.broker.del(&session_key(session_id)).await?;
+        self.broker.del(&answerers_key(session_id)).await?;
+        self.broker.remove_member(SESSIONS_KEY, session_id).await?;
+        for slot in 0..crate::server::MAX_CLIENTS_PER_SESSION {
+            self.broker.del(&inbox_key(session_id, slot)).await?;
+            self.broker
+                .publish(&notify_channel(session_id, slot), GONE)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn sessions(&self) -> anyhow::Result<Vec<super::SessionInfo>> {
+        let now = now_millis();
+        let mut sessions = vec![];
+        for (session_id, record) in self.live_sessions().await? {
+            let answerers = match self.broker.get(&answerers_key(&session_id)).await? {
+                Some(answerers) => String::from_utf8(answerers)?.parse::<usize>()?,
+                None => 0,
+            };
+            let age_millis = now.saturating_sub(record.created_at_millis);
+            sessions.push(super::SessionInfo {
+                age: std::time::Duration::from_millis(age_millis),
+                num_clients: (1 + answerers).min(crate::server::MAX_CLIENTS_PER_SESSION),
+                advertisement: record.advertisement,
+                session_id,
+            });
+        }
+        Ok(sessions)
+    }
+}
@@ -0,0 +1,264 @@
+enum Value {
+    Bytes(Vec<u8>),
+    List(std::collections::VecDeque<Vec<u8>>),
+    /// Members and when they expire.
+    Set(std::collections::BTreeMap<String, std::time::Instant>),
+}
+
+struct Entry {
+    value: Value,
+    expires_at: Option<std::time::Instant>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: std::collections::HashMap<String, Entry>,
+    subscribers:
+        std::collections::HashMap<String, Vec<tokio::sync::mpsc::UnboundedSender<Vec<u8>>>>,
+}
+
+impl State {
+    /// The members of a set that haven't expired yet.
+    fn set(
+        &mut self,
+        key: &str,
+    ) -> anyhow::Result<Option<&mut std::collections::BTreeMap<String, std::time::Instant>>> {
+        let now = std::time::Instant::now();
+        match self.entry(key) {
+            Some(Entry {
+                value: Value::Set(set),
+                ..
+            }) => {
+                set.retain(|_, expires_at| *expires_at > now);
+                Ok(Some(set))
+            }
+            Some(_) => anyhow::bail!("{} is not a set", key),
+            None => Ok(None),
+        }
+    }
+
+    fn entry(&mut self, key: &str) -> Option<&mut Entry> {
+        let now = std::time::Instant::now();
+        if self
+            .entries
+            .get(key)
+            .and_then(|entry| entry.expires_at)
+            .map(|expires_at| expires_at <= now)
+            .unwrap_or(false)
+        {
+            self.entries.remove(key);
+        }
+        self.entries.get_mut(key)
+    }
+}
+
+/// A `Broker` that lives in this process, for running several servers side by side without a real broker.
+#[derive(Default)]
+pub struct LocalBroker {
+    state: std::sync::Mutex<State>,
+}
+
+impl LocalBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Broker for LocalBroker {
+    async fn set_nx(
+        &self,
+        key: &str,
+        value: &[u8],
+        ttl: std::time::Duration,
+    ) -> anyhow::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        if state.entry(key).is_some() {
+            return Ok(false);
+        }
+        state.entries.insert(
+            key.to_string(),
+            Entry {
+                value: Value::Bytes(value.to_vec()),
+                expires_at: Some(std::time::Instant::now() + ttl),
+            },
+        );
+        Ok(true)
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.state.lock().unwrap().entry(key) {
+            Some(Entry {
+                value: Value::Bytes(value),
+                ..
+            }) => Ok(Some(value.clone())),
+            Some(_) => anyhow::bail!("{} is not a plain value", key),
+            None => Ok(None),
+        }
+    }
+
+    async fn incr(&self, key: &str, ttl: std::time::Duration) -> anyhow::Result<i64> {
+        let mut state = self.state.lock().unwrap();
+        let n = match state.entry(key) {
+            Some(Entry {
+                value: Value::Bytes(value),
+                ..
+            }) => std::str::from_utf8(value)?.parse::<i64>()? + 1,
+            Some(_) => anyhow::bail!("{} is not a plain value", key),
+            None => 1,
+        };
+        state.entries.insert(
+            key.to_string(),
+            Entry {
+                value: Value::Bytes(n.to_string().into_bytes()),
+                expires_at: Some(std::time::Instant::now() + ttl),
+            },
+        );
+        Ok(n)
+    }
+
+    async fn decr(&self, key: &str) -> anyhow::Result<i64> {
+        let mut state = self.state.lock().unwrap();
+        match state.entry(key) {
+            Some(Entry {
+                value: Value::Bytes(value),
+                ..
+            }) => {
+                let n = std::str::from_utf8(value)?.parse::<i64>()? - 1;
+                *value = n.to_string().into_bytes();
+                Ok(n)
+            }
+            Some(_) => anyhow::bail!("{} is not a plain value", key),
+            None => {
+                state.entries.insert(
+                    key.to_string(),
+                    Entry {
+                        value: Value::Bytes(b"-1".to_vec()),
+                        expires_at: None,
+                    },
+                );
+                Ok(-1)
+            }
+        }
+    }
+
+    async fn del(&self, key: &str) -> anyhow::Result<()> {
+        self.state.lock().unwrap().entries.remove(key);
+        Ok(())
+    }
+
+    async fn push(
+        &self,
+        key: &str,
+        value: &[u8],
+        ttl: std::time::Duration,
+    ) -> anyhow::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.entry(key).is_none() {
+            state.entries.insert(
+                key.to_string(),
+                Entry {
+                    value: Value::List(std::collections::VecDeque::new()),
+                    expires_at: None,
+                },
+            );
+        }
+        let entry = state.entries.get_mut(key).unwrap();
+        entry.expires_at = Some(std::time::Instant::now() + ttl);
+        match &mut entry.value {
+            Value::List(list) => {
+                list.push_back(value.to_vec());
+                Ok(list.len())
+            }
+            _ => anyhow::bail!("{} is not a list", key),
+        }
+    }
+
+    async fn drain(&self, key: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        match state.entry(key) {
+            Some(Entry {
+                value: Value::List(_),
+                ..
+            }) => {}
+            Some(_) => anyhow::bail!("{} is not a list", key),
+            None => {
+                return Ok(vec![]);
+            }
+        }
+        match state.entries.remove(key).map(|entry| entry.value) {
+            Some(Value::List(list)) => Ok(list.into_iter().collect()),
+            _ => unreachable!(),
+        }
+    }
+
+    async fn add_member(
+        &self,
+        key: &str,
+        member: &str,
+        ttl: std::time::Duration,
+    ) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let entry = state
+            .entries
+            .entry(key.to_string())
+            .or_insert_with(|| Entry {
+                value: Value::Set(std::collections::BTreeMap::new()),
+                expires_at: None,
+            });
+        match &mut entry.value {
+            Value::Set(set) => {
+                set.insert(member.to_string(), std::time::Instant::now() + ttl);
+                Ok(())
+            }
+            _ => anyhow::bail!("{} is not a set", key),
+        }
+    }
+
+    async fn remove_member(&self, key: &str, member: &str) -> anyhow::Result<()> {
+        if let Some(set) = self.state.lock().unwrap().set(key)? {
+            set.remove(member);
+        }
+        Ok(())
+    }
+
+    async fn members(&self, key: &str) -> anyhow::Result<Vec<String>> {
+        Ok(match self.state.lock().unwrap().set(key)? {
+            Some(set) => set.keys().cloned().collect(),
+            None => vec![],
+        })
+    }
+
+    async fn count_members(&self, key: &str) -> anyhow::Result<usize> {
+        Ok(match self.state.lock().unwrap().set(key)? {
+            Some(set) => set.len(),
+            None => 0,
+        })
+    }
+
+    async fn publish(&self, channel: &str, payload: &[u8]) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(subscribers) = state.subscribers.get_mut(channel) {
+            subscribers.retain(|subscriber| subscriber.send(payload.to_vec()).is_ok());
+            if subscribers.is_empty() {
+                state.subscribers.remove(channel);
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        channel: &str,
+    ) -> anyhow::Result<tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        self.state
+            .lock()
+            .unwrap()
+            .subscribers
+            .entry(channel.to_string())
+            .or_default()
+            .push(tx);
+        Ok(rx)
+    }
+}
@@ -0,0 +1,271 @@
+use futures_util::StreamExt;
+
+/// Every channel the store publishes to starts with this, so one pattern subscription covers all of them.
+const CHANNEL_PREFIX: &str = "tango:";
+
+const PUBSUB_RECONNECT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+type Messages = std::pin::Pin<Box<dyn futures_util::Stream<Item = redis::Msg> + Send>>;
+
+#[derive(Default)]
+struct Subscriptions {
+    /// Unset while the pubsub connection is down, since nothing published then would reach us.
+    connected: bool,
+    senders: std::collections::HashMap<String, Vec<tokio::sync::mpsc::UnboundedSender<Vec<u8>>>>,
+}
+
+pub struct RedisBroker {
+    conn: redis::aio::MultiplexedConnection,
+    subscriptions: std::sync::Arc<std::sync::Mutex<Subscriptions>>,
+}
+
+impl RedisBroker {
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = client.get_multiplexed_tokio_connection().await?;
+        let messages = psubscribe(&client).await?;
+        let subscriptions = std::sync::Arc::new(std::sync::Mutex::new(Subscriptions {
+            connected: true,
+            ..Default::default()
+        }));
+        tokio::spawn(dispatch(client, messages, subscriptions.clone()));
+        Ok(Self {
+            conn,
+            subscriptions,
+        })
+    }
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Subscribing takes over the whole connection, so all subscriptions share one pattern subscription on a connection of its own.
+async fn psubscribe(client: &redis::Client) -> anyhow::Result<Messages> {
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+    pubsub.psubscribe(format!("{}*", CHANNEL_PREFIX)).await?;
+    Ok(Box::pin(pubsub.into_on_message()))
+}
+
+/// Hands each message to whoever subscribed to its channel, reconnecting if the connection drops.
+async fn dispatch(
+    client: redis::Client,
+    mut messages: Messages,
+    subscriptions: std::sync::Arc<std::sync::Mutex<Subscriptions>>,
+) {
+    loop {
+        while let Some(msg) = messages.next().await {
+            let channel = msg.get_channel_name();
+            let payload = match msg.get_payload::<Vec<u8>>() {
+                Ok(payload) => payload,
+                Err(e) => {
+                    log::warn!("bad message on {}: {}", channel, e);
+                    continue;
+                }
+            };
+            let mut subscriptions = subscriptions.lock().unwrap();
+            if let Some(senders) = subscriptions.senders.get_mut(channel) {
+                senders.retain(|tx| tx.send(payload.clone()).is_ok());
+                if senders.is_empty() {
+                    subscriptions.senders.remove(channel);
+                }
+            }
+        }
+
+        log::warn!("lost pubsub connection, reconnecting");
+        {
+            // Anything published from here on is lost, so end every subscription rather than leave them waiting.
+            let mut subscriptions = subscriptions.lock().unwrap();
+            subscriptions.connected = false;
+            subscriptions.senders.clear();
+        }
+        messages = loop {
+            tokio::time::sleep(PUBSUB_RECONNECT_INTERVAL).await;
+            match psubscribe(&client).await {
+                Ok(messages) => break messages,
+                Err(e) => log::warn!("failed to reconnect pubsub: {}", e),
+            }
+        };
+        subscriptions.lock().unwrap().connected = true;
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Broker for RedisBroker {
+    async fn set_nx(
+        &self,
+        key: &str,
+        value: &[u8],
+        ttl: std::time::Duration,
+    ) -> anyhow::Result<bool> {
+        let r: Option<String> = redis::cmd("SET")
+            .arg(key)
+            .arg(value)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(r.is_some())
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(redis::cmd("GET")
+            .arg(key)
+            .query_async(&mut self.conn.clone())
+            .await?)
+    }
+
+    async fn incr(&self, key: &str, ttl: std::time::Duration) -> anyhow::Result<i64> {
+        let (n,): (i64,) = redis::pipe()
+            .atomic()
+            .cmd("INCR")
+            .arg(key)
+            .cmd("PEXPIRE")
+            .arg(key)
+            .arg(ttl.as_millis() as u64)
+            .ignore()
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(n)
+    }
+
+    async fn decr(&self, key: &str) -> anyhow::Result<i64> {
+        Ok(redis::cmd("DECR")
+            .arg(key)
+            .query_async(&mut self.conn.clone())
+            .await?)
+    }
+
+    async fn del(&self, key: &str) -> anyhow::Result<()> {
+        redis::cmd("DEL")
+            .arg(key)
+            .query_async::<_, ()>(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn push(
+        &self,
+        key: &str,
+        value: &[u8],
+        ttl: std::time::Duration,
+    ) -> anyhow::Result<usize> {
+        let (n,): (usize,) = redis::pipe()
+            .atomic()
+            .cmd("RPUSH")
+            .arg(key)
+            .arg(value)
+            .cmd("PEXPIRE")
+            .arg(key)
+            .arg(ttl.as_millis() as u64)
+            .ignore()
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(n)
+    }
+
+    async fn drain(&self, key: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+        let (values,): (Vec<Vec<u8>>,) = redis::pipe()
+            .atomic()
+            .cmd("LRANGE")
+            .arg(key)
+            .arg(0)
+            .arg(-1)
+            .cmd("DEL")
+            .arg(key)
+            .ignore()
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(values)
+    }
+
+    // Sets are sorted sets scored by when each member expires, which gets pruned before it's read.
+    async fn add_member(
+        &self,
+        key: &str,
+        member: &str,
+        ttl: std::time::Duration,
+    ) -> anyhow::Result<()> {
+        redis::cmd("ZADD")
+            .arg(key)
+            .arg(now_millis() + ttl.as_millis() as u64)
+            .arg(member)
+            .query_async::<_, ()>(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_member(&self, key: &str, member: &str) -> anyhow::Result<()> {
+        redis::cmd("ZREM")
+            .arg(key)
+            .arg(member)
+            .query_async::<_, ()>(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn members(&self, key: &str) -> anyhow::Result<Vec<String>> {
+        let (members,): (Vec<String>,) = redis::pipe()
+            .atomic()
+            .cmd("ZREMRANGEBYSCORE")
+            .arg(key)
+            .arg("-inf")
+            .arg(now_millis())
+            .ignore()
+            .cmd("ZRANGE")
+            .arg(key)
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(members)
+    }
+
+    async fn count_members(&self, key: &str) -> anyhow::Result<usize> {
+        let (n,): (usize,) = redis::pipe()
+            .atomic()
+            .cmd("ZREMRANGEBYSCORE")
+            .arg(key)
+            .arg("-inf")
+            .arg(now_millis())
+            .ignore()
+            .cmd("ZCARD")
+            .arg(key)
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(n)
+    }
+
+    async fn publish(&self, channel: &str, payload: &[u8]) -> anyhow::Result<()> {
+        redis::cmd("PUBLISH")
+            .arg(channel)
+            .arg(payload)
+            .query_async::<_, ()>(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        channel: &str,
+    ) -> anyhow::Result<tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>> {
+        if !channel.starts_with(CHANNEL_PREFIX) {
+            anyhow::bail!("{} is not under {}", channel, CHANNEL_PREFIX);
+        }
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if !subscriptions.connected {
+            anyhow::bail!("not connected to pubsub");
+        }
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        subscriptions
+            .senders
+            .entry(channel.to_string())
+            .or_default()
+            .push(tx);
+        Ok(rx)
+    }
+}
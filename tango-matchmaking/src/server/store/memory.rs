@@ -0,0 +1,131 @@
+use crate::protocol;
+
+struct Session {
+    created_at: std::time::Instant,
+    offer_sdp: String,
+    advertisement: Option<protocol::Advertisement>,
+    num_clients: usize,
+    inboxes: Vec<(
+        tokio::sync::mpsc::Sender<protocol::Packet>,
+        Option<tokio::sync::mpsc::Receiver<protocol::Packet>>,
+    )>,
+}
+
+/// Keeps sessions in this process, so every client in a session has to connect to the same node.
+pub struct MemoryStore {
+    max_sessions: usize,
+    sessions: tokio::sync::Mutex<std::collections::HashMap<String, Session>>,
+    /// When each ended session's ID is free again. Only locked while `sessions` is.
+    ended: tokio::sync::Mutex<std::collections::HashMap<String, std::time::Instant>>,
+}
+
+impl MemoryStore {
+    pub fn new(max_sessions: usize) -> Self {
+        Self {
+            max_sessions,
+            sessions: tokio::sync::Mutex::new(std::collections::HashMap::new()),
+            ended: tokio::sync::Mutex::new(std::collections::HashMap::new()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl super::SessionStore for MemoryStore {
+    async fn join(
+        &self,
+        session_id: &str,
+        offer_sdp: &str,
+        advertisement: Option<protocol::Advertisement>,
+    ) -> anyhow::Result<Result<super::Joined, super::JoinError>> {
+        let mut sessions = self.sessions.lock().await;
+        let mut ended = self.ended.lock().await;
+        let now = std::time::Instant::now();
+        ended.retain(|_, expires_at| *expires_at > now);
+        if ended.contains_key(session_id) {
+            return Ok(Err(super::JoinError::SessionEnded));
+        }
+        drop(ended);
+
+        if !sessions.contains_key(session_id) && sessions.len() >= self.max_sessions {
+            return Ok(Err(super::JoinError::TooManySessions));
+        }
+
+        let session = sessions
+            .entry(session_id.to_string())
+            .or_insert_with(|| Session {
+                created_at: std::time::Instant::now(),
+                offer_sdp: offer_sdp.to_string(),
+                advertisement,
+                num_clients: 0,
+                inboxes: (0..crate::server::MAX_CLIENTS_PER_SESSION)
+                    .map(|_| {
+                        let (tx, rx) =
+                            tokio::sync::mpsc::channel(crate::server::MAX_PENDING_PACKETS);
+                        (tx, Some(rx))
+                    })
+                    .collect(),
+            });
+        if session.num_clients >= crate::server::MAX_CLIENTS_PER_SESSION {
+            return Ok(Err(super::JoinError::SessionFull));
+        }
+
+        let slot = session.num_clients;
+        session.num_clients += 1;
+        Ok(Ok(super::Joined {
+            slot,
+            offer_sdp: session.offer_sdp.clone(),
+            inbox: session.inboxes[slot].1.take().expect("inbox"),
+        }))
+    }
+
+    async fn send(
+        &self,
+        session_id: &str,
+        slot: usize,
+        packet: &protocol::Packet,
+    ) -> anyhow::Result<()> {
+        let sessions = self.sessions.lock().await;
+        let session = match sessions.get(session_id) {
+            Some(session) => session,
+            None => {
+                return Ok(());
+            }
+        };
+        match session.inboxes[slot].0.try_send(packet.clone()) {
+            Ok(()) => Ok(()),
+            Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+                anyhow::bail!("too many pending packets")
+            }
+            // Whoever was in this slot has already left.
+            Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => Ok(()),
+        }
+    }
+
+    async fn remove(&self, session_id: &str) -> anyhow::Result<()> {
+        let mut sessions = self.sessions.lock().await;
+        if let Some(session) = sessions.remove(session_id) {
+            if session.num_clients >= crate::server::MAX_CLIENTS_PER_SESSION {
+                self.ended.lock().await.insert(
+                    session_id.to_string(),
+                    std::time::Instant::now() + super::ENDED_SESSION_TTL,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn sessions(&self) -> anyhow::Result<Vec<super::SessionInfo>> {
+        Ok(self
+            .sessions
+            .lock()
+            .await
+            .iter()
+            .map(|(session_id, session)| super::SessionInfo {
+                session_id: session_id.clone(),
+                age: session.created_at.elapsed(),
+                num_clients: session.num_clients,
+                advertisement: session.advertisement.clone(),
+            })
+            .collect())
+    }
+}
@@ -20,16 +20,35 @@ debug = Debug
 
 connect = Connect
     .input-link-code = Link code
+    .input-random-opponent = Find a random opponent
+    .input-lan = Play on the local network
+    .input-lan-host = Host a game
+    .input-advertise = Open a public room
+    .rooms = Open rooms
+    .rooms-empty = No open rooms right now
     .input-input-delay = Input delay
+    .input-auto-input-delay = Pick input delay from connection latency
+    .input-renegotiate-input-delay = Measure latency again before each battle
     .input-replay-folder-name = Replay folder name
+    .input-allow-spectators = Allow spectators
     .description = Enter a link code that you and your opponent have decided on to connect to each other.
     .description-not-started = Connecting...
+    .description-queueing = Looking for an opponent...
     .description-signalling = Waiting for opponent...
     .description-handshaking = Connecting to opponent...
     .description-error-match-type-mismatch = You and your opponent have selected different modes.
     .description-error-incompatible-games = You and your opponent have incompatible games.
     .description-error-protocol-version-mismatch = You and your opponent have incompatible versions of Tango.
+    .description-error-matchmaking-protocol-version-mismatch = Your version of Tango is too old or too new for the matchmaking server.
+    .description-error-session-full = Someone else is already using this link code.
+    .description-error-server-busy = The matchmaking server is busy. Please try again later.
+    .description-error-timed-out = Timed out waiting for an opponent.
+    .description-error-matchmaking-rejected = The matchmaking server rejected the connection.
     .description-error-unknown = An unknown error occurred.
     .confirm = Connect
     .cancel = Cancel
     .confirm-error = Damn!
+
+desync = Desync detected
+    .description = Your game and your opponent's game went out of sync in battle { $battle_number } at tick { $tick }. The battle state and inputs have been saved next to the replay.
+    .dismiss = OK
@@ -20,16 +20,35 @@ debug = デバッグ
 
 connect = 接続
     .input-link-code = リンクコード
+    .input-random-opponent = ランダムな対戦相手を探す
+    .input-lan = ローカルネットワークで対戦する
+    .input-lan-host = 対戦を募集する
+    .input-advertise = 公開ルームを作る
+    .rooms = 公開ルーム
+    .rooms-empty = 今は公開ルームがありません
     .input-input-delay = 入力遅延
+    .input-auto-input-delay = 通信遅延から入力遅延を自動で決める
+    .input-renegotiate-input-delay = バトルごとに通信遅延を測り直す
     .input-replay-folder-name = リプレイフォルダ名
+    .input-allow-spectators = 観戦を許可する
     .description = お互いに接続するために、あなたと相手が決めたリンクコードを以下に入力してください。
     .description-not-started = 接続中...
+    .description-queueing = 対戦相手を探しています...
     .description-signalling = 対戦相手を待機中...
     .description-handshaking = 対戦相手に接続中...
     .description-error-match-type-mismatch = 相手と選択したモードが異なります。
     .description-error-incompatible-games = 相手のゲームと互換性がありません。
     .description-error-protocol-version-mismatch = 相手の tango ヴァーションと互換性がありません。
+    .description-error-matchmaking-protocol-version-mismatch = マッチングサーバーとtango ヴァーションの互換性がありません。
+    .description-error-session-full = このリンクコードはすでに他の人に使われています。
+    .description-error-server-busy = マッチングサーバーが混み合っています。しばらくしてからもう一度お試しください。
+    .description-error-timed-out = 対戦相手の待機中にタイムアウトしました。
+    .description-error-matchmaking-rejected = マッチングサーバーに接続を拒否されました。
     .description-error-unknown = 不明なエラーが発生しました。
     .confirm = 接続
     .cancel = キャンセル
     .confirm-error = やべっ！
+
+desync = 非同期を検出しました
+    .description = 第{ $battle_number }戦のティック{ $tick }で、あなたと相手のゲームの同期がずれました。バトルの状態と入力はリプレイと同じフォルダに保存されました。
+    .dismiss = OK
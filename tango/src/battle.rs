@@ -1,8 +1,10 @@
 use crate::compat;
-use crate::datachannel;
+use crate::config;
 use crate::input;
 use crate::protocol;
 use crate::replay;
+use crate::spectator;
+use crate::telemetry;
 use crate::transport;
 use rand::Rng;
 use rand::SeedableRng;
@@ -11,6 +13,300 @@ use std::io::Read;
 use std::io::Write;
 use subtle::ConstantTimeEq;
 
+/// How many pings to send while negotiating before settling on an input delay.
+const NEGOTIATION_PINGS: usize = 5;
+
+/// How often to keep measuring round-trip time once the match is running.
+const PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How long to keep trying to reconnect for after the connection drops, before giving up on the match.
+const RECONNECT_GRACE_PERIOD: std::time::Duration = std::time::Duration::from_secs(30);
+
+const RECONNECT_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How many unacknowledged inputs to repeat in each packet when sending over an unreliable transport.
+const MAX_REDUNDANT_INPUTS: usize = 8;
+
+const MIN_INPUT_DELAY: u32 = 3;
+const MAX_INPUT_DELAY: u32 = 10;
+
+/// How long to wait for the remote's inputs hash after a battle ends, before finishing the replay without it.
+const REMOTE_INPUTS_HASH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How many ticks to hold on to a state hash for while waiting for the other side's hash for the same tick.
+const MAX_STATE_HASH_AGE: u32 = 60 * 60;
+
+const TICK_DURATION: std::time::Duration = std::time::Duration::from_nanos(1_000_000_000 / 60);
+
+/// Picks an input delay that covers the time it takes for an input to reach the other side, plus a tick of leeway.
+fn input_delay_for_rtt(rtt: std::time::Duration) -> u32 {
+    let one_way = rtt / 2;
+    let ticks =
+        ((one_way.as_nanos() + TICK_DURATION.as_nanos() - 1) / TICK_DURATION.as_nanos()) as u32;
+    (ticks + 1).clamp(MIN_INPUT_DELAY, MAX_INPUT_DELAY)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum InputDelay {
+    Fixed(u32),
+    /// Proposed from the round-trip time measured while negotiating, and optionally measured again before each battle.
+    Auto {
+        renegotiate: bool,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub enum Pairing {
+    /// Both players have agreed on a link code to use as the session ID.
+    Code(String),
+    /// Waits in the matchmaking server's queue for anyone with a compatible game and the same match type.
+    Random { matchmaking_connect_addr: String },
+}
+
+/// Describes what we're looking for in an opponent to the matchmaking server, for the room list and the random opponent queue.
+pub fn make_advertisement(
+    compat_list: &compat::CompatList,
+    game_title: &str,
+    game_crc32: u32,
+    match_type: u16,
+    input_delay: InputDelay,
+    nickname: &str,
+) -> anyhow::Result<tango_matchmaking::protocol::Advertisement> {
+    let game_id = compat_list
+        .id_by_title_and_crc32(game_title, game_crc32)
+        .ok_or_else(|| anyhow::anyhow!("unknown game"))?;
+    let compat_group = compat_list
+        .compat_group(game_id)
+        .ok_or_else(|| anyhow::anyhow!("{} is not compatible with anything", game_id))?;
+    Ok(tango_matchmaking::protocol::Advertisement {
+        compat_group,
+        match_type,
+        input_delay: match input_delay {
+            InputDelay::Fixed(input_delay) => Some(input_delay),
+            InputDelay::Auto { .. } => None,
+        },
+        nickname: nickname.to_string(),
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConnectionState {
+    Connected,
+    Reconnecting,
+    Disconnected,
+}
+
+/// The transport we're currently talking over, and what we need to pick the match back up over a new one if it drops.
+#[derive(Default)]
+struct Session {
+    transport: Option<std::sync::Arc<dyn transport::Transport>>,
+    local_init: Option<protocol::Init>,
+    /// Inputs the remote hasn't acknowledged yet, all from the current battle.
+    unacked_inputs: std::collections::VecDeque<protocol::Input>,
+    /// Battle number and local tick of the current battle's first local input.
+    local_first_input: Option<(u8, u32)>,
+    last_remote_init_battle_number: Option<u8>,
+    last_remote_input: Option<(u8, u32)>,
+    /// Inputs that arrived after a gap, waiting for the gap to be filled.
+    pending_remote_inputs: std::collections::BTreeMap<(u8, u32), protocol::Input>,
+}
+
+impl Session {
+    fn add_local_input(&mut self, input: protocol::Input) {
+        if self
+            .local_first_input
+            .map(|(battle_number, _)| battle_number != input.battle_number)
+            .unwrap_or(true)
+        {
+            self.unacked_inputs.clear();
+            self.local_first_input = Some((input.battle_number, input.local_tick));
+        }
+        self.unacked_inputs.push_back(input);
+    }
+
+    fn local_first_tick(&self) -> u32 {
+        self.local_first_input
+            .map(|(_, local_tick)| local_tick)
+            .unwrap_or(0)
+    }
+
+    /// The packet to send the given local input in. Over unreliable transports, this also repeats the newest inputs the remote hasn't acknowledged yet, and the oldest one so that a gap left by a lost packet always gets filled eventually, for at most `MAX_REDUNDANT_INPUTS` in all.
+    ///
+    /// `input` must already have been added with `add_local_input`.
+    fn inputs_packet(&self, input: protocol::Input, redundant: bool) -> protocol::Inputs {
+        protocol::Inputs {
+            ack: self.last_remote_input,
+            first_tick: self.local_first_tick(),
+            inputs: if redundant {
+                let mut inputs = self
+                    .unacked_inputs
+                    .iter()
+                    .filter(|i| {
+                        i.battle_number != input.battle_number || i.local_tick != input.local_tick
+                    })
+                    .rev()
+                    .take(MAX_REDUNDANT_INPUTS - 1)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if inputs.len() == MAX_REDUNDANT_INPUTS - 1 {
+                    if let (Some(oldest), Some(last)) =
+                        (self.unacked_inputs.front(), inputs.last_mut())
+                    {
+                        // If the oldest didn't make the cut, it takes the place of the oldest one that did.
+                        if last.battle_number != oldest.battle_number
+                            || last.local_tick != oldest.local_tick
+                        {
+                            *last = oldest.clone();
+                        }
+                    }
+                }
+                inputs.reverse();
+                inputs.push(input);
+                inputs
+            } else {
+                vec![input]
+            },
+        }
+    }
+
+    /// Drops everything the remote has acknowledged, and returns only the inputs we haven't received yet, in order.
+    ///
+    /// Inputs may have been sent more than once, either redundantly or again after reconnecting, and may arrive out of order. Anything we've already received is dropped, and anything past a gap, including a new battle's inputs arriving before its first, is held on to until the gap is filled: we only ever acknowledge the last input we received in order, so the remote keeps sending what's missing.
+    fn receive_remote_inputs(&mut self, inputs: protocol::Inputs) -> Vec<protocol::Input> {
+        if let Some((ack_battle_number, ack_local_tick)) = inputs.ack {
+            while self
+                .unacked_inputs
+                .front()
+                .map(|i| {
+                    i.battle_number < ack_battle_number
+                        || (i.battle_number == ack_battle_number && i.local_tick <= ack_local_tick)
+                })
+                .unwrap_or(false)
+            {
+                self.unacked_inputs.pop_front();
+            }
+        }
+
+        let mut received = vec![];
+        for input in inputs.inputs {
+            let next_tick = match self.last_remote_input {
+                Some((battle_number, local_tick)) => {
+                    if input.battle_number < battle_number
+                        || (input.battle_number == battle_number && input.local_tick <= local_tick)
+                    {
+                        continue;
+                    }
+                    if input.battle_number == battle_number {
+                        local_tick + 1
+                    } else {
+                        inputs.first_tick
+                    }
+                }
+                None => inputs.first_tick,
+            };
+            if input.local_tick != next_tick {
+                self.pending_remote_inputs
+                    .insert((input.battle_number, input.local_tick), input);
+                continue;
+            }
+            self.last_remote_input = Some((input.battle_number, input.local_tick));
+            received.push(input);
+
+            // Anything held on to from an earlier battle will never be needed now.
+            let (battle_number, _) = self.last_remote_input.expect("last remote input");
+            self.pending_remote_inputs
+                .retain(|(pending_battle_number, _), _| *pending_battle_number >= battle_number);
+
+            while let Some((battle_number, local_tick)) = self.last_remote_input {
+                let input = match self
+                    .pending_remote_inputs
+                    .remove(&(battle_number, local_tick + 1))
+                {
+                    Some(input) => input,
+                    None => {
+                        break;
+                    }
+                };
+                self.last_remote_input = Some((input.battle_number, input.local_tick));
+                received.push(input);
+            }
+        }
+        received
+    }
+
+    fn resume(&self) -> protocol::Resume {
+        protocol::Resume {
+            last_init_battle_number: self.last_remote_init_battle_number,
+            last_input: self.last_remote_input,
+        }
+    }
+
+    /// Everything we've sent that the remote hasn't received, given what it last received.
+    fn missing_packets(
+        &self,
+        last_init_battle_number: Option<u8>,
+        last_input: Option<(u8, u32)>,
+    ) -> Vec<protocol::Packet> {
+        let mut packets = vec![];
+        if let Some(init) = &self.local_init {
+            if last_init_battle_number
+                .map(|battle_number| battle_number < init.battle_number)
+                .unwrap_or(true)
+            {
+                packets.push(protocol::Packet::Init(init.clone()));
+            }
+        }
+        let inputs = self
+            .unacked_inputs
+            .iter()
+            .filter(|i| match last_input {
+                Some((battle_number, local_tick)) => {
+                    i.battle_number > battle_number
+                        || (i.battle_number == battle_number && i.local_tick > local_tick)
+                }
+                None => true,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        packets.extend(inputs.chunks(MAX_REDUNDANT_INPUTS).map(|chunk| {
+            protocol::Packet::Inputs(protocol::Inputs {
+                ack: self.last_remote_input,
+                first_tick: self.local_first_tick(),
+                inputs: chunk.to_vec(),
+            })
+        }));
+        packets
+    }
+}
+
+async fn send_or_warn(
+    transport: Option<std::sync::Arc<dyn transport::Transport>>,
+    packet: &protocol::Packet,
+    unreliable: bool,
+) {
+    let transport = match transport {
+        Some(transport) => transport,
+        None => {
+            log::warn!("no transport to send {:?} over", packet);
+            return;
+        }
+    };
+    if let Err(e) = if unreliable {
+        transport.send_unreliable(packet).await
+    } else {
+        transport.send(packet).await
+    } {
+        log::warn!("failed to send packet: {}", e);
+    }
+}
+
+/// A battle's replay that can't be finished until we've both ended the battle and received the remote's inputs hash, which can happen in either order.
+#[derive(Default)]
+struct FinishingReplay {
+    writer: Option<replay::Writer>,
+    remote_inputs_hash: Option<[u8; 32]>,
+}
+
 pub struct BattleState {
     pub number: u8,
     pub battle: Option<Battle>,
@@ -20,9 +316,12 @@ pub struct BattleState {
 enum Negotiation {
     NotReady(NegotiationProgress),
     Negotiated {
-        peer_conn: webrtc::peer_connection::RTCPeerConnection,
-        dc: std::sync::Arc<datachannel::DataChannel>,
         rng: rand_pcg::Mcg128Xsl64,
+        reconnect_secret: [u8; 16],
+        local_game_id: String,
+        remote_game_id: String,
+        remote_nickname: String,
+        input_delay: u32,
     },
     Err(NegotiationError),
 }
@@ -32,23 +331,43 @@ pub struct Match {
     r#impl: std::sync::Arc<MatchImpl>,
 }
 
-pub struct Settings {
+pub struct SpectatorSettings {
     pub matchmaking_connect_addr: String,
-    pub make_webrtc_config:
-        Box<dyn Fn() -> webrtc::peer_connection::configuration::RTCConfiguration + Send + Sync>,
+    pub webrtc: config::WebRTC,
+}
+
+pub struct Settings {
+    pub nickname: String,
+    pub connector: Box<dyn transport::Connector>,
+    pub spectators: Option<SpectatorSettings>,
+    /// Replays are left unsigned without one.
+    pub signing_key: Option<replay::SigningKey>,
 }
 
 struct MatchImpl {
     compat_list: std::sync::Arc<compat::CompatList>,
     negotiation: tokio::sync::Mutex<Negotiation>,
     replay_folder_name: std::path::PathBuf,
-    session_id: String,
+    pairing: Pairing,
+    /// Only known up front when pairing by link code.
+    session_id: parking_lot::Mutex<String>,
+    /// Only set if we signalled through the matchmaking server, which only lets the session's players host its spectators.
+    spectator_host_token: parking_lot::Mutex<Option<String>>,
     match_type: u16,
     game_title: String,
     game_crc32: u32,
-    input_delay: u32,
+    input_delay: InputDelay,
     settings: Settings,
+    epoch: std::time::Instant,
+    connection: parking_lot::Mutex<telemetry::Connection>,
+    session: parking_lot::Mutex<Session>,
+    /// Looked up whenever we connect, since asking the transport for it is too slow to do every frame.
+    candidate_pair: parking_lot::Mutex<Option<transport::CandidatePair>>,
+    connection_state: tokio::sync::watch::Sender<ConnectionState>,
+    connection_state_rx: tokio::sync::watch::Receiver<ConnectionState>,
+    broadcaster: Option<std::sync::Arc<spectator::Broadcaster>>,
     battle_state: tokio::sync::Mutex<BattleState>,
+    finishing_replays: parking_lot::Mutex<std::collections::HashMap<u8, FinishingReplay>>,
     remote_init_sender: tokio::sync::mpsc::Sender<protocol::Init>,
     remote_init_receiver: tokio::sync::Mutex<tokio::sync::mpsc::Receiver<protocol::Init>>,
 }
@@ -57,23 +376,23 @@ struct MatchImpl {
 pub enum NegotiationError {
     ExpectedHello,
     ExpectedHola,
+    ExpectedPong,
+    ExpectedInputDelayProposal,
     IdenticalCommitment,
     ProtocolVersionMismatch,
     MatchTypeMismatch,
     IncompatibleGames,
     InvalidCommitment,
+    Matchmaking(tango_matchmaking::client::ServerError),
     Other(anyhow::Error),
 }
 
 impl From<anyhow::Error> for NegotiationError {
     fn from(err: anyhow::Error) -> Self {
-        NegotiationError::Other(err)
-    }
-}
-
-impl From<webrtc::Error> for NegotiationError {
-    fn from(err: webrtc::Error) -> Self {
-        NegotiationError::Other(err.into())
+        match err.downcast::<tango_matchmaking::client::ServerError>() {
+            Ok(err) => NegotiationError::Matchmaking(err),
+            Err(err) => NegotiationError::Other(err),
+        }
     }
 }
 
@@ -88,11 +407,16 @@ impl std::fmt::Display for NegotiationError {
         match self {
             NegotiationError::ExpectedHello => write!(f, "expected hello"),
             NegotiationError::ExpectedHola => write!(f, "expected hola"),
+            NegotiationError::ExpectedPong => write!(f, "expected pong"),
+            NegotiationError::ExpectedInputDelayProposal => {
+                write!(f, "expected input delay proposal")
+            }
             NegotiationError::IdenticalCommitment => write!(f, "identical commitment"),
             NegotiationError::ProtocolVersionMismatch => write!(f, "protocol version mismatch"),
             NegotiationError::MatchTypeMismatch => write!(f, "match type mismatch"),
             NegotiationError::IncompatibleGames => write!(f, "game mismatch"),
             NegotiationError::InvalidCommitment => write!(f, "invalid commitment"),
+            NegotiationError::Matchmaking(e) => write!(f, "{}", e),
             NegotiationError::Other(e) => write!(f, "other error: {}", e),
         }
     }
@@ -104,6 +428,7 @@ pub enum NegotiationFailure {
     ProtocolVersionMismatch,
     MatchTypeMismatch,
     IncompatibleGames,
+    Matchmaking(tango_matchmaking::protocol::ErrorCode),
     Unknown,
 }
 
@@ -116,54 +441,47 @@ pub enum NegotiationStatus {
 #[derive(Clone, Debug)]
 pub enum NegotiationProgress {
     NotStarted,
+    Queueing,
     Signalling,
     Handshaking,
 }
 
 impl MatchImpl {
-    async fn negotiate(&self) -> Result<(), NegotiationError> {
-        log::info!("negotiating match, session_id = {}", self.session_id);
+    fn session_id(&self) -> String {
+        self.session_id.lock().clone()
+    }
 
-        *self.negotiation.lock().await = Negotiation::NotReady(NegotiationProgress::Signalling);
-        let api = webrtc::api::APIBuilder::new().build();
-        let (peer_conn, dc, side) = tango_matchmaking::client::connect(
-            &self.settings.matchmaking_connect_addr,
-            || async {
-                let peer_conn = api
-                    .new_peer_connection((self.settings.make_webrtc_config)())
-                    .await?;
-                let dc = peer_conn
-                    .create_data_channel(
-                        "tango",
-                        Some(
-                            webrtc::data_channel::data_channel_init::RTCDataChannelInit {
-                                id: Some(1),
-                                negotiated: Some(true),
-                                ordered: Some(true),
-                                ..Default::default()
-                            },
-                        ),
-                    )
-                    .await?;
-                Ok((peer_conn, dc))
-            },
-            &self.session_id,
+    async fn find_opponent(&self, matchmaking_connect_addr: &str) -> anyhow::Result<String> {
+        tango_matchmaking::client::find_opponent(
+            matchmaking_connect_addr,
+            make_advertisement(
+                &self.compat_list,
+                &self.game_title,
+                self.game_crc32,
+                self.match_type,
+                self.input_delay,
+                &self.settings.nickname,
+            )?,
         )
-        .await?;
-        let dc = datachannel::DataChannel::new(dc).await;
+        .await
+    }
 
-        log::info!(
-            "local sdp: {}",
-            peer_conn.local_description().await.expect("local sdp").sdp
-        );
-        log::info!(
-            "remote sdp: {}",
-            peer_conn
-                .remote_description()
-                .await
-                .expect("remote sdp")
-                .sdp
-        );
+    async fn negotiate(&self) -> Result<(), NegotiationError> {
+        if let Pairing::Random {
+            matchmaking_connect_addr,
+        } = &self.pairing
+        {
+            *self.negotiation.lock().await = Negotiation::NotReady(NegotiationProgress::Queueing);
+            let session_id = self.find_opponent(matchmaking_connect_addr).await?;
+            *self.session_id.lock() = session_id;
+        }
+
+        let session_id = self.session_id();
+        log::info!("negotiating match, session_id = {}", session_id);
+
+        *self.negotiation.lock().await = Negotiation::NotReady(NegotiationProgress::Signalling);
+        let transport = self.settings.connector.connect(&session_id).await?;
+        *self.spectator_host_token.lock() = transport.spectator_host_token();
 
         let mut nonce = [0u8; 16];
         rand::rngs::OsRng {}.fill(&mut nonce);
@@ -172,32 +490,23 @@ impl MatchImpl {
         log::info!("our nonce={:?}, commitment={:?}", nonce, commitment);
 
         *self.negotiation.lock().await = Negotiation::NotReady(NegotiationProgress::Handshaking);
-        dc.send(
-            protocol::Packet::Hello(protocol::Hello {
+        transport
+            .send(&protocol::Packet::Hello(protocol::Hello {
                 protocol_version: protocol::VERSION,
+                nickname: self.settings.nickname.clone(),
                 game_title: self.game_title.clone(),
                 game_crc32: self.game_crc32,
                 match_type: self.match_type,
                 rng_commitment: commitment.to_vec(),
-            })
-            .serialize()
-            .expect("serialize")
-            .as_slice(),
-        )
-        .await?;
+            }))
+            .await?;
 
-        let hello = match protocol::Packet::deserialize(
-            match dc.receive().await {
-                Some(d) => d,
-                None => {
-                    return Err(NegotiationError::ExpectedHello);
-                }
-            }
-            .as_slice(),
-        )
-        .map_err(|_| NegotiationError::ExpectedHello)?
+        let hello = match transport
+            .receive()
+            .await
+            .map_err(|_| NegotiationError::ExpectedHello)?
         {
-            protocol::Packet::Hello(hello) => hello,
+            Some(protocol::Packet::Hello(hello)) => hello,
             _ => {
                 return Err(NegotiationError::ExpectedHello);
             }
@@ -220,44 +529,35 @@ impl MatchImpl {
         let my_game_id = self
             .compat_list
             .id_by_title_and_crc32(&self.game_title, self.game_crc32)
-            .unwrap();
+            .unwrap()
+            .clone();
 
         let their_game_id = if let Some(id) = self
             .compat_list
             .id_by_title_and_crc32(&hello.game_title, hello.game_crc32)
         {
-            id
+            id.clone()
         } else {
             return Err(NegotiationError::IncompatibleGames);
         };
 
         // THIS IS NOT AN ANTI-CHEAT MECHANISM. THIS IS NOT AN ANTI-CHEAT MECHANISM. THIS IS NOT AN ANTI-CHEAT MECHANISM.
-        if !self.compat_list.is_compatible(my_game_id, their_game_id) {
+        if !self.compat_list.is_compatible(&my_game_id, &their_game_id) {
             return Err(NegotiationError::IncompatibleGames);
         }
 
-        dc.send(
-            protocol::Packet::Hola(protocol::Hola {
+        transport
+            .send(&protocol::Packet::Hola(protocol::Hola {
                 rng_nonce: nonce.to_vec(),
-            })
-            .serialize()
-            .expect("serialize")
-            .as_slice(),
-        )
-        .await?;
+            }))
+            .await?;
 
-        let hola = match protocol::Packet::deserialize(
-            match dc.receive().await {
-                Some(d) => d,
-                None => {
-                    return Err(NegotiationError::ExpectedHola);
-                }
-            }
-            .as_slice(),
-        )
-        .map_err(|_| NegotiationError::ExpectedHola)?
+        let hola = match transport
+            .receive()
+            .await
+            .map_err(|_| NegotiationError::ExpectedHola)?
         {
-            protocol::Packet::Hola(hola) => hola,
+            Some(protocol::Packet::Hola(hola)) => hola,
             _ => {
                 return Err(NegotiationError::ExpectedHola);
             }
@@ -270,7 +570,8 @@ impl MatchImpl {
             return Err(NegotiationError::InvalidCommitment);
         }
 
-        log::info!("connection ok!");
+        let input_delay = self.negotiate_input_delay(transport.as_ref()).await?;
+        log::info!("connection ok! input delay = {}", input_delay);
 
         let seed = hola
             .rng_nonce
@@ -279,14 +580,130 @@ impl MatchImpl {
             .map(|(&x1, &x2)| x1 ^ x2)
             .collect::<Vec<u8>>();
 
+        let reconnect_secret = make_reconnect_secret(&seed)?;
         let mut rng = rand_pcg::Mcg128Xsl64::from_seed(seed.try_into().expect("rng seed"));
 
+        // Commitments are distinct (checked above), so ordering them gives both sides opposite roles regardless of transport.
         self.battle_state.lock().await.won_last_battle =
-            rng.gen::<bool>() == (side == tango_matchmaking::client::ConnectionSide::Polite);
-        *self.negotiation.lock().await = Negotiation::Negotiated { dc, peer_conn, rng };
+            rng.gen::<bool>() == (commitment.as_slice() < hello.rng_commitment.as_slice());
+        *self.candidate_pair.lock() = transport.candidate_pair().await;
+        self.session.lock().transport = Some(transport);
+        *self.negotiation.lock().await = Negotiation::Negotiated {
+            rng,
+            reconnect_secret,
+            local_game_id: my_game_id,
+            remote_game_id: their_game_id,
+            remote_nickname: hello.nickname,
+            input_delay,
+        };
         Ok(())
     }
 
+    fn now_ts(&self) -> u64 {
+        self.epoch.elapsed().as_micros() as u64
+    }
+
+    async fn send_ping(&self, transport: &dyn transport::Transport) -> anyhow::Result<()> {
+        transport
+            .send(&protocol::Packet::Ping(protocol::Ping {
+                ts: self.now_ts(),
+            }))
+            .await
+    }
+
+    fn record_pong(&self, pong: protocol::Pong) -> std::time::Duration {
+        let rtt = std::time::Duration::from_micros(self.now_ts().saturating_sub(pong.ts));
+        self.connection.lock().pong_received(rtt);
+        rtt
+    }
+
+    fn propose_input_delay(&self) -> u32 {
+        match self.input_delay {
+            InputDelay::Fixed(input_delay) => input_delay,
+            InputDelay::Auto { .. } => self
+                .connection
+                .lock()
+                .median_rtt()
+                .map(input_delay_for_rtt)
+                .unwrap_or(MIN_INPUT_DELAY),
+        }
+    }
+
+    /// Pings the remote a few times, then trades input delay proposals with it and settles on the larger one.
+    ///
+    /// Both sides ping at the same time, so pings from the remote are answered while we wait for our own pongs and for its proposal.
+    async fn negotiate_input_delay(
+        &self,
+        transport: &dyn transport::Transport,
+    ) -> Result<u32, NegotiationError> {
+        let mut remote_proposal = None;
+        for _ in 0..NEGOTIATION_PINGS {
+            self.send_ping(transport).await?;
+            loop {
+                match transport
+                    .receive()
+                    .await
+                    .map_err(|_| NegotiationError::ExpectedPong)?
+                {
+                    Some(protocol::Packet::Ping(ping)) => {
+                        transport
+                            .send(&protocol::Packet::Pong(protocol::Pong { ts: ping.ts }))
+                            .await?;
+                    }
+                    Some(protocol::Packet::Pong(pong)) => {
+                        log::info!("rtt = {:?}", self.record_pong(pong));
+                        break;
+                    }
+                    Some(protocol::Packet::InputDelayProposal(proposal)) => {
+                        remote_proposal = Some(proposal.input_delay);
+                    }
+                    _ => {
+                        return Err(NegotiationError::ExpectedPong);
+                    }
+                }
+            }
+        }
+
+        let local_proposal = self.propose_input_delay();
+        transport
+            .send(&protocol::Packet::InputDelayProposal(
+                protocol::InputDelayProposal {
+                    input_delay: local_proposal,
+                },
+            ))
+            .await?;
+
+        let remote_proposal = match remote_proposal {
+            Some(remote_proposal) => remote_proposal,
+            None => loop {
+                match transport
+                    .receive()
+                    .await
+                    .map_err(|_| NegotiationError::ExpectedInputDelayProposal)?
+                {
+                    Some(protocol::Packet::Ping(ping)) => {
+                        transport
+                            .send(&protocol::Packet::Pong(protocol::Pong { ts: ping.ts }))
+                            .await?;
+                    }
+                    Some(protocol::Packet::InputDelayProposal(proposal)) => {
+                        break proposal.input_delay;
+                    }
+                    _ => {
+                        return Err(NegotiationError::ExpectedInputDelayProposal);
+                    }
+                }
+            },
+        };
+
+        log::info!(
+            "input delay proposals: local = {}, remote = {}",
+            local_proposal,
+            remote_proposal
+        );
+        Ok(local_proposal.max(remote_proposal))
+    }
+
     async fn run(&self) -> anyhow::Result<()> {
         if let Err(e) = self.negotiate().await {
             let e2 = anyhow::format_err!("{}", e);
@@ -294,67 +711,215 @@ impl MatchImpl {
             return Err(e2);
         }
 
-        let dc = match &*self.negotiation.lock().await {
-            Negotiation::Negotiated { dc, .. } => dc.clone(),
+        tokio::select! {
+            r = self.run_connections() => r,
+            _ = self.host_spectators() => unreachable!(),
+        }
+    }
+
+    /// Runs the match over the current transport, replacing it whenever it drops.
+    async fn run_connections(&self) -> anyhow::Result<()> {
+        let reconnect_secret = match &*self.negotiation.lock().await {
+            Negotiation::Negotiated {
+                reconnect_secret, ..
+            } => *reconnect_secret,
             _ => unreachable!(),
         };
 
+        let mut transport = self.session.lock().transport.clone().expect("transport");
+        loop {
+            tokio::select! {
+                r = self.receive_packets(transport.clone()) => r?,
+                r = self.send_pings(transport.clone()) => r?,
+            }
+
+            log::warn!("connection lost, trying to reconnect");
+            let _ = self.connection_state.send(ConnectionState::Reconnecting);
+            let _ = transport.close().await;
+
+            transport =
+                tokio::time::timeout(RECONNECT_GRACE_PERIOD, self.reconnect(&reconnect_secret))
+                    .await
+                    .map_err(|_| anyhow::anyhow!("could not reconnect in time"))?;
+            let _ = self.connection_state.send(ConnectionState::Connected);
+            log::info!("reconnected");
+        }
+    }
+
+    async fn reconnect(&self, reconnect_secret: &[u8]) -> std::sync::Arc<dyn transport::Transport> {
+        // Only the two of us know the secret, so no one else can take over the match by signalling with it. Both sides have to land on the same session without talking to each other, so it only depends on what was agreed on during negotiation: anything counted on each side, like reconnect attempts, drifts apart as soon as one side sees a drop that the other doesn't.
+        let session_id = reconnect_secret
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        loop {
+            match self.try_reconnect(&session_id).await {
+                Ok(transport) => {
+                    return transport;
+                }
+                Err(e) => {
+                    log::warn!("failed to reconnect: {}", e);
+                }
+            }
+            tokio::time::sleep(RECONNECT_RETRY_INTERVAL).await;
+        }
+    }
+
+    async fn try_reconnect(
+        &self,
+        session_id: &str,
+    ) -> anyhow::Result<std::sync::Arc<dyn transport::Transport>> {
+        let transport = self.settings.connector.connect(session_id).await?;
+
+        let resume = self.session.lock().resume();
+        transport.send(&protocol::Packet::Resume(resume)).await?;
+        let remote_resume = match transport.receive().await? {
+            Some(protocol::Packet::Resume(resume)) => resume,
+            p => anyhow::bail!("expected resume, got {:?}", p),
+        };
+        log::info!("their resume={:?}", remote_resume);
+
+        // Anything sent while we catch the remote up goes over the old transport, so keep going until there's nothing left before switching over.
+        let mut last_init_battle_number = remote_resume.last_init_battle_number;
+        let mut last_input = remote_resume.last_input;
         loop {
-            match protocol::Packet::deserialize(
-                match dc.receive().await {
-                    None => break,
-                    Some(buf) => buf,
+            let packets = {
+                let mut session = self.session.lock();
+                let packets = session.missing_packets(last_init_battle_number, last_input);
+                if packets.is_empty() {
+                    session.transport = Some(transport.clone());
+                    break;
+                }
+                packets
+            };
+            for p in packets {
+                match &p {
+                    protocol::Packet::Init(init) => {
+                        last_init_battle_number = Some(init.battle_number);
+                    }
+                    protocol::Packet::Inputs(inputs) => {
+                        if let Some(input) = inputs.inputs.last() {
+                            last_input = Some((input.battle_number, input.local_tick));
+                        }
+                    }
+                    _ => {}
+                }
+                transport.send(&p).await?;
+            }
+        }
+
+        *self.candidate_pair.lock() = transport.candidate_pair().await;
+        Ok(transport)
+    }
+
+    /// Returns once sending a ping fails, which means the connection has dropped.
+    async fn send_pings(
+        &self,
+        transport: std::sync::Arc<dyn transport::Transport>,
+    ) -> anyhow::Result<()> {
+        loop {
+            tokio::time::sleep(PING_INTERVAL).await;
+            if let Err(e) = self.send_ping(transport.as_ref()).await {
+                log::warn!("failed to send ping: {}", e);
+                return Ok(());
+            }
+        }
+    }
+
+    async fn host_spectators(&self) {
+        let spectator_host_token = self.spectator_host_token.lock().clone();
+        if let (Some(spectators), Some(broadcaster), Some(spectator_host_token)) = (
+            &self.settings.spectators,
+            &self.broadcaster,
+            spectator_host_token,
+        ) {
+            if let Err(e) = spectator::host(
+                spectators.matchmaking_connect_addr.clone(),
+                spectators.webrtc.clone(),
+                self.session_id(),
+                spectator_host_token,
+                broadcaster.clone(),
+            )
+            .await
+            {
+                log::warn!("stopped hosting spectators: {:?}", e);
+            }
+        }
+        std::future::pending::<()>().await;
+    }
+
+    /// Returns once the connection drops, or with an error if the remote misbehaves or ends the match.
+    async fn receive_packets(
+        &self,
+        transport: std::sync::Arc<dyn transport::Transport>,
+    ) -> anyhow::Result<()> {
+        loop {
+            match match transport.receive().await {
+                Ok(Some(p)) => p,
+                Ok(None) => break,
+                Err(e) => {
+                    log::warn!("failed to receive: {}", e);
+                    break;
+                }
+            } {
+                protocol::Packet::Ping(ping) => {
+                    if let Err(e) = transport
+                        .send(&protocol::Packet::Pong(protocol::Pong { ts: ping.ts }))
+                        .await
+                    {
+                        log::warn!("failed to send pong: {}", e);
+                        break;
+                    }
+                }
+                protocol::Packet::Pong(pong) => {
+                    self.record_pong(pong);
                 }
-                .as_slice(),
-            )? {
                 protocol::Packet::Init(init) => {
+                    self.session.lock().last_remote_init_battle_number = Some(init.battle_number);
                     self.remote_init_sender
                         .send(init)
                         .await
                         .expect("receive init");
                 }
-                protocol::Packet::Input(input) => {
-                    let state_committed_rx = {
-                        let mut battle_state = self.battle_state.lock().await;
-
-                        if input.battle_number != battle_state.number {
-                            log::info!("battle number mismatch, dropping input");
-                            continue;
-                        }
-
-                        let battle = match &mut battle_state.battle {
-                            None => {
-                                log::info!("no battle in progress, dropping input");
-                                continue;
-                            }
-                            Some(b) => b,
-                        };
-                        battle.state_committed_rx.take()
-                    };
-
-                    if let Some(state_committed_rx) = state_committed_rx {
-                        state_committed_rx.await.unwrap();
+                protocol::Packet::Inputs(inputs) => {
+                    if let Some(input) = inputs.inputs.last() {
+                        self.connection
+                            .lock()
+                            .input_packet_received(input.battle_number, input.local_tick);
                     }
-
+                    let inputs = self.session.lock().receive_remote_inputs(inputs);
+                    for input in inputs {
+                        self.handle_remote_input(input).await?;
+                    }
+                }
+                protocol::Packet::StateHash(state_hash) => {
                     let mut battle_state = self.battle_state.lock().await;
 
+                    if state_hash.battle_number != battle_state.number {
+                        log::info!("battle number mismatch, dropping state hash");
+                        continue;
+                    }
+
                     let battle = match &mut battle_state.battle {
                         None => {
-                            log::info!("no battle in progress, dropping input");
+                            log::info!("no battle in progress, dropping state hash");
                             continue;
                         }
                         Some(b) => b,
                     };
 
-                    if !battle.add_remote_input(input::Input {
-                        local_tick: input.local_tick,
-                        remote_tick: input.remote_tick,
-                        joyflags: input.joyflags as u16,
-                        custom_screen_state: input.custom_screen_state as u8,
-                        turn: input.turn,
-                    }) {
-                        anyhow::bail!("remote overflowed our input buffer");
-                    }
+                    battle.add_remote_state_hash(state_hash.tick, state_hash.hash);
+                }
+                protocol::Packet::InputsHash(inputs_hash) => {
+                    self.finishing_replays
+                        .lock()
+                        .entry(inputs_hash.battle_number)
+                        .or_default()
+                        .remote_inputs_hash = Some(inputs_hash.hash);
+                    self.finish_replay(inputs_hash.battle_number, false);
+                }
+                protocol::Packet::Bye(_) => {
+                    anyhow::bail!("remote ended the match");
                 }
                 p => anyhow::bail!("unknown packet: {:?}", p),
             }
@@ -362,6 +927,96 @@ impl MatchImpl {
 
         Ok(())
     }
+
+    /// Finishes a battle's replay once we have the remote's inputs hash for it, or without it if `give_up` is set.
+    fn finish_replay(&self, battle_number: u8, give_up: bool) {
+        let finishing_replay = {
+            let mut finishing_replays = self.finishing_replays.lock();
+            match finishing_replays.get(&battle_number) {
+                Some(FinishingReplay {
+                    writer: Some(_),
+                    remote_inputs_hash,
+                }) if remote_inputs_hash.is_some() || give_up => {}
+                _ => {
+                    return;
+                }
+            }
+            finishing_replays.remove(&battle_number).unwrap()
+        };
+        let writer = finishing_replay.writer.unwrap();
+
+        match &finishing_replay.remote_inputs_hash {
+            Some(remote_inputs_hash) => {
+                if *remote_inputs_hash != writer.inputs_hash() {
+                    log::warn!(
+                        "remote saw different inputs in battle {}: local hash = {:02x?}, remote hash = {:02x?}",
+                        battle_number,
+                        writer.inputs_hash(),
+                        remote_inputs_hash
+                    );
+                }
+            }
+            None => {
+                log::warn!(
+                    "no inputs hash from remote for battle {}, finishing replay without it",
+                    battle_number
+                );
+            }
+        }
+
+        if let Err(e) = writer.finish(
+            finishing_replay.remote_inputs_hash.as_ref(),
+            self.settings.signing_key.as_ref(),
+        ) {
+            log::error!("failed to finish replay: {}", e);
+        }
+    }
+
+    async fn handle_remote_input(&self, input: protocol::Input) -> anyhow::Result<()> {
+        let state_committed_rx = {
+            let mut battle_state = self.battle_state.lock().await;
+
+            if input.battle_number != battle_state.number {
+                log::info!("battle number mismatch, dropping input");
+                return Ok(());
+            }
+
+            let battle = match &mut battle_state.battle {
+                None => {
+                    log::info!("no battle in progress, dropping input");
+                    return Ok(());
+                }
+                Some(b) => b,
+            };
+            battle.state_committed_rx.take()
+        };
+
+        if let Some(state_committed_rx) = state_committed_rx {
+            state_committed_rx.await.unwrap();
+        }
+
+        let mut battle_state = self.battle_state.lock().await;
+
+        let battle = match &mut battle_state.battle {
+            None => {
+                log::info!("no battle in progress, dropping input");
+                return Ok(());
+            }
+            Some(b) => b,
+        };
+
+        if !battle.add_remote_input(input::Input {
+            local_tick: input.local_tick,
+            remote_tick: input.remote_tick,
+            joyflags: input.joyflags as u16,
+            custom_screen_state: input.custom_screen_state as u8,
+            turn: input.turn,
+        }) {
+            anyhow::bail!("remote overflowed our input buffer");
+        }
+
+        Ok(())
+    }
 }
 
 fn make_rng_commitment(nonce: &[u8]) -> std::io::Result<[u8; 32]> {
@@ -377,6 +1032,17 @@ fn make_rng_commitment(nonce: &[u8]) -> std::io::Result<[u8; 32]> {
     Ok(commitment)
 }
 
+fn make_reconnect_secret(seed: &[u8]) -> std::io::Result<[u8; 16]> {
+    let mut shake128 = sha3::Shake128::default();
+    shake128.write_all(b"tango:reconnect:")?;
+    shake128.write_all(seed)?;
+
+    let mut secret = [0u8; 16];
+    shake128.finalize_xof().read_exact(secret.as_mut_slice())?;
+
+    Ok(secret)
+}
+
 impl Drop for Match {
     fn drop(&mut self) {
         self.cancellation_token.cancel();
@@ -387,31 +1053,50 @@ impl Match {
     pub fn new(
         compat_list: std::sync::Arc<compat::CompatList>,
         replay_folder_name: std::path::PathBuf,
-        session_id: String,
+        pairing: Pairing,
         match_type: u16,
         game_title: String,
         game_crc32: u32,
-        input_delay: u32,
+        input_delay: InputDelay,
         settings: Settings,
     ) -> Self {
         let (remote_init_sender, remote_init_receiver) = tokio::sync::mpsc::channel(1);
+        let (connection_state, connection_state_rx) =
+            tokio::sync::watch::channel(ConnectionState::Connected);
+        let broadcaster = settings
+            .spectators
+            .as_ref()
+            .map(|_| std::sync::Arc::new(spectator::Broadcaster::new()));
         let r#impl = std::sync::Arc::new(MatchImpl {
             compat_list,
             negotiation: tokio::sync::Mutex::new(Negotiation::NotReady(
                 NegotiationProgress::NotStarted,
             )),
             replay_folder_name,
-            session_id,
+            session_id: parking_lot::Mutex::new(match &pairing {
+                Pairing::Code(code) => code.clone(),
+                Pairing::Random { .. } => "".to_string(),
+            }),
+            spectator_host_token: parking_lot::Mutex::new(None),
+            pairing,
             match_type,
             game_title,
             game_crc32,
             input_delay,
             settings,
+            epoch: std::time::Instant::now(),
+            connection: parking_lot::Mutex::new(telemetry::Connection::new()),
+            session: parking_lot::Mutex::new(Session::default()),
+            candidate_pair: parking_lot::Mutex::new(None),
+            connection_state,
+            connection_state_rx,
+            broadcaster,
             battle_state: tokio::sync::Mutex::new(BattleState {
                 number: 0,
                 battle: None,
                 won_last_battle: false,
             }),
+            finishing_replays: parking_lot::Mutex::new(std::collections::HashMap::new()),
             remote_init_sender,
             remote_init_receiver: tokio::sync::Mutex::new(remote_init_receiver),
         });
@@ -442,17 +1127,68 @@ impl Match {
             Negotiation::Err(NegotiationError::ProtocolVersionMismatch) => {
                 NegotiationStatus::Failed(NegotiationFailure::ProtocolVersionMismatch)
             }
+            Negotiation::Err(NegotiationError::Matchmaking(e)) => {
+                NegotiationStatus::Failed(NegotiationFailure::Matchmaking(e.code))
+            }
             Negotiation::Err(_) => NegotiationStatus::Failed(NegotiationFailure::Unknown),
         }
     }
 
-    pub async fn transport(&self) -> anyhow::Result<transport::Transport> {
-        let dc = match &*self.r#impl.negotiation.lock().await {
-            Negotiation::Negotiated { dc, .. } => dc.clone(),
-            Negotiation::NotReady(_) => anyhow::bail!("not ready"),
-            Negotiation::Err(e) => anyhow::bail!("{}", e),
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.r#impl.connection_state_rx.borrow()
+    }
+
+    /// Waits out any reconnection in progress, returning false if the connection is gone for good.
+    pub async fn wait_for_connection(&self) -> bool {
+        let mut connection_state_rx = self.r#impl.connection_state_rx.clone();
+        loop {
+            let connection_state = *connection_state_rx.borrow();
+            match connection_state {
+                ConnectionState::Connected => {
+                    return true;
+                }
+                ConnectionState::Disconnected => {
+                    return false;
+                }
+                ConnectionState::Reconnecting => {}
+            }
+            if connection_state_rx.changed().await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    /// Inputs and inits that fail to send are sent again once we reconnect, so failing to send them isn't fatal.
+    pub async fn send_init(&self, init: protocol::Init) {
+        let transport = {
+            let mut session = self.r#impl.session.lock();
+            session.local_init = Some(init.clone());
+            session.transport.clone()
+        };
+        send_or_warn(transport, &protocol::Packet::Init(init), false).await;
+    }
+
+    pub async fn send_input(&self, input: protocol::Input) {
+        let (transport, inputs, unreliable) = {
+            let mut session = self.r#impl.session.lock();
+            session.add_local_input(input.clone());
+            let unreliable = session
+                .transport
+                .as_ref()
+                .map(|transport| transport.is_unreliable())
+                .unwrap_or(false);
+            (
+                session.transport.clone(),
+                session.inputs_packet(input, unreliable),
+                unreliable,
+            )
         };
-        Ok(transport::Transport::new(dc))
+        send_or_warn(transport, &protocol::Packet::Inputs(inputs), unreliable).await;
+    }
+
+    pub async fn send_state_hash(&self, state_hash: protocol::StateHash) {
+        let transport = self.r#impl.session.lock().transport.clone();
+        send_or_warn(transport, &protocol::Packet::StateHash(state_hash), false).await;
     }
 
     pub async fn lock_rng(
@@ -475,13 +1211,49 @@ impl Match {
         self.r#impl.match_type
     }
 
+    pub fn broadcaster(&self) -> Option<&std::sync::Arc<spectator::Broadcaster>> {
+        self.r#impl.broadcaster.as_ref()
+    }
+
+    pub fn connection_stats(&self) -> telemetry::ConnectionStats {
+        self.r#impl.connection.lock().stats()
+    }
+
+    pub fn candidate_pair(&self) -> Option<transport::CandidatePair> {
+        self.r#impl.candidate_pair.lock().clone()
+    }
+
     pub async fn start_battle(&self) {
+        let (local_game_id, remote_game_id, remote_nickname, negotiated_input_delay) =
+            match &*self.r#impl.negotiation.lock().await {
+                Negotiation::Negotiated {
+                    local_game_id,
+                    remote_game_id,
+                    remote_nickname,
+                    input_delay,
+                    ..
+                } => (
+                    local_game_id.clone(),
+                    remote_game_id.clone(),
+                    remote_nickname.clone(),
+                    *input_delay,
+                ),
+                _ => Default::default(),
+            };
+
+        // The remote proposes its own delay in its init, and both sides then settle on the larger one.
+        let input_delay = match self.r#impl.input_delay {
+            InputDelay::Auto { renegotiate: true } => self.r#impl.propose_input_delay(),
+            _ => negotiated_input_delay,
+        };
+
         let mut battle_state = self.r#impl.battle_state.lock().await;
         battle_state.number += 1;
         let local_player_index = if battle_state.won_last_battle { 0 } else { 1 };
         log::info!(
-            "starting battle: local_player_index = {}",
-            local_player_index
+            "starting battle: local_player_index = {}, proposed input delay = {}",
+            local_player_index,
+            input_delay
         );
         let replay_filename = std::path::Path::new("replays")
             .join(&self.r#impl.replay_folder_name)
@@ -489,10 +1261,23 @@ impl Match {
         let replay_file = std::fs::File::create(&replay_filename).expect("new replay file");
         log::info!("opened replay: {}", replay_filename.display());
 
+        let telemetry_filename = replay_filename.with_extension("telemetry.csv");
+        let telemetry = match telemetry::Recorder::with_csv(&telemetry_filename) {
+            Ok(telemetry) => telemetry,
+            Err(e) => {
+                log::warn!(
+                    "failed to open telemetry {}: {}",
+                    telemetry_filename.display(),
+                    e
+                );
+                telemetry::Recorder::new()
+            }
+        };
+
         let (tx, rx) = tokio::sync::oneshot::channel();
         battle_state.battle = Some(Battle {
             local_player_index,
-            iq: input::PairQueue::new(120, self.r#impl.input_delay),
+            iq: input::PairQueue::new(120, input_delay),
             remote_delay: 0,
             is_accepting_input: false,
             last_committed_remote_input: input::Input {
@@ -506,14 +1291,86 @@ impl Match {
             state_committed_tx: Some(tx),
             state_committed_rx: Some(rx),
             committed_state: None,
+            initial_state: None,
+            committed_input_pairs: vec![],
             local_pending_turn: None,
-            replay_writer: replay::Writer::new(Box::new(replay_file), local_player_index)
-                .expect("new replay writer"),
+            replay_filename,
+            replay_writer: replay::Writer::new(
+                Box::new(replay_file),
+                local_player_index,
+                replay::Metadata {
+                    session_id: self.r#impl.session_id(),
+                    battle_number: battle_state.number,
+                    local: replay::PlayerMetadata {
+                        nickname: self.r#impl.settings.nickname.clone(),
+                        game_id: local_game_id,
+                        input_delay,
+                    },
+                    remote: replay::PlayerMetadata {
+                        nickname: remote_nickname,
+                        game_id: remote_game_id,
+                        input_delay: 0,
+                    },
+                    started_at: replay::now_timestamp(),
+                },
+            )
+            .expect("new replay writer"),
+            won: None,
+            telemetry,
+            last_local_state_hash_tick: None,
+            local_state_hashes: std::collections::BTreeMap::new(),
+            remote_state_hashes: std::collections::BTreeMap::new(),
+            desynced_at: None,
+            desync_notification: None,
         });
     }
 
     pub async fn end_battle(&self) {
-        self.r#impl.battle_state.lock().await.battle = None;
+        let mut battle_state = self.r#impl.battle_state.lock().await;
+        let battle_number = battle_state.number;
+        if let Some(broadcaster) = &self.r#impl.broadcaster {
+            broadcaster.end_battle(battle_number);
+        }
+        let mut battle = match battle_state.battle.take() {
+            Some(battle) => battle,
+            None => {
+                return;
+            }
+        };
+        drop(battle_state);
+
+        if let Err(e) = battle.write_outcome() {
+            log::error!("failed to write outcome to replay: {}", e);
+        }
+        if let Err(e) = battle.telemetry.flush() {
+            log::error!("failed to write telemetry: {}", e);
+        }
+
+        let inputs_hash = battle.replay_writer.inputs_hash();
+        self.r#impl
+            .finishing_replays
+            .lock()
+            .entry(battle_number)
+            .or_default()
+            .writer = Some(battle.replay_writer);
+        self.r#impl.finish_replay(battle_number, false);
+
+        let transport = self.r#impl.session.lock().transport.clone();
+        send_or_warn(
+            transport,
+            &protocol::Packet::InputsHash(protocol::InputsHash {
+                battle_number,
+                hash: inputs_hash,
+            }),
+            false,
+        )
+        .await;
+
+        let r#impl = self.r#impl.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(REMOTE_INPUTS_HASH_TIMEOUT).await;
+            r#impl.finish_replay(battle_number, true);
+        });
     }
 
     pub fn start(&self, handle: tokio::runtime::Handle) {
@@ -526,10 +1383,13 @@ impl Match {
                     log::info!("match thread ending: {:?}", e);
                 },
             };
-            if let Negotiation::Negotiated { dc, peer_conn, .. } = &*r#impl.negotiation.lock().await
-            {
-                let _ = dc.close().await;
-                let _ = peer_conn.close().await;
+            let _ = r#impl.connection_state.send(ConnectionState::Disconnected);
+            let transport = r#impl.session.lock().transport.take();
+            if let Some(transport) = transport {
+                let _ = transport
+                    .send(&protocol::Packet::Bye(protocol::Bye {}))
+                    .await;
+                let _ = transport.close().await;
             }
         });
     }
@@ -550,8 +1410,18 @@ pub struct Battle {
     state_committed_tx: Option<tokio::sync::oneshot::Sender<()>>,
     state_committed_rx: Option<tokio::sync::oneshot::Receiver<()>>,
     committed_state: Option<mgba::state::State>,
+    initial_state: Option<mgba::state::State>,
+    committed_input_pairs: Vec<input::Pair<input::Input>>,
     local_pending_turn: Option<LocalPendingTurn>,
+    replay_filename: std::path::PathBuf,
     replay_writer: replay::Writer,
+    won: Option<bool>,
+    telemetry: telemetry::Recorder,
+    last_local_state_hash_tick: Option<u32>,
+    local_state_hashes: std::collections::BTreeMap<u32, Vec<u8>>,
+    remote_state_hashes: std::collections::BTreeMap<u32, Vec<u8>>,
+    desynced_at: Option<u32>,
+    desync_notification: Option<u32>,
 }
 
 impl Battle {
@@ -568,6 +1438,9 @@ impl Battle {
     }
 
     pub fn set_committed_state(&mut self, state: mgba::state::State) {
+        if self.initial_state.is_none() {
+            self.initial_state = Some(state.clone());
+        }
         self.committed_state = Some(state);
         if let Some(tx) = self.state_committed_tx.take() {
             let _ = tx.send(());
@@ -586,14 +1459,52 @@ impl Battle {
         self.iq.local_delay()
     }
 
-    pub fn set_remote_delay(&mut self, delay: u32) {
+    /// Settles on the larger of our proposed delay and the remote's, which is what the remote settles on too.
+    pub fn agree_on_delay(&mut self, remote_proposal: u32) {
+        let delay = self.iq.local_delay().max(remote_proposal);
+        self.iq.set_local_delay(delay);
         self.remote_delay = delay;
+        let metadata = self.replay_writer.metadata_mut();
+        metadata.local.input_delay = delay;
+        metadata.remote.input_delay = delay;
+    }
+
+    pub fn set_won(&mut self, did_win: bool) {
+        self.won = Some(did_win);
+    }
+
+    fn write_outcome(&mut self) -> std::io::Result<()> {
+        let local_player_index = self.local_player_index;
+        self.replay_writer.write_outcome(&replay::Outcome {
+            winner_player_index: self.won.map(|did_win| {
+                if did_win {
+                    local_player_index
+                } else {
+                    1 - local_player_index
+                }
+            }),
+            ended_at: replay::now_timestamp(),
+        })
     }
 
     pub fn remote_delay(&self) -> u32 {
         self.remote_delay
     }
 
+    pub fn record_telemetry(
+        &mut self,
+        frame: &telemetry::Frame,
+        connection: &telemetry::ConnectionStats,
+    ) {
+        if let Err(e) = self.telemetry.record(frame, connection) {
+            log::error!("failed to write telemetry: {}", e);
+        }
+    }
+
+    pub fn telemetry_summary(&self) -> &telemetry::Summary {
+        self.telemetry.summary()
+    }
+
     pub fn local_queue_length(&self) -> usize {
         self.iq.local_queue_length()
     }
@@ -625,6 +1536,8 @@ impl Battle {
         if let Some(last) = input_pairs.last() {
             self.last_committed_remote_input = last.remote.clone();
         }
+        self.committed_input_pairs
+            .extend(input_pairs.iter().cloned());
         (input_pairs, left)
     }
 
@@ -676,4 +1589,238 @@ impl Battle {
                 - self.last_committed_remote_input.local_tick as i32
                 - self.remote_delay() as i32)
     }
+
+    /// Returns false if we've already seen a hash for this tick.
+    pub fn add_local_state_hash(&mut self, tick: u32, hash: Vec<u8>) -> bool {
+        if self
+            .last_local_state_hash_tick
+            .map(|last_tick| tick <= last_tick)
+            .unwrap_or(false)
+        {
+            return false;
+        }
+        self.last_local_state_hash_tick = Some(tick);
+        self.local_state_hashes.insert(tick, hash);
+        self.check_state_hash(tick);
+        self.prune_state_hashes(tick);
+        true
+    }
+
+    pub fn add_remote_state_hash(&mut self, tick: u32, hash: Vec<u8>) {
+        self.remote_state_hashes.insert(tick, hash);
+        self.check_state_hash(tick);
+        self.prune_state_hashes(tick);
+    }
+
+    /// Drops hashes the other side has had plenty of time to send its half for, e.g. if it never sends any.
+    fn prune_state_hashes(&mut self, tick: u32) {
+        let oldest_tick = tick.saturating_sub(MAX_STATE_HASH_AGE);
+        self.local_state_hashes = self.local_state_hashes.split_off(&oldest_tick);
+        self.remote_state_hashes = self.remote_state_hashes.split_off(&oldest_tick);
+    }
+
+    fn check_state_hash(&mut self, tick: u32) {
+        let (local, remote) = match (
+            self.local_state_hashes.get(&tick),
+            self.remote_state_hashes.get(&tick),
+        ) {
+            (Some(local), Some(remote)) => (local.clone(), remote.clone()),
+            _ => {
+                return;
+            }
+        };
+        // Both sides send hashes in order, so anything older that's still here will never get its other half.
+        self.local_state_hashes = self.local_state_hashes.split_off(&(tick + 1));
+        self.remote_state_hashes = self.remote_state_hashes.split_off(&(tick + 1));
+
+        if local == remote || self.desynced_at.is_some() {
+            return;
+        }
+
+        log::error!(
+            "desync detected at tick {}: local hash = {:02x?}, remote hash = {:02x?}",
+            tick,
+            local,
+            remote
+        );
+        self.desynced_at = Some(tick);
+        self.desync_notification = Some(tick);
+
+        if let Err(e) = self.dump_desync(tick) {
+            log::error!("failed to dump desync: {}", e);
+        }
+    }
+
+    fn dump_desync(&self, tick: u32) -> std::io::Result<()> {
+        if let Some(committed_state) = &self.committed_state {
+            let path = self
+                .replay_filename
+                .with_extension(format!("desync{}.state", tick));
+            std::fs::write(&path, committed_state.as_slice())?;
+            log::info!("dumped committed state to {}", path.display());
+        }
+
+        if let Some(initial_state) = &self.initial_state {
+            let path = self
+                .replay_filename
+                .with_extension(format!("desync{}.tangoreplay", tick));
+            let mut writer = replay::Writer::new(
+                Box::new(std::fs::File::create(&path)?),
+                self.local_player_index,
+                self.replay_writer.metadata().clone(),
+            )?;
+            writer.write_state(initial_state)?;
+            for ip in &self.committed_input_pairs {
+                writer.write_input(self.local_player_index, ip)?;
+            }
+            writer.finish(None, None)?;
+            log::info!("dumped input history to {}", path.display());
+        }
+
+        Ok(())
+    }
+
+    pub fn take_desync_notification(&mut self) -> Option<u32> {
+        self.desync_notification.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_input(battle_number: u8, local_tick: u32) -> protocol::Input {
+        protocol::Input {
+            battle_number,
+            local_tick,
+            remote_tick: 0,
+            joyflags: 0,
+            custom_screen_state: 0,
+            turn: vec![],
+        }
+    }
+
+    fn test_inputs(first_tick: u32, inputs: &[(u8, u32)]) -> protocol::Inputs {
+        protocol::Inputs {
+            ack: None,
+            first_tick,
+            inputs: inputs
+                .iter()
+                .map(|(battle_number, local_tick)| test_input(*battle_number, *local_tick))
+                .collect(),
+        }
+    }
+
+    fn ticks(inputs: &[protocol::Input]) -> Vec<(u8, u32)> {
+        inputs
+            .iter()
+            .map(|input| (input.battle_number, input.local_tick))
+            .collect()
+    }
+
+    #[test]
+    fn receive_remote_inputs_dedups() {
+        let mut session = Session::default();
+        assert_eq!(
+            ticks(&session.receive_remote_inputs(test_inputs(10, &[(1, 10), (1, 11), (1, 12)]))),
+            vec![(1, 10), (1, 11), (1, 12)]
+        );
+        assert_eq!(
+            ticks(&session.receive_remote_inputs(test_inputs(10, &[(1, 11), (1, 12), (1, 13)]))),
+            vec![(1, 13)]
+        );
+        assert!(ticks(&session.receive_remote_inputs(test_inputs(10, &[(1, 12)]))).is_empty());
+        assert_eq!(session.last_remote_input, Some((1, 13)));
+    }
+
+    #[test]
+    fn receive_remote_inputs_holds_inputs_past_gap() {
+        let mut session = Session::default();
+        assert_eq!(
+            ticks(&session.receive_remote_inputs(test_inputs(10, &[(1, 10)]))),
+            vec![(1, 10)]
+        );
+        assert!(session
+            .receive_remote_inputs(test_inputs(10, &[(1, 12), (1, 13)]))
+            .is_empty());
+        assert_eq!(session.last_remote_input, Some((1, 10)));
+        assert_eq!(
+            ticks(&session.receive_remote_inputs(test_inputs(10, &[(1, 11)]))),
+            vec![(1, 11), (1, 12), (1, 13)]
+        );
+        assert!(session.pending_remote_inputs.is_empty());
+
+        // The first input of the match can go missing too.
+        let mut session = Session::default();
+        assert!(session
+            .receive_remote_inputs(test_inputs(10, &[(1, 11)]))
+            .is_empty());
+        assert_eq!(
+            ticks(&session.receive_remote_inputs(test_inputs(10, &[(1, 10)]))),
+            vec![(1, 10), (1, 11)]
+        );
+    }
+
+    #[test]
+    fn receive_remote_inputs_rolls_over_to_next_battle() {
+        let mut session = Session::default();
+        session.receive_remote_inputs(test_inputs(10, &[(1, 10), (1, 11)]));
+        // Held past a gap that will never be filled, since the battle ends.
+        session.receive_remote_inputs(test_inputs(10, &[(1, 13)]));
+
+        // The next battle's first input is missing, so the rest have to wait for it.
+        assert!(session
+            .receive_remote_inputs(test_inputs(100, &[(2, 101), (2, 102)]))
+            .is_empty());
+        assert_eq!(session.last_remote_input, Some((1, 11)));
+        assert_eq!(
+            ticks(&session.receive_remote_inputs(test_inputs(100, &[(2, 100)]))),
+            vec![(2, 100), (2, 101), (2, 102)]
+        );
+        assert!(session.pending_remote_inputs.is_empty());
+
+        // Anything from the previous battle is too late now.
+        assert!(session
+            .receive_remote_inputs(test_inputs(10, &[(1, 12)]))
+            .is_empty());
+        assert_eq!(session.last_remote_input, Some((2, 102)));
+    }
+
+    #[test]
+    fn inputs_packet_holds_at_most_max_redundant_inputs() {
+        let mut session = Session::default();
+        for local_tick in 10..30 {
+            session.add_local_input(test_input(1, local_tick));
+        }
+
+        let packet = session.inputs_packet(test_input(1, 29), true);
+        assert_eq!(packet.first_tick, 10);
+        assert_eq!(packet.inputs.len(), MAX_REDUNDANT_INPUTS);
+        // The oldest, then the newest, ending with the one being sent.
+        let mut want = vec![(1, 10)];
+        want.extend((30 - MAX_REDUNDANT_INPUTS as u32 + 1..30).map(|local_tick| (1, local_tick)));
+        assert_eq!(ticks(&packet.inputs), want);
+
+        assert_eq!(
+            ticks(&session.inputs_packet(test_input(1, 29), false).inputs),
+            vec![(1, 29)]
+        );
+
+        // Once the remote acknowledges all but the last few, only those are repeated.
+        session.receive_remote_inputs(protocol::Inputs {
+            ack: Some((1, 26)),
+            first_tick: 0,
+            inputs: vec![],
+        });
+        assert_eq!(
+            ticks(&session.inputs_packet(test_input(1, 29), true).inputs),
+            vec![(1, 27), (1, 28), (1, 29)]
+        );
+
+        // A new battle starts afresh.
+        session.add_local_input(test_input(2, 100));
+        let packet = session.inputs_packet(test_input(2, 100), true);
+        assert_eq!(packet.first_tick, 100);
+        assert_eq!(ticks(&packet.inputs), vec![(2, 100)]);
+    }
 }
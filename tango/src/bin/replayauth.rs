@@ -0,0 +1,122 @@
+use clap::Parser;
+
+#[derive(clap::Parser)]
+enum Cli {
+    /// Generate a new key to sign replays with.
+    Keygen {
+        #[clap(parse(from_os_str))]
+        path: std::path::PathBuf,
+    },
+
+    /// Print the public key of a signing key.
+    Pubkey {
+        #[clap(parse(from_os_str))]
+        path: std::path::PathBuf,
+    },
+
+    /// Check the signatures and inputs hashes of replays. Given both sides' replays of a battle, also check that they agree with each other.
+    Verify {
+        #[clap(parse(from_os_str), min_values = 1, max_values = 2)]
+        paths: Vec<std::path::PathBuf>,
+    },
+}
+
+/// Checks a single replay, returning whose side it's from and its inputs hash if everything about it checks out.
+fn verify(path: &std::path::Path) -> anyhow::Result<(u8, [u8; 32])> {
+    let replay = tango::replay::Replay::decode(std::fs::File::open(path)?)?;
+
+    match &replay.signature {
+        Some(signature) if signature.is_valid() => {
+            println!(
+                "{}: signed by {}",
+                path.display(),
+                tango::replay::hex(&signature.public_key)
+            );
+        }
+        Some(signature) => {
+            anyhow::bail!(
+                "{}: signature by {} is invalid",
+                path.display(),
+                tango::replay::hex(&signature.public_key)
+            );
+        }
+        None => {
+            anyhow::bail!("{}: not signed", path.display());
+        }
+    }
+
+    let inputs_hashes = replay
+        .inputs_hashes
+        .ok_or_else(|| anyhow::format_err!("{}: no inputs hashes", path.display()))?;
+    let inputs_hash = tango::replay::inputs_hash(replay.local_player_index, &replay.input_pairs);
+    if inputs_hash != inputs_hashes.local {
+        anyhow::bail!(
+            "{}: inputs don't match the recorded hash: recorded {}, actual {}",
+            path.display(),
+            tango::replay::hex(&inputs_hashes.local),
+            tango::replay::hex(&inputs_hash)
+        );
+    }
+    match inputs_hashes.remote {
+        Some(remote) if remote == inputs_hash => {
+            println!("{}: both sides saw the same inputs", path.display());
+        }
+        Some(remote) => {
+            anyhow::bail!(
+                "{}: the other side saw different inputs: local {}, remote {}",
+                path.display(),
+                tango::replay::hex(&inputs_hash),
+                tango::replay::hex(&remote)
+            );
+        }
+        None => {
+            anyhow::bail!(
+                "{}: the other side never sent their inputs hash",
+                path.display()
+            );
+        }
+    }
+
+    Ok((replay.local_player_index, inputs_hash))
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    env_logger::Builder::from_default_env()
+        .filter(Some("tango"), log::LevelFilter::Info)
+        .filter(Some("replayauth"), log::LevelFilter::Info)
+        .init();
+
+    match Cli::parse() {
+        Cli::Keygen { path } => {
+            if path.exists() {
+                anyhow::bail!("{} already exists", path.display());
+            }
+            let signing_key = tango::replay::SigningKey::generate();
+            signing_key.save(&path)?;
+            println!("{}", tango::replay::hex(&signing_key.public_key()));
+        }
+        Cli::Pubkey { path } => {
+            let signing_key = tango::replay::SigningKey::load(&path)?;
+            println!("{}", tango::replay::hex(&signing_key.public_key()));
+        }
+        Cli::Verify { paths } => {
+            let sides = paths
+                .iter()
+                .map(|path| verify(path))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            if let [(a_player_index, a_inputs_hash), (b_player_index, b_inputs_hash)] =
+                sides.as_slice()
+            {
+                if a_player_index == b_player_index {
+                    anyhow::bail!("the replays are from the same side");
+                }
+                if a_inputs_hash != b_inputs_hash {
+                    anyhow::bail!("the replays are not of the same battle");
+                }
+                println!("both sides' replays agree");
+            }
+        }
+    }
+
+    Ok(())
+}
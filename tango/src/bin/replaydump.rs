@@ -49,6 +49,9 @@ fn main() -> Result<(), anyhow::Error> {
     let mut f = std::fs::File::open(path.clone())?;
 
     let replay = tango::replay::Replay::decode(&mut f)?;
+    if replay.truncated {
+        log::warn!("replay is truncated, it will end early");
+    }
     log::info!(
         "replay is for {} (crc32 = {:08x})",
         replay.state.rom_title(),
@@ -129,7 +132,7 @@ fn main() -> Result<(), anyhow::Error> {
     core.as_mut().load_rom(vf)?;
     core.as_mut().reset();
 
-    let done = std::rc::Rc::new(std::cell::RefCell::new(false));
+    let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
 
     let ff_state = {
         let done = done.clone();
@@ -139,7 +142,7 @@ fn main() -> Result<(), anyhow::Error> {
             0,
             0,
             Box::new(move || {
-                *done.borrow_mut() = true;
+                done.store(true, std::sync::atomic::Ordering::Relaxed);
             }),
         )
     };
@@ -195,7 +198,7 @@ fn main() -> Result<(), anyhow::Error> {
     let mut samples = vec![0i16; SAMPLE_RATE as usize];
     let mut vbuf = vec![0u8; (mgba::gba::SCREEN_WIDTH * mgba::gba::SCREEN_HEIGHT * 4) as usize];
     let bar = indicatif::ProgressBar::new(ff_state.inputs_pairs_left() as u64);
-    while !*done.borrow() {
+    while !done.load(std::sync::atomic::Ordering::Relaxed) {
         bar.inc(1);
         core.as_mut().run_frame();
         let clock_rate = core.as_ref().frequency();
@@ -0,0 +1,184 @@
+use clap::Parser;
+
+/// Plays each replay back twice on fresh cores and checks that both runs land on exactly the same states, and on the keyframes and state hashes recorded live.
+#[derive(clap::Parser)]
+struct Cli {
+    /// Replays, or folders to look for replays in.
+    #[clap(parse(from_os_str), min_values = 1)]
+    paths: Vec<std::path::PathBuf>,
+
+    #[clap(parse(from_os_str), long, default_value = "roms")]
+    roms_path: std::path::PathBuf,
+
+    /// How often, in ticks, to compare the two runs' savestates.
+    #[clap(long, default_value = "60")]
+    interval: usize,
+}
+
+/// Returns how many times the two runs were compared.
+fn verify(
+    path: &std::path::Path,
+    roms: &std::collections::HashMap<(String, u32), (String, std::path::PathBuf)>,
+    compat_list: &tango::compat::CompatList,
+    interval: usize,
+) -> anyhow::Result<usize> {
+    let replay = tango::replay::Replay::decode(std::fs::File::open(path)?)?;
+    if replay.truncated {
+        log::warn!(
+            "{} is truncated, only verifying what's left",
+            path.display()
+        );
+    }
+    if replay.input_pairs.is_empty() {
+        anyhow::bail!("no inputs to play back");
+    }
+
+    let (id, rom_path) = roms
+        .get(&(replay.state.rom_title(), replay.state.rom_crc32()))
+        .ok_or_else(|| {
+            anyhow::format_err!(
+                "could not find eligible rom for {} (crc32 = {:08x})",
+                replay.state.rom_title(),
+                replay.state.rom_crc32()
+            )
+        })?;
+    let hooks = tango::hooks::HOOKS
+        .get(
+            &compat_list
+                .game_by_id(id)
+                .ok_or_else(|| anyhow::format_err!("no compatibility data for {}", id))?
+                .hooks,
+        )
+        .ok_or_else(|| anyhow::format_err!("no hooks for {}", id))?;
+
+    // Stop at every interval, and wherever there's a keyframe to compare against.
+    let mut stops = (interval..replay.input_pairs.len())
+        .step_by(interval)
+        .chain(
+            replay
+                .keyframes
+                .iter()
+                .map(|keyframe| keyframe.input_index)
+                .filter(|input_index| *input_index > 0 && *input_index <= replay.input_pairs.len()),
+        )
+        .chain(std::iter::once(replay.input_pairs.len()))
+        .collect::<Vec<_>>();
+    stops.sort_unstable();
+    stops.dedup();
+
+    let mut runs = [
+        tango::fastforwarder::Fastforwarder::new(rom_path, hooks)?,
+        tango::fastforwarder::Fastforwarder::new(rom_path, hooks)?,
+    ];
+    let mut states = [replay.state.clone(), replay.state.clone()];
+    let mut keyframes = replay.keyframes.iter().peekable();
+    let mut recorded_state_hashes = replay.state_hashes.iter().peekable();
+    let mut start = 0;
+    let mut num_compared = 0;
+    for stop in stops {
+        let pairs = &replay.input_pairs[start..stop];
+        let last_committed_remote_input = pairs.last().expect("last pair").remote.clone();
+        // The committed state is from right before the input for the tick after the last one played.
+        let tick = pairs.last().expect("last pair").local.local_tick + 1;
+
+        for (ff, state) in runs.iter_mut().zip(states.iter_mut()) {
+            let (committed_state, _, _) = ff.fastforward(
+                state,
+                replay.local_player_index,
+                pairs,
+                last_committed_remote_input.clone(),
+                &[],
+            )?;
+            *state = committed_state;
+        }
+
+        if states[0].as_slice() != states[1].as_slice() {
+            anyhow::bail!("runs diverged by tick {}", tick);
+        }
+
+        let state_hashes = [runs[0].take_state_hashes(), runs[1].take_state_hashes()];
+        if state_hashes[0] != state_hashes[1] {
+            anyhow::bail!("runs' state hashes diverged by tick {}", tick);
+        }
+
+        // The live side may have missed some, e.g. if it only started hashing partway through, so only compare the ticks both have.
+        let state_hashes = state_hashes[0]
+            .iter()
+            .cloned()
+            .collect::<std::collections::HashMap<_, _>>();
+        while let Some((recorded_tick, recorded_hash)) =
+            recorded_state_hashes.next_if(|(recorded_tick, _)| *recorded_tick < tick)
+        {
+            if let Some(hash) = state_hashes.get(recorded_tick) {
+                if hash != recorded_hash {
+                    anyhow::bail!(
+                        "state hash at tick {} does not match the one recorded live: recorded {}, actual {}",
+                        recorded_tick,
+                        tango::replay::hex(recorded_hash),
+                        tango::replay::hex(hash)
+                    );
+                }
+            }
+        }
+
+        while let Some(keyframe) = keyframes.next_if(|keyframe| keyframe.input_index <= stop) {
+            if keyframe.input_index == stop && keyframe.state.as_slice() != states[0].as_slice() {
+                anyhow::bail!(
+                    "state at tick {} does not match the keyframe recorded live",
+                    keyframe.tick
+                );
+            }
+        }
+
+        start = stop;
+        num_compared += 1;
+    }
+
+    Ok(num_compared)
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    env_logger::Builder::from_default_env()
+        .filter(Some("tango"), log::LevelFilter::Info)
+        .filter(Some("replayverify"), log::LevelFilter::Info)
+        .init();
+    mgba::log::init();
+
+    let args = Cli::parse();
+    if args.interval == 0 {
+        anyhow::bail!("interval must be at least 1");
+    }
+
+    let compat_list = tango::compat::load()?;
+    let roms = tango::compat::find_roms(&args.roms_path, &compat_list)?;
+
+    let mut replays = vec![];
+    for path in &args.paths {
+        tango::replay::find_replays(path, &mut replays)?;
+    }
+    replays.sort();
+
+    let mut num_failed = 0;
+    for replay_path in &replays {
+        match verify(replay_path, &roms, &compat_list, args.interval) {
+            Ok(num_compared) => {
+                log::info!(
+                    "{}: ok, compared {} states",
+                    replay_path.display(),
+                    num_compared
+                );
+            }
+            Err(e) => {
+                log::error!("{}: {}", replay_path.display(), e);
+                num_failed += 1;
+            }
+        }
+    }
+
+    log::info!("verified {} replays, {} failed", replays.len(), num_failed);
+    if num_failed > 0 {
+        anyhow::bail!("{} replays failed verification", num_failed);
+    }
+
+    Ok(())
+}
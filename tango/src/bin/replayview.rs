@@ -6,10 +6,246 @@ struct Cli {
     #[clap(long)]
     dump: bool,
 
+    /// Start playback from the closest keyframe at or before this tick.
+    #[clap(long)]
+    start_tick: Option<u32>,
+
     #[clap(parse(from_os_str))]
     path: Option<std::path::PathBuf>,
 }
 
+const SPEEDS: &[f32] = &[0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+const NORMAL_SPEED_INDEX: usize = 2;
+
+/// How many input pairs apart the states we can rewind to are saved.
+const REWIND_INTERVAL: usize = 300;
+
+enum Command {
+    Seek(usize),
+    Restart,
+    Rewind,
+}
+
+/// Playback controls, set from the window and acted on by the core thread between frames.
+struct Controls {
+    paused: bool,
+    steps: u32,
+    speed_index: usize,
+    command: Option<Command>,
+}
+
+/// What the overlay shows, published by the core thread after every frame.
+#[derive(Default)]
+struct Status {
+    local_player_index: u8,
+    input_index: usize,
+    input_pair: Option<tango::input::Pair<tango::input::Input>>,
+}
+
+/// Owned by the core thread: the replay being played and the states we can jump back to.
+struct Player {
+    local_player_index: u8,
+    input_pairs: Vec<tango::input::Pair<tango::input::Input>>,
+    ff_state: tango::fastforwarder::State,
+    /// Saved states keyed by the index of the first input pair to play after loading them.
+    saved_states: std::collections::BTreeMap<usize, mgba::state::State>,
+}
+
+impl Player {
+    fn input_index(&self) -> usize {
+        self.input_pairs.len() - self.ff_state.inputs_pairs_left()
+    }
+
+    fn jump(&mut self, core: &mut mgba::core::CoreMutRef, input_index: usize) {
+        let (input_index, state) = self
+            .saved_states
+            .range(..=input_index)
+            .next_back()
+            .map(|(i, state)| (*i, state.clone()))
+            .expect("initial state");
+        self.ff_state.reset(self.local_player_index);
+        for ip in &self.input_pairs[input_index..] {
+            self.ff_state.push_input_pair(ip.clone());
+        }
+        core.load_state(&state).expect("load state");
+    }
+
+    fn after_frame(&mut self, core: &mut mgba::core::CoreMutRef) -> Status {
+        let input_index = self.input_index();
+        if input_index % REWIND_INTERVAL == 0 && !self.saved_states.contains_key(&input_index) {
+            self.saved_states
+                .insert(input_index, core.save_state().expect("save state"));
+        }
+        Status {
+            local_player_index: self.local_player_index,
+            input_index,
+            input_pair: input_index
+                .checked_sub(1)
+                .map(|i| self.input_pairs[i].clone()),
+        }
+    }
+}
+
+struct Overlay {
+    ctx: egui::Context,
+    winit_state: egui_winit::State,
+    screen_descriptor: egui_wgpu_backend::ScreenDescriptor,
+    rpass: egui_wgpu_backend::RenderPass,
+    paint_jobs: Vec<egui::ClippedMesh>,
+    textures: egui::TexturesDelta,
+}
+
+impl Overlay {
+    fn new(width: u32, height: u32, scale_factor: f32, pixels: &pixels::Pixels) -> Self {
+        let max_texture_size = pixels.device().limits().max_texture_dimension_2d as usize;
+        Self {
+            ctx: egui::Context::default(),
+            winit_state: egui_winit::State::from_pixels_per_point(max_texture_size, scale_factor),
+            screen_descriptor: egui_wgpu_backend::ScreenDescriptor {
+                physical_width: width,
+                physical_height: height,
+                scale_factor,
+            },
+            rpass: egui_wgpu_backend::RenderPass::new(
+                pixels.device(),
+                pixels.render_texture_format(),
+                1,
+            ),
+            paint_jobs: Vec::new(),
+            textures: egui::TexturesDelta::default(),
+        }
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        if width > 0 && height > 0 {
+            self.screen_descriptor.physical_width = width;
+            self.screen_descriptor.physical_height = height;
+        }
+    }
+
+    fn prepare(&mut self, window: &winit::window::Window, controls: &Controls, status: &Status) {
+        let raw_input = self.winit_state.take_egui_input(window);
+        let output = self.ctx.run(raw_input, |ctx| {
+            egui::Area::new("overlay")
+                .fixed_pos(egui::pos2(4.0, 4.0))
+                .show(ctx, |ui| {
+                    egui::Frame::dark_canvas(ui.style()).show(ui, |ui| {
+                        ui.monospace(format!(
+                            "{} {}x",
+                            if controls.paused { "paused" } else { "playing" },
+                            SPEEDS[controls.speed_index]
+                        ));
+                        let input_pair = match &status.input_pair {
+                            Some(input_pair) => input_pair,
+                            None => {
+                                ui.monospace("waiting for first input");
+                                return;
+                            }
+                        };
+                        ui.monospace(format!(
+                            "tick {} (input {})",
+                            input_pair.local.local_tick, status.input_index
+                        ));
+                        // Same order as Writer::write_input, so P1 is whoever was player 1 in the match.
+                        let (p1, p2) = if status.local_player_index == 0 {
+                            (&input_pair.local, &input_pair.remote)
+                        } else {
+                            (&input_pair.remote, &input_pair.local)
+                        };
+                        for (name, input) in [("P1", p1), ("P2", p2)] {
+                            ui.monospace(format!(
+                                "{} {:04x} {:<28} screen {:02x}",
+                                name,
+                                input.joyflags,
+                                joyflags_to_string(input.joyflags),
+                                input.custom_screen_state
+                            ));
+                        }
+                    });
+                });
+        });
+
+        self.textures.append(output.textures_delta);
+        self.winit_state
+            .handle_platform_output(window, &self.ctx, output.platform_output);
+        self.paint_jobs = self.ctx.tessellate(output.shapes);
+    }
+
+    fn render(
+        &mut self,
+        encoder: &mut pixels::wgpu::CommandEncoder,
+        render_target: &pixels::wgpu::TextureView,
+        context: &pixels::PixelsContext,
+    ) -> Result<(), egui_wgpu_backend::BackendError> {
+        self.rpass
+            .add_textures(&context.device, &context.queue, &self.textures)?;
+        self.rpass.update_buffers(
+            &context.device,
+            &context.queue,
+            &self.paint_jobs,
+            &self.screen_descriptor,
+        );
+
+        self.rpass.execute(
+            encoder,
+            render_target,
+            &self.paint_jobs,
+            &self.screen_descriptor,
+            None,
+        )?;
+
+        let textures = std::mem::take(&mut self.textures);
+        self.rpass.remove_textures(textures)
+    }
+}
+
+fn joyflags_to_string(joyflags: u16) -> String {
+    [
+        (mgba::input::keys::A, "A"),
+        (mgba::input::keys::B, "B"),
+        (mgba::input::keys::L, "L"),
+        (mgba::input::keys::R, "R"),
+        (mgba::input::keys::SELECT, "SELECT"),
+        (mgba::input::keys::START, "START"),
+        (mgba::input::keys::UP, "UP"),
+        (mgba::input::keys::DOWN, "DOWN"),
+        (mgba::input::keys::LEFT, "LEFT"),
+        (mgba::input::keys::RIGHT, "RIGHT"),
+    ]
+    .into_iter()
+    .filter(|(key, _)| joyflags as u32 & key != 0)
+    .map(|(_, name)| name)
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+fn handle_key(controls: &mut Controls, key: winit::event::VirtualKeyCode) {
+    match key {
+        winit::event::VirtualKeyCode::Space => {
+            controls.paused = !controls.paused;
+            controls.steps = 0;
+        }
+        winit::event::VirtualKeyCode::Right | winit::event::VirtualKeyCode::Period => {
+            if controls.paused {
+                controls.steps += 1;
+            }
+        }
+        winit::event::VirtualKeyCode::Up => {
+            controls.speed_index = (controls.speed_index + 1).min(SPEEDS.len() - 1);
+        }
+        winit::event::VirtualKeyCode::Down => {
+            controls.speed_index = controls.speed_index.saturating_sub(1);
+        }
+        winit::event::VirtualKeyCode::R => {
+            controls.command = Some(Command::Restart);
+        }
+        winit::event::VirtualKeyCode::Left => {
+            controls.command = Some(Command::Rewind);
+        }
+        _ => {}
+    }
+}
+
 fn main() -> Result<(), anyhow::Error> {
     env_logger::Builder::from_default_env()
         .filter(Some("tango"), log::LevelFilter::Info)
@@ -31,6 +267,9 @@ fn main() -> Result<(), anyhow::Error> {
     let mut f = std::fs::File::open(path)?;
 
     let replay = tango::replay::Replay::decode(&mut f)?;
+    if replay.truncated {
+        log::warn!("replay is truncated, it will end early");
+    }
     log::info!(
         "replay is for {} (crc32 = {:08x})",
         replay.state.rom_title(),
@@ -149,26 +388,53 @@ fn main() -> Result<(), anyhow::Error> {
         .build()?
     };
 
-    let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let overlay = {
+        let window_size = window.inner_size();
+        Overlay::new(
+            window_size.width,
+            window_size.height,
+            window.scale_factor() as f32,
+            &pixels,
+        )
+    };
+
+    let start_input_index = match args.start_tick {
+        Some(tick) => {
+            let seek = replay.seek(tick);
+            log::info!("starting from tick {}", seek.tick);
+            replay.input_pairs.len() - seek.input_pairs.len()
+        }
+        None => 0,
+    };
+
     let hooks = tango::hooks::HOOKS
         .get(&compat_list.game_by_id(&id).unwrap().hooks)
         .unwrap();
     hooks.prepare_for_fastforward(core.as_mut());
 
-    {
-        let done = done.clone();
-        core.set_traps(
-            hooks.get_fastforwarder_traps(tango::fastforwarder::State::new(
-                replay.local_player_index,
-                replay.input_pairs,
-                0,
-                0,
-                Box::new(move || {
-                    done.store(true, std::sync::atomic::Ordering::Relaxed);
-                }),
-            )),
-        );
+    let ff_state =
+        tango::fastforwarder::State::new(replay.local_player_index, vec![], 0, 0, Box::new(|| {}));
+    core.set_traps(hooks.get_fastforwarder_traps(ff_state.clone()));
+
+    let mut saved_states = std::collections::BTreeMap::new();
+    saved_states.insert(0, replay.state);
+    for keyframe in replay.keyframes {
+        saved_states.insert(keyframe.input_index, keyframe.state);
     }
+    let player = parking_lot::Mutex::new(Player {
+        local_player_index: replay.local_player_index,
+        input_pairs: replay.input_pairs,
+        ff_state,
+        saved_states,
+    });
+
+    let controls = std::sync::Arc::new(parking_lot::Mutex::new(Controls {
+        paused: false,
+        steps: 0,
+        speed_index: NORMAL_SPEED_INDEX,
+        command: Some(Command::Seek(start_input_index)),
+    }));
+    let status = std::sync::Arc::new(parking_lot::Mutex::new(Status::default()));
 
     let stream = tango::audio::open_stream(
         &audio_device,
@@ -184,43 +450,112 @@ fn main() -> Result<(), anyhow::Error> {
     let thread = mgba::thread::Thread::new(core);
     thread.start();
     thread.handle().pause();
-    thread.handle().run_on_core(|mut core| {
-        core.gba_mut()
-            .sync_mut()
-            .as_mut()
-            .expect("sync")
-            .set_fps_target(60.0);
-    });
     {
         let vbuf = vbuf.clone();
-        thread.set_frame_callback(move |_core, video_buffer| {
-            let mut vbuf = vbuf.lock();
-            vbuf.copy_from_slice(video_buffer);
-            for i in (0..vbuf.len()).step_by(4) {
-                vbuf[i + 3] = 0xff;
+        let controls = controls.clone();
+        let status = status.clone();
+        thread.set_frame_callback(move |mut core, video_buffer| {
+            {
+                let mut vbuf = vbuf.lock();
+                vbuf.copy_from_slice(video_buffer);
+                for i in (0..vbuf.len()).step_by(4) {
+                    vbuf[i + 3] = 0xff;
+                }
+            }
+
+            let mut player = player.lock();
+            *status.lock() = player.after_frame(&mut core);
+
+            loop {
+                let mut controls = controls.lock();
+                core.gba_mut()
+                    .sync_mut()
+                    .as_mut()
+                    .expect("sync")
+                    .set_fps_target(60.0 * SPEEDS[controls.speed_index]);
+
+                if let Some(command) = controls.command.take() {
+                    let input_index = match command {
+                        Command::Seek(input_index) => input_index,
+                        Command::Restart => 0,
+                        Command::Rewind => player.input_index().saturating_sub(REWIND_INTERVAL),
+                    };
+                    player.jump(&mut core, input_index);
+                    break;
+                }
+
+                // Once the inputs run out the game would carry on without them, so stop there.
+                if player.ff_state.inputs_pairs_left() == 0 {
+                    controls.paused = true;
+                    controls.steps = 0;
+                }
+
+                if !controls.paused {
+                    break;
+                }
+
+                if controls.steps > 0 {
+                    controls.steps -= 1;
+                    break;
+                }
+
+                drop(controls);
+                std::thread::sleep(std::time::Duration::from_millis(1));
             }
         });
     }
-    thread.handle().run_on_core(move |mut core| {
-        core.load_state(&replay.state).expect("load state");
-    });
     thread.handle().unpause();
 
     {
         let vbuf = vbuf.clone();
+        let mut overlay = overlay;
         event_loop.run(move |event, _, control_flow| {
             *control_flow = winit::event_loop::ControlFlow::Poll;
 
-            if done.load(std::sync::atomic::Ordering::Relaxed) {
-                *control_flow = winit::event_loop::ControlFlow::Exit;
-                return;
-            }
-
             match event {
+                winit::event::Event::WindowEvent {
+                    event: ref window_event,
+                    ..
+                } => {
+                    match window_event {
+                        winit::event::WindowEvent::CloseRequested => {
+                            *control_flow = winit::event_loop::ControlFlow::Exit;
+                        }
+                        winit::event::WindowEvent::Resized(size) => {
+                            pixels.resize_surface(size.width, size.height);
+                            overlay.resize(size.width, size.height);
+                        }
+                        winit::event::WindowEvent::KeyboardInput {
+                            input:
+                                winit::event::KeyboardInput {
+                                    state: winit::event::ElementState::Pressed,
+                                    virtual_keycode: Some(key),
+                                    ..
+                                },
+                            ..
+                        } => {
+                            handle_key(&mut controls.lock(), *key);
+                        }
+                        _ => {}
+                    }
+                    overlay.winit_state.on_event(&overlay.ctx, window_event);
+                }
                 winit::event::Event::MainEventsCleared => {
                     let vbuf = vbuf.lock().clone();
                     pixels.get_frame().copy_from_slice(&vbuf);
-                    pixels.render().expect("render pixels");
+
+                    {
+                        let controls = controls.lock();
+                        let status = status.lock();
+                        overlay.prepare(&window, &controls, &status);
+                    }
+                    pixels
+                        .render_with(|encoder, render_target, context| {
+                            context.scaling_renderer.render(encoder, render_target);
+                            overlay.render(encoder, render_target, context)?;
+                            Ok(())
+                        })
+                        .expect("render pixels");
                 }
                 _ => {}
             }
@@ -0,0 +1,369 @@
+use clap::Parser;
+use cpal::traits::{HostTrait, StreamTrait};
+
+#[derive(clap::Parser)]
+struct Cli {
+    /// Link code of the match to watch.
+    session_id: String,
+
+    /// How many ticks to stay behind the match, so that late inputs don't stall playback.
+    #[clap(long, default_value = "180")]
+    delay: usize,
+}
+
+fn find_rom(
+    compat_list: &tango::compat::CompatList,
+    title: &str,
+    crc32: u32,
+) -> anyhow::Result<(String, std::path::PathBuf)> {
+    std::fs::read_dir("roms")?
+        .flat_map(|dirent| {
+            let dirent = dirent.as_ref().expect("dirent");
+            let mut core = mgba::core::Core::new_gba("tango").expect("new_gba");
+            let vf = match mgba::vfile::VFile::open(&dirent.path(), mgba::vfile::flags::O_RDONLY) {
+                Ok(vf) => vf,
+                Err(e) => {
+                    log::warn!(
+                        "failed to open {} for probing: {}",
+                        dirent.path().display(),
+                        e
+                    );
+                    return vec![];
+                }
+            };
+
+            if let Err(e) = core.as_mut().load_rom(vf) {
+                log::warn!(
+                    "failed to load {} for probing: {}",
+                    dirent.path().display(),
+                    e
+                );
+                return vec![];
+            }
+
+            if core.as_ref().game_title() != title || core.as_ref().crc32() != crc32 {
+                return vec![];
+            }
+
+            match compat_list.id_by_title_and_crc32(title, crc32) {
+                Some(id) => vec![(id.to_string(), dirent.path())],
+                None => {
+                    log::warn!(
+                        "could not find compatibility data for {} where title = {}, crc32 = {:08x}",
+                        dirent.path().display(),
+                        title,
+                        crc32
+                    );
+                    vec![]
+                }
+            }
+        })
+        .next()
+        .ok_or_else(|| anyhow::format_err!("could not find eligible rom"))
+}
+
+/// A battle for the core thread to cut over to at the end of its next frame.
+struct NextBattle {
+    local_player_index: u8,
+    state: mgba::state::State,
+    input_pairs: Vec<tango::input::Pair<tango::input::Input>>,
+}
+
+/// Plays the feed back onto the core, keeping `delay` input pairs in reserve.
+///
+/// The core thread's frame callback blocks while `feeding` is set and no inputs are queued, so the game never runs a battle tick without the real inputs for it.
+fn feed(
+    events: impl Iterator<Item = tango::spectator::Event>,
+    next_battle_slot: std::sync::Arc<parking_lot::Mutex<Option<NextBattle>>>,
+    ff_state: tango::fastforwarder::State,
+    feeding: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    delay: usize,
+) {
+    let mut pending = std::collections::VecDeque::new();
+    let mut next_battle = None;
+    let mut ended = false;
+
+    for event in events {
+        match event {
+            tango::spectator::Event::StartBattle {
+                battle_number,
+                local_player_index,
+                state,
+            } => {
+                log::info!("battle {} is starting", battle_number);
+                if next_battle.is_some() {
+                    log::warn!("battle started before the previous one could be shown");
+                }
+                next_battle = Some((local_player_index, state));
+                pending.clear();
+                ended = false;
+            }
+            tango::spectator::Event::InputPairs(input_pairs) => {
+                pending.extend(input_pairs);
+            }
+            tango::spectator::Event::EndBattle => {
+                ended = true;
+            }
+        }
+
+        if next_battle.is_some() && (pending.len() > delay || ended) {
+            let (local_player_index, state) = next_battle.take().unwrap();
+
+            // Let the previous battle play out before cutting over.
+            while ff_state.inputs_pairs_left() > 0 {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            feeding.store(false, std::sync::atomic::Ordering::Relaxed);
+
+            let n = if ended {
+                pending.len()
+            } else {
+                pending.len() - delay
+            };
+            *next_battle_slot.lock() = Some(NextBattle {
+                local_player_index,
+                state,
+                input_pairs: pending.drain(..n).collect(),
+            });
+
+            // Anything we queue before the cutover would be thrown away by it.
+            while next_battle_slot.lock().is_some() {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        }
+
+        if next_battle.is_some() {
+            continue;
+        }
+
+        while pending.len() > delay || (ended && !pending.is_empty()) {
+            ff_state.push_input_pair(pending.pop_front().unwrap());
+        }
+
+        if ended {
+            feeding.store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    log::info!("spectator feed ended");
+    feeding.store(false, std::sync::atomic::Ordering::Relaxed);
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    env_logger::Builder::from_default_env()
+        .filter(Some("tango"), log::LevelFilter::Info)
+        .filter(Some("spectate"), log::LevelFilter::Info)
+        .init();
+    mgba::log::init();
+
+    let args = Cli::parse();
+
+    let compat_list = std::sync::Arc::new(tango::compat::load()?);
+    let config = tango::config::load()?;
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    log::info!("connecting to spectate {}", args.session_id);
+    let transport = rt.block_on(tango::spectator::connect(
+        &config.matchmaking.connect_addr,
+        &config.webrtc,
+        &args.session_id,
+    ))?;
+
+    let (event_tx, event_rx) = std::sync::mpsc::channel();
+    rt.spawn(async move {
+        let mut receiver = tango::spectator::Receiver::new();
+        loop {
+            let p = match transport.receive().await {
+                Ok(Some(p)) => p,
+                Ok(None) => {
+                    break;
+                }
+                Err(e) => {
+                    log::error!("failed to receive from host: {:?}", e);
+                    break;
+                }
+            };
+            match receiver.handle_packet(p) {
+                Ok(Some(event)) => {
+                    if event_tx.send(event).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::error!("bad packet from host: {:?}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    let first_event = loop {
+        if let event @ tango::spectator::Event::StartBattle { .. } = event_rx.recv()? {
+            break event;
+        }
+    };
+
+    let (local_player_index, rom_title, rom_crc32) = match &first_event {
+        tango::spectator::Event::StartBattle {
+            local_player_index,
+            state,
+            ..
+        } => (*local_player_index, state.rom_title(), state.rom_crc32()),
+        _ => unreachable!(),
+    };
+    log::info!("match is for {} (crc32 = {:08x})", rom_title, rom_crc32);
+
+    let (id, rom_path) = find_rom(&compat_list, &rom_title, rom_crc32)?;
+    log::info!("found rom {}: {}", id, rom_path.display());
+
+    let mut core = mgba::core::Core::new_gba("tango")?;
+    let vf = mgba::vfile::VFile::open(&rom_path, mgba::vfile::flags::O_RDONLY)?;
+    core.as_mut().load_rom(vf)?;
+    core.enable_video_buffer();
+
+    let vbuf = std::sync::Arc::new(parking_lot::Mutex::new(vec![
+        0u8;
+        (mgba::gba::SCREEN_WIDTH * mgba::gba::SCREEN_HEIGHT * 4)
+            as usize
+    ]));
+
+    let audio_device = cpal::default_host()
+        .default_output_device()
+        .ok_or_else(|| anyhow::format_err!("could not open audio device"))?;
+
+    let supported_config = tango::audio::get_supported_config(&audio_device)?;
+    log::info!("selected audio config: {:?}", supported_config);
+
+    let event_loop = winit::event_loop::EventLoop::new();
+
+    let window = {
+        let size =
+            winit::dpi::LogicalSize::new(mgba::gba::SCREEN_WIDTH * 3, mgba::gba::SCREEN_HEIGHT * 3);
+        winit::window::WindowBuilder::new()
+            .with_title("tango spectate")
+            .with_inner_size(size)
+            .with_min_inner_size(size)
+            .build(&event_loop)?
+    };
+
+    let mut pixels = {
+        let window_size = window.inner_size();
+        let surface_texture =
+            pixels::SurfaceTexture::new(window_size.width, window_size.height, &window);
+        pixels::PixelsBuilder::new(
+            mgba::gba::SCREEN_WIDTH,
+            mgba::gba::SCREEN_HEIGHT,
+            surface_texture,
+        )
+        .build()?
+    };
+
+    let hooks = tango::hooks::HOOKS
+        .get(&compat_list.game_by_id(&id).unwrap().hooks)
+        .unwrap();
+    hooks.prepare_for_fastforward(core.as_mut());
+
+    let ff_state =
+        tango::fastforwarder::State::new(local_player_index, vec![], 0, 0, Box::new(|| {}));
+    core.set_traps(hooks.get_fastforwarder_traps(ff_state.clone()));
+
+    let stream = tango::audio::open_stream(
+        &audio_device,
+        &supported_config,
+        tango::audio::timewarp_stream::TimewarpStream::new(
+            &core,
+            supported_config.sample_rate(),
+            supported_config.channels(),
+        ),
+    )?;
+    stream.play()?;
+
+    let feeding = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    let thread = mgba::thread::Thread::new(core);
+    thread.start();
+    thread.handle().pause();
+    thread.handle().run_on_core(|mut core| {
+        core.gba_mut()
+            .sync_mut()
+            .as_mut()
+            .expect("sync")
+            .set_fps_target(60.0);
+    });
+    let next_battle_slot = std::sync::Arc::new(parking_lot::Mutex::new(None::<NextBattle>));
+    {
+        let vbuf = vbuf.clone();
+        let ff_state = ff_state.clone();
+        let feeding = feeding.clone();
+        let next_battle_slot = next_battle_slot.clone();
+        thread.set_frame_callback(move |mut core, video_buffer| {
+            {
+                let mut vbuf = vbuf.lock();
+                vbuf.copy_from_slice(video_buffer);
+                for i in (0..vbuf.len()).step_by(4) {
+                    vbuf[i + 3] = 0xff;
+                }
+            }
+
+            loop {
+                if let Some(next_battle) = next_battle_slot.lock().take() {
+                    ff_state.reset(next_battle.local_player_index);
+                    for ip in next_battle.input_pairs {
+                        ff_state.push_input_pair(ip);
+                    }
+                    core.load_state(&next_battle.state).expect("load state");
+                    feeding.store(true, std::sync::atomic::Ordering::Relaxed);
+                    break;
+                }
+
+                if !feeding.load(std::sync::atomic::Ordering::Relaxed)
+                    || ff_state.inputs_pairs_left() > 0
+                {
+                    break;
+                }
+
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        });
+    }
+    thread.handle().unpause();
+
+    {
+        let delay = args.delay;
+        std::thread::spawn(move || {
+            feed(
+                std::iter::once(first_event).chain(event_rx),
+                next_battle_slot,
+                ff_state,
+                feeding,
+                delay,
+            );
+        });
+    }
+
+    {
+        let vbuf = vbuf.clone();
+        event_loop.run(move |event, _, control_flow| {
+            *control_flow = winit::event_loop::ControlFlow::Poll;
+
+            match event {
+                winit::event::Event::WindowEvent {
+                    event: winit::event::WindowEvent::CloseRequested,
+                    ..
+                } => {
+                    *control_flow = winit::event_loop::ControlFlow::Exit;
+                }
+                winit::event::Event::MainEventsCleared => {
+                    let vbuf = vbuf.lock().clone();
+                    pixels.get_frame().copy_from_slice(&vbuf);
+                    pixels.render().expect("render pixels");
+                }
+                _ => {}
+            }
+        });
+    }
+}
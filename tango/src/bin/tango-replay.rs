@@ -0,0 +1,191 @@
+use clap::Parser;
+
+#[derive(clap::Parser)]
+enum Cli {
+    /// Convert a replay to JSON or CBOR.
+    Export {
+        #[clap(parse(from_os_str))]
+        path: std::path::PathBuf,
+
+        /// Where to write the document to. Defaults to stdout.
+        #[clap(parse(from_os_str))]
+        output_path: Option<std::path::PathBuf>,
+
+        /// json or cbor. Defaults to the output path's extension, or json.
+        #[clap(long)]
+        format: Option<String>,
+    },
+
+    /// Convert a JSON or CBOR document back to a replay.
+    Import {
+        #[clap(parse(from_os_str))]
+        path: std::path::PathBuf,
+
+        #[clap(parse(from_os_str))]
+        output_path: std::path::PathBuf,
+
+        /// json or cbor. Defaults to the input path's extension, or json.
+        #[clap(long)]
+        format: Option<String>,
+    },
+
+    /// Rewrite every replay under a folder that's in an older version in the newest one, keeping the originals as backups next to them.
+    Migrate {
+        #[clap(parse(from_os_str), default_value = "replays")]
+        path: std::path::PathBuf,
+
+        /// Only list what would be migrated.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Also migrate signed replays, which loses their signature, and truncated ones, which are written back out as if they were complete.
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+const BACKUP_EXTENSION: &str = "tangoreplay.bak";
+
+enum Migration {
+    UpToDate,
+    Migrated,
+    /// Migrating would lose something, e.g. the signature.
+    Skipped(&'static str),
+}
+
+fn migrate(path: &std::path::Path, dry_run: bool, force: bool) -> anyhow::Result<Migration> {
+    let replay = tango::replay::Replay::decode(std::fs::File::open(path)?)?;
+    if replay.is_latest_version() {
+        return Ok(Migration::UpToDate);
+    }
+    if !force {
+        if replay.signature.is_some() {
+            return Ok(Migration::Skipped("it is signed"));
+        }
+        if replay.truncated {
+            return Ok(Migration::Skipped("it is truncated"));
+        }
+    }
+    if dry_run {
+        return Ok(Migration::Migrated);
+    }
+
+    let backup_path = path.with_extension(BACKUP_EXTENSION);
+    if backup_path.exists() {
+        anyhow::bail!("backup {} already exists", backup_path.display());
+    }
+
+    // Write the new version next to the original first, so nothing is lost if writing it fails.
+    let tmp_path = path.with_extension("tangoreplay.tmp");
+    if let Err(e) = replay.upgrade(Box::new(std::fs::File::create(&tmp_path)?)) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    tango::replay::Replay::decode(std::fs::File::open(&tmp_path)?)?;
+
+    std::fs::rename(path, &backup_path)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(Migration::Migrated)
+}
+
+fn parse_format(
+    format: Option<String>,
+    path: Option<&std::path::Path>,
+) -> anyhow::Result<tango::replay::interchange::Format> {
+    Ok(match format.as_deref() {
+        Some("json") => tango::replay::interchange::Format::Json,
+        Some("cbor") => tango::replay::interchange::Format::Cbor,
+        Some(format) => anyhow::bail!("unknown format: {}", format),
+        None => path
+            .and_then(tango::replay::interchange::Format::from_path)
+            .unwrap_or(tango::replay::interchange::Format::Json),
+    })
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    env_logger::Builder::from_default_env()
+        .filter(Some("tango"), log::LevelFilter::Info)
+        .filter(Some("tango_replay"), log::LevelFilter::Info)
+        .init();
+
+    match Cli::parse() {
+        Cli::Export {
+            path,
+            output_path,
+            format,
+        } => {
+            let format = parse_format(format, output_path.as_deref())?;
+            let replay = tango::replay::Replay::decode(std::fs::File::open(&path)?)?;
+            let document = tango::replay::interchange::Document::from_replay(&replay);
+            match output_path {
+                Some(output_path) => document.encode(
+                    format,
+                    std::io::BufWriter::new(std::fs::File::create(&output_path)?),
+                )?,
+                None => document.encode(format, std::io::stdout().lock())?,
+            }
+        }
+        Cli::Import {
+            path,
+            output_path,
+            format,
+        } => {
+            let format = parse_format(format, Some(&path))?;
+            let document = tango::replay::interchange::Document::decode(
+                format,
+                std::io::BufReader::new(std::fs::File::open(&path)?),
+            )?;
+            document.write_replay(Box::new(std::fs::File::create(&output_path)?))?;
+        }
+        Cli::Migrate {
+            path,
+            dry_run,
+            force,
+        } => {
+            let mut replays = vec![];
+            tango::replay::find_replays(&path, &mut replays)?;
+            replays.sort();
+
+            let mut num_migrated = 0;
+            let mut num_skipped = 0;
+            let mut num_failed = 0;
+            for replay_path in replays {
+                match migrate(&replay_path, dry_run, force) {
+                    Ok(Migration::Migrated) => {
+                        log::info!(
+                            "{} {}",
+                            if dry_run { "would migrate" } else { "migrated" },
+                            replay_path.display()
+                        );
+                        num_migrated += 1;
+                    }
+                    Ok(Migration::UpToDate) => {}
+                    Ok(Migration::Skipped(reason)) => {
+                        log::warn!(
+                            "skipped {} since {}, pass --force to migrate it anyway",
+                            replay_path.display(),
+                            reason
+                        );
+                        num_skipped += 1;
+                    }
+                    Err(e) => {
+                        log::error!("failed to migrate {}: {}", replay_path.display(), e);
+                        num_failed += 1;
+                    }
+                }
+            }
+            log::info!(
+                "{} {} replays, {} skipped, {} failed",
+                if dry_run { "would migrate" } else { "migrated" },
+                num_migrated,
+                num_skipped,
+                num_failed
+            );
+            if num_failed > 0 {
+                anyhow::bail!("failed to migrate {} replays", num_failed);
+            }
+        }
+    }
+
+    Ok(())
+}
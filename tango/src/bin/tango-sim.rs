@@ -0,0 +1,97 @@
+use clap::Parser;
+
+#[derive(clap::Parser)]
+struct Cli {
+    #[clap(parse(from_os_str))]
+    p1_rom_path: std::path::PathBuf,
+
+    #[clap(parse(from_os_str))]
+    p1_save_path: std::path::PathBuf,
+
+    #[clap(parse(from_os_str))]
+    p2_rom_path: std::path::PathBuf,
+
+    #[clap(parse(from_os_str))]
+    p2_save_path: std::path::PathBuf,
+
+    #[clap(long, parse(from_os_str))]
+    p1_state: Option<std::path::PathBuf>,
+
+    #[clap(long, parse(from_os_str))]
+    p2_state: Option<std::path::PathBuf>,
+
+    #[clap(long, parse(from_os_str))]
+    p1_script: Option<std::path::PathBuf>,
+
+    #[clap(long, parse(from_os_str))]
+    p2_script: Option<std::path::PathBuf>,
+
+    #[clap(long, default_value = "sim")]
+    session_id: String,
+
+    #[clap(long, default_value = "3")]
+    input_delay: u32,
+
+    #[clap(long, default_value = "1")]
+    battles: usize,
+
+    #[clap(long, default_value = "216000")]
+    max_frames: u32,
+
+    #[clap(long, default_value = "0")]
+    seed: u64,
+
+    #[clap(long, parse(from_os_str), default_value = "sim")]
+    replays_path: std::path::PathBuf,
+}
+
+fn load_script(path: &Option<std::path::PathBuf>) -> Result<tango::sim::Script, anyhow::Error> {
+    Ok(match path {
+        Some(path) => tango::sim::Script::load(path)?,
+        None => tango::sim::Script::default(),
+    })
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    env_logger::Builder::from_default_env()
+        .filter(Some("tango"), log::LevelFilter::Info)
+        .filter(Some("tango_sim"), log::LevelFilter::Info)
+        .init();
+    mgba::log::init();
+
+    let args = Cli::parse();
+
+    let report = tango::sim::run(tango::sim::Settings {
+        sides: [
+            tango::sim::Side {
+                rom_path: args.p1_rom_path,
+                save_path: args.p1_save_path,
+                state_path: args.p1_state,
+                script: load_script(&args.p1_script)?,
+            },
+            tango::sim::Side {
+                rom_path: args.p2_rom_path,
+                save_path: args.p2_save_path,
+                state_path: args.p2_state,
+                script: load_script(&args.p2_script)?,
+            },
+        ],
+        session_id: args.session_id,
+        input_delay: args.input_delay,
+        battles: args.battles,
+        max_frames: args.max_frames,
+        seed: args.seed,
+        replays_path: args.replays_path,
+    })?;
+
+    for battle in report.battles {
+        log::info!(
+            "battle {}: in sync ({} / {} committed ticks)",
+            battle.number,
+            battle.committed_ticks[0],
+            battle.committed_ticks[1]
+        );
+    }
+
+    Ok(())
+}
@@ -57,6 +57,14 @@ impl CompatList {
         self.games.get(&id.to_string())
     }
 
+    /// Names the group of games the given game is compatible with by the first ID in it, in sorted order.
+    pub fn compat_group(&self, id: &str) -> Option<String> {
+        self.compatibility
+            .iter()
+            .find(|ids| ids.contains(id))
+            .and_then(|ids| ids.iter().min().cloned())
+    }
+
     pub fn is_compatible(&self, id1: &str, id2: &str) -> bool {
         self.compatibility
             .iter()
@@ -71,3 +79,43 @@ pub fn load() -> anyhow::Result<CompatList> {
         COMPAT_FILE,
     )?)?))
 }
+
+/// Finds the game ID and path of every ROM we have compatibility data for, by title and CRC32.
+pub fn find_roms(
+    roms_path: &std::path::Path,
+    compat_list: &CompatList,
+) -> anyhow::Result<std::collections::HashMap<(String, u32), (String, std::path::PathBuf)>> {
+    let mut roms = std::collections::HashMap::new();
+    for dirent in std::fs::read_dir(roms_path)? {
+        let path = dirent?.path();
+        let mut core = mgba::core::Core::new_gba("tango")?;
+        let vf = match mgba::vfile::VFile::open(&path, mgba::vfile::flags::O_RDONLY) {
+            Ok(vf) => vf,
+            Err(e) => {
+                log::warn!("failed to open {} for probing: {}", path.display(), e);
+                continue;
+            }
+        };
+        if let Err(e) = core.as_mut().load_rom(vf) {
+            log::warn!("failed to load {} for probing: {}", path.display(), e);
+            continue;
+        }
+
+        let title = core.as_ref().game_title();
+        let crc32 = core.as_ref().crc32();
+        let id = match compat_list.id_by_title_and_crc32(&title, crc32) {
+            Some(id) => id.to_string(),
+            None => {
+                log::warn!(
+                    "could not find compatibility data for {} where title = {}, crc32 = {:08x}",
+                    path.display(),
+                    title,
+                    crc32
+                );
+                continue;
+            }
+        };
+        roms.insert((title, crc32), (id, path));
+    }
+    Ok(roms)
+}
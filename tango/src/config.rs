@@ -31,14 +31,37 @@ impl Default for Keymapping {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ICECredentialType {
+    Password,
+    OAuth,
+}
+
+impl Default for ICECredentialType {
+    fn default() -> Self {
+        Self::Password
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ICEServer {
     pub urls: Vec<String>,
+    /// Only used by TURN servers.
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub credential: String,
+    #[serde(default)]
+    pub credential_type: ICECredentialType,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WebRTC {
     pub ice_servers: Vec<ICEServer>,
+    /// Send inputs over an unordered data channel that never retransmits, relying on each packet repeating unacknowledged inputs instead.
+    #[serde(default)]
+    pub unreliable_inputs: bool,
 }
 
 impl Default for WebRTC {
@@ -47,20 +70,26 @@ impl Default for WebRTC {
             ice_servers: vec![
                 ICEServer {
                     urls: vec!["stun:stun.l.google.com:19302".to_owned()],
+                    ..Default::default()
                 },
                 ICEServer {
                     urls: vec!["stun:stun1.l.google.com:19302".to_owned()],
+                    ..Default::default()
                 },
                 ICEServer {
                     urls: vec!["stun:stun2.l.google.com:19302".to_owned()],
+                    ..Default::default()
                 },
                 ICEServer {
                     urls: vec!["stun:stun3.l.google.com:19302".to_owned()],
+                    ..Default::default()
                 },
                 ICEServer {
                     urls: vec!["stun:stun4.l.google.com:19302".to_owned()],
+                    ..Default::default()
                 },
             ],
+            unreliable_inputs: false,
         }
     }
 }
@@ -74,7 +103,16 @@ impl WebRTC {
                 .map(
                     |ice_server| webrtc::ice_transport::ice_server::RTCIceServer {
                         urls: ice_server.urls.clone(),
-                        ..Default::default()
+                        username: ice_server.username.clone(),
+                        credential: ice_server.credential.clone(),
+                        credential_type: match ice_server.credential_type {
+                            ICECredentialType::Password => {
+                                webrtc::ice_transport::ice_credential_type::RTCIceCredentialType::Password
+                            }
+                            ICECredentialType::OAuth => {
+                                webrtc::ice_transport::ice_credential_type::RTCIceCredentialType::Oauth
+                            }
+                        },
                     },
                 )
                 .collect(),
@@ -98,11 +136,62 @@ impl Default for Matchmaking {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lan {
+    /// Where we wait for someone to dial us while hosting.
+    pub bind_addr: String,
+    /// Every instance on the local network needs to use the same one.
+    pub discovery_port: u16,
+}
+
+impl Default for Lan {
+    fn default() -> Self {
+        Self {
+            bind_addr: "0.0.0.0:1985".to_owned(),
+            discovery_port: 1986,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Transport {
+    #[serde(rename = "webrtc")]
+    WebRTC,
+    Udp {
+        bind_addr: String,
+        peer_addr: String,
+    },
+    Tcp {
+        bind_addr: String,
+        peer_addr: Option<String>,
+    },
+    /// Only connects to other matches within the same process.
+    Memory,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::WebRTC
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Config {
+    #[serde(default)]
+    pub nickname: String,
+    /// Where to keep the key replays are signed with, generated on first use. Replays are left unsigned if unset.
+    ///
+    /// This has to come before any tables, since TOML can't have plain values after them.
+    #[serde(default)]
+    pub replay_signing_key_path: Option<std::path::PathBuf>,
     pub keymapping: Keymapping,
     pub matchmaking: Matchmaking,
     pub webrtc: WebRTC,
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default)]
+    pub lan: Lan,
 }
 
 const CONFIG_FILE: &str = "tango.toml";
@@ -1,10 +1,15 @@
-use crate::{audio, battle, compat, config, fastforwarder, gui, input, loaded};
+use crate::transport;
+use crate::{
+    audio, battle, compat, config, fastforwarder, gui, input, lan, loaded, protocol, replay,
+    telemetry,
+};
 
 pub struct BattleStateFacadeGuard<'a> {
     m: &'a battle::Match,
     guard: tokio::sync::MutexGuard<'a, battle::BattleState>,
     audio_state_holder: std::sync::Arc<parking_lot::Mutex<Option<mgba::state::State>>>,
     fastforwarder: std::sync::Arc<parking_lot::Mutex<fastforwarder::Fastforwarder>>,
+    gui_state: std::sync::Arc<gui::State>,
 }
 
 impl<'a> BattleStateFacadeGuard<'a> {
@@ -33,6 +38,11 @@ impl<'a> BattleStateFacadeGuard<'a> {
         custom_screen_state: u8,
         turn: Vec<u8>,
     ) -> bool {
+        if self.m.connection_state() == battle::ConnectionState::Disconnected {
+            log::warn!("connection is gone, not adding any more inputs");
+            return false;
+        }
+
         let fastforwarder = self.fastforwarder.clone();
         let battle_number = self.guard.number;
 
@@ -58,19 +68,15 @@ impl<'a> BattleStateFacadeGuard<'a> {
         }
 
         self.m
-            .transport()
-            .await
-            .expect("transport not available")
-            .send_input(
+            .send_input(protocol::Input {
                 battle_number,
                 local_tick,
                 remote_tick,
                 joyflags,
                 custom_screen_state,
                 turn,
-            )
-            .await
-            .expect("send input");
+            })
+            .await;
 
         let (input_pairs, left) = battle.consume_and_peek_local();
 
@@ -81,7 +87,12 @@ impl<'a> BattleStateFacadeGuard<'a> {
                 .expect("write input");
         }
 
+        if let Some(broadcaster) = self.m.broadcaster() {
+            broadcaster.add_input_pairs(battle_number, &input_pairs);
+        }
+
         let mut fastforwarder = fastforwarder.lock();
+        let fastforward_start = std::time::Instant::now();
         let (committed_state, dirty_state, last_input) = fastforwarder
             .fastforward(
                 battle.committed_state().as_ref().expect("committed state"),
@@ -91,6 +102,19 @@ impl<'a> BattleStateFacadeGuard<'a> {
                 &left,
             )
             .expect("fastforward");
+        let fastforward_time = fastforward_start.elapsed();
+        let state_hashes = fastforwarder.take_state_hashes();
+
+        if let Some(ip) = input_pairs.last() {
+            // The committed state is from right before the input for the tick after the last committed one.
+            let tick = ip.local.local_tick + 1;
+            if battle.replay_writer().wants_keyframe(tick) {
+                battle
+                    .replay_writer()
+                    .write_keyframe(tick, &committed_state)
+                    .expect("write keyframe");
+            }
+        }
 
         core.load_state(&dirty_state).expect("load dirty state");
 
@@ -99,15 +123,53 @@ impl<'a> BattleStateFacadeGuard<'a> {
         battle.set_committed_state(committed_state);
         battle.set_last_input(last_input);
 
-        core.gba_mut()
-            .sync_mut()
-            .expect("set fps target")
-            .set_fps_target((loaded::EXPECTED_FPS as i32 + battle.tps_adjustment()) as f32);
+        for (tick, hash) in state_hashes {
+            if !battle.add_local_state_hash(tick, hash.clone()) {
+                continue;
+            }
+            battle
+                .replay_writer()
+                .write_state_hash(tick, &hash)
+                .expect("write state hash");
+            self.m
+                .send_state_hash(protocol::StateHash {
+                    battle_number,
+                    tick,
+                    hash,
+                })
+                .await;
+        }
+
+        if let Some(tick) = battle.take_desync_notification() {
+            self.gui_state.report_desync(gui::Desync {
+                battle_number,
+                tick,
+            });
+        }
+
+        let tps_adjustment = battle.tps_adjustment();
+        battle.record_telemetry(
+            &telemetry::Frame {
+                tick: current_tick,
+                rollback_depth: left.len(),
+                fastforward_time,
+                stalled: input_pairs.is_empty(),
+                local_qlen: battle.local_queue_length(),
+                remote_qlen: battle.remote_queue_length(),
+                tps_adjustment,
+            },
+            &self.m.connection_stats(),
+        );
+
+        if let Some(mut sync) = core.gba_mut().sync_mut() {
+            sync.set_fps_target((loaded::EXPECTED_FPS as i32 + tps_adjustment) as f32);
+        }
 
         true
     }
 
     pub fn set_committed_state(&mut self, state: mgba::state::State) {
+        let battle_number = self.guard.number;
         let battle = self
             .guard
             .battle
@@ -118,6 +180,9 @@ impl<'a> BattleStateFacadeGuard<'a> {
                 .replay_writer()
                 .write_state(&state)
                 .expect("write state");
+            if let Some(broadcaster) = self.m.broadcaster() {
+                broadcaster.start_battle(battle_number, battle.local_player_index(), state.clone());
+            }
         }
         battle.set_committed_state(state);
     }
@@ -157,12 +222,12 @@ impl<'a> BattleStateFacadeGuard<'a> {
             .local_delay();
 
         self.m
-            .transport()
-            .await
-            .expect("no transport")
-            .send_init(self.guard.number, local_delay, init)
-            .await
-            .expect("send init");
+            .send_init(protocol::Init {
+                battle_number: self.guard.number,
+                input_delay: local_delay,
+                marshaled: init.to_vec(),
+            })
+            .await;
         log::info!("sent local init: {:?}", init);
     }
 
@@ -187,7 +252,7 @@ impl<'a> BattleStateFacadeGuard<'a> {
             .battle
             .as_mut()
             .expect("attempted to get battle information while no battle was active!")
-            .set_remote_delay(init.input_delay);
+            .agree_on_delay(init.input_delay);
 
         Some(init.marshaled)
     }
@@ -246,6 +311,9 @@ impl<'a> BattleStateFacadeGuard<'a> {
 
     pub fn set_won_last_battle(&mut self, did_win: bool) {
         self.guard.won_last_battle = did_win;
+        if let Some(battle) = self.guard.battle.as_mut() {
+            battle.set_won(did_win);
+        }
     }
 }
 
@@ -258,6 +326,7 @@ pub struct MatchStateFacadeGuard<'a> {
     primary_mux_handle: audio::mux_stream::MuxHandle,
     audio_core_mux_handle: audio::mux_stream::MuxHandle,
     config: std::sync::Arc<parking_lot::Mutex<config::Config>>,
+    gui_state: std::sync::Arc<gui::State>,
 }
 
 impl<'a> MatchStateFacadeGuard<'a> {
@@ -294,20 +363,101 @@ impl<'a> MatchStateFacadeGuard<'a> {
         s: gui::ConnectRequest,
     ) {
         let config = self.config.lock();
+        let input_delay = if s.auto_input_delay {
+            battle::InputDelay::Auto {
+                renegotiate: s.renegotiate_input_delay,
+            }
+        } else {
+            battle::InputDelay::Fixed(s.input_delay)
+        };
+        let (pairing, connector): (_, Box<dyn transport::Connector>) = if s.lan {
+            match s.lan_host {
+                Some(host) => (
+                    battle::Pairing::Code(host.session_id),
+                    Box::new(transport::tcp::Connector::new(
+                        config.lan.bind_addr.clone(),
+                        Some(host.addr.to_string()),
+                    )),
+                ),
+                None => (
+                    battle::Pairing::Code(lan::make_session_id()),
+                    Box::new(lan::Connector::new(
+                        lan::DISCOVERY.clone(),
+                        config.lan.bind_addr.clone(),
+                        config.nickname.clone(),
+                        core.as_ref().game_title(),
+                        match_type,
+                    )),
+                ),
+            }
+        } else if s.random_opponent {
+            (
+                battle::Pairing::Random {
+                    matchmaking_connect_addr: config.matchmaking.connect_addr.clone(),
+                },
+                transport::make_connector(&config, None),
+            )
+        } else if s.advertise {
+            let advertisement = match battle::make_advertisement(
+                &self.compat_list,
+                &core.as_ref().game_title(),
+                core.as_ref().crc32(),
+                match_type,
+                input_delay,
+                &config.nickname,
+            ) {
+                Ok(advertisement) => Some(advertisement),
+                Err(e) => {
+                    log::warn!("not listing room: {}", e);
+                    None
+                }
+            };
+            (
+                battle::Pairing::Code(if s.code.is_empty() {
+                    lan::make_session_id()
+                } else {
+                    s.code.to_string()
+                }),
+                transport::make_connector(&config, advertisement),
+            )
+        } else {
+            (
+                battle::Pairing::Code(s.code.to_string()),
+                transport::make_connector(&config, None),
+            )
+        };
         let m = battle::Match::new(
             self.compat_list.clone(),
             s.replay_folder_name,
-            s.code.to_string(),
+            pairing,
             match_type,
             core.as_ref().game_title(),
             core.as_ref().crc32(),
-            s.input_delay,
+            input_delay,
             battle::Settings {
-                matchmaking_connect_addr: config.matchmaking.connect_addr.to_string(),
-                make_webrtc_config: {
-                    let webrtc = config.webrtc.clone();
-                    Box::new(move || webrtc.make_webrtc_config())
+                nickname: config.nickname.clone(),
+                connector,
+                spectators: if s.allow_spectators && !s.lan {
+                    Some(battle::SpectatorSettings {
+                        matchmaking_connect_addr: config.matchmaking.connect_addr.clone(),
+                        webrtc: config.webrtc.clone(),
+                    })
+                } else {
+                    None
                 },
+                signing_key: config.replay_signing_key_path.as_ref().and_then(|path| {
+                    match replay::SigningKey::load_or_generate(path) {
+                        Ok(signing_key) => Some(signing_key),
+                        Err(e) => {
+                            log::warn!(
+                                "failed to load replay signing key from {}, replays will not be signed: {}",
+                                path.display(),
+                                e
+                            );
+                            None
+                        }
+                    }
+                }),
             },
         );
         m.start(handle);
@@ -315,10 +465,9 @@ impl<'a> MatchStateFacadeGuard<'a> {
     }
 
     pub fn abort(&mut self, mut core: mgba::core::CoreMutRef) {
-        core.gba_mut()
-            .sync_mut()
-            .expect("sync")
-            .set_fps_target(loaded::EXPECTED_FPS as f32);
+        if let Some(mut sync) = core.gba_mut().sync_mut() {
+            sync.set_fps_target(loaded::EXPECTED_FPS as f32);
+        }
         self.primary_mux_handle.switch();
         self.audio_core_handle.pause();
         *self.guard = loaded::MatchState::Aborted;
@@ -330,12 +479,15 @@ impl<'a> MatchStateFacadeGuard<'a> {
         } else {
             unreachable!();
         };
+        // Hold the game up while we try to reconnect, rather than letting inputs pile up.
+        m.wait_for_connection().await;
         let guard = m.lock_battle_state().await;
         BattleStateFacadeGuard {
             m,
             guard,
             audio_state_holder: self.audio_state_holder.clone(),
             fastforwarder: self.fastforwarder.clone(),
+            gui_state: self.gui_state.clone(),
         }
     }
 
@@ -362,10 +514,9 @@ impl<'a> MatchStateFacadeGuard<'a> {
         } else {
             unreachable!();
         };
-        core.gba_mut()
-            .sync_mut()
-            .expect("sync")
-            .set_fps_target(loaded::EXPECTED_FPS as f32);
+        if let Some(mut sync) = core.gba_mut().sync_mut() {
+            sync.set_fps_target(loaded::EXPECTED_FPS as f32);
+        }
         self.primary_mux_handle.switch();
         self.audio_core_handle.pause();
         m.end_battle().await;
@@ -404,6 +555,7 @@ pub struct MatchStateFacade {
     primary_mux_handle: audio::mux_stream::MuxHandle,
     audio_core_mux_handle: audio::mux_stream::MuxHandle,
     config: std::sync::Arc<parking_lot::Mutex<config::Config>>,
+    gui_state: std::sync::Arc<gui::State>,
 }
 
 impl MatchStateFacade {
@@ -417,6 +569,7 @@ impl MatchStateFacade {
             primary_mux_handle: self.primary_mux_handle.clone(),
             audio_core_mux_handle: self.audio_core_mux_handle.clone(),
             config: self.config.clone(),
+            gui_state: self.gui_state.clone(),
         }
     }
 }
@@ -476,6 +629,7 @@ impl Facade {
             primary_mux_handle: self.0.borrow().primary_mux_handle.clone(),
             audio_core_mux_handle: self.0.borrow().audio_core_mux_handle.clone(),
             config: self.0.borrow().config.clone(),
+            gui_state: self.0.borrow().gui_state.clone(),
         }
     }
 
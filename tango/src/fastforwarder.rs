@@ -1,6 +1,8 @@
 use crate::hooks;
 use crate::input;
 
+pub const STATE_HASH_INTERVAL: u32 = 60;
+
 struct InnerState {
     local_player_index: u8,
     input_pairs: std::collections::VecDeque<input::Pair<input::Input>>,
@@ -8,7 +10,8 @@ struct InnerState {
     committed_state: Option<mgba::state::State>,
     dirty_time: u32,
     dirty_state: Option<mgba::state::State>,
-    on_battle_ended: Box<dyn Fn()>,
+    on_battle_ended: Box<dyn Fn() + Send>,
+    state_hashes: Vec<(u32, Vec<u8>)>,
     result: anyhow::Result<()>,
 }
 
@@ -18,7 +21,7 @@ impl InnerState {
         input_pairs: Vec<input::Pair<input::Input>>,
         commit_time: u32,
         dirty_time: u32,
-        on_battle_ended: Box<dyn Fn()>,
+        on_battle_ended: Box<dyn Fn() + Send>,
     ) -> Self {
         InnerState {
             local_player_index,
@@ -28,6 +31,7 @@ impl InnerState {
             dirty_time,
             dirty_state: None,
             on_battle_ended,
+            state_hashes: vec![],
             result: Ok(()),
         }
     }
@@ -37,10 +41,11 @@ pub struct Fastforwarder {
     core: mgba::core::Core,
     state: State,
     hooks: &'static Box<dyn hooks::Hooks + Send + Sync>,
+    state_hashes: Vec<(u32, Vec<u8>)>,
 }
 
 #[derive(Clone)]
-pub struct State(std::rc::Rc<std::cell::RefCell<Option<InnerState>>>);
+pub struct State(std::sync::Arc<parking_lot::Mutex<Option<InnerState>>>);
 
 impl State {
     pub fn new(
@@ -48,46 +53,55 @@ impl State {
         input_pairs: Vec<input::Pair<input::Input>>,
         commit_time: u32,
         dirty_time: u32,
-        on_battle_ended: Box<dyn Fn()>,
+        on_battle_ended: Box<dyn Fn() + Send>,
     ) -> State {
-        State(std::rc::Rc::new(
-            std::cell::RefCell::<Option<InnerState>>::new(Some(InnerState::new(
+        State(std::sync::Arc::new(parking_lot::Mutex::new(Some(
+            InnerState::new(
                 local_player_index,
                 input_pairs,
                 commit_time,
                 dirty_time,
                 on_battle_ended,
-            ))),
-        ))
+            ),
+        ))))
     }
 
     pub fn commit_time(&self) -> u32 {
-        self.0.borrow().as_ref().expect("commit time").commit_time
+        self.0.lock().as_ref().expect("commit time").commit_time
     }
 
     pub fn set_committed_state(&self, state: mgba::state::State) {
         self.0
-            .borrow_mut()
+            .lock()
             .as_mut()
             .expect("committed state")
             .committed_state = Some(state);
     }
 
-    pub fn dirty_time(&self) -> u32 {
-        self.0.borrow().as_ref().expect("dirty time").dirty_time
+    pub fn wants_state_hash(&self, tick: u32) -> bool {
+        tick % STATE_HASH_INTERVAL == 0 && tick <= self.commit_time()
     }
 
-    pub fn set_dirty_state(&self, state: mgba::state::State) {
+    pub fn add_state_hash(&self, tick: u32, hash: Vec<u8>) {
         self.0
-            .borrow_mut()
+            .lock()
             .as_mut()
-            .expect("dirty state")
-            .dirty_state = Some(state);
+            .expect("state hashes")
+            .state_hashes
+            .push((tick, hash));
+    }
+
+    pub fn dirty_time(&self) -> u32 {
+        self.0.lock().as_ref().expect("dirty time").dirty_time
+    }
+
+    pub fn set_dirty_state(&self, state: mgba::state::State) {
+        self.0.lock().as_mut().expect("dirty state").dirty_state = Some(state);
     }
 
     pub fn peek_input_pair(&self) -> Option<input::Pair<input::Input>> {
         self.0
-            .borrow()
+            .lock()
             .as_ref()
             .expect("input pairs")
             .input_pairs
@@ -97,7 +111,7 @@ impl State {
 
     pub fn pop_input_pair(&self) -> Option<input::Pair<input::Input>> {
         self.0
-            .borrow_mut()
+            .lock()
             .as_mut()
             .expect("input pairs")
             .input_pairs
@@ -105,12 +119,12 @@ impl State {
     }
 
     pub fn set_anyhow_error(&self, err: anyhow::Error) {
-        self.0.borrow_mut().as_mut().expect("error").result = Err(err);
+        self.0.lock().as_mut().expect("error").result = Err(err);
     }
 
     pub fn local_player_index(&self) -> u8 {
         self.0
-            .borrow()
+            .lock()
             .as_ref()
             .expect("local player index")
             .local_player_index
@@ -119,15 +133,37 @@ impl State {
     pub fn on_battle_ended(&self) {
         (self
             .0
-            .borrow_mut()
+            .lock()
             .as_mut()
             .expect("on battle ended")
             .on_battle_ended)();
     }
 
+    /// Queues another input pair to be played back, e.g. as they arrive from a live feed.
+    pub fn push_input_pair(&self, ip: input::Pair<input::Input>) {
+        self.0
+            .lock()
+            .as_mut()
+            .expect("input pairs")
+            .input_pairs
+            .push_back(ip);
+    }
+
+    /// Drops any queued input pairs and starts feeding a new battle into the same traps.
+    pub fn reset(&self, local_player_index: u8) {
+        let mut inner = self.0.lock();
+        let inner = inner.as_mut().expect("state");
+        inner.local_player_index = local_player_index;
+        inner.input_pairs.clear();
+        inner.committed_state = None;
+        inner.dirty_state = None;
+        inner.state_hashes.clear();
+        inner.result = Ok(());
+    }
+
     pub fn inputs_pairs_left(&self) -> usize {
         self.0
-            .borrow()
+            .lock()
             .as_ref()
             .expect("input pairs")
             .input_pairs
@@ -147,14 +183,17 @@ impl Fastforwarder {
             core
         };
 
-        let state = State(std::rc::Rc::new(
-            std::cell::RefCell::<Option<InnerState>>::new(None),
-        ));
+        let state = State(std::sync::Arc::new(parking_lot::Mutex::new(None)));
 
         core.set_traps(hooks.get_fastforwarder_traps(state.clone()));
         core.as_mut().reset();
 
-        Ok(Fastforwarder { core, state, hooks })
+        Ok(Fastforwarder {
+            core,
+            state,
+            hooks,
+            state_hashes: vec![],
+        })
     }
 
     pub fn fastforward(
@@ -209,7 +248,7 @@ impl Fastforwarder {
         let commit_time = start_current_tick + commit_pairs.len() as u32;
         let dirty_time = start_current_tick + input_pairs.len() as u32 - 1;
 
-        *self.state.0.borrow_mut() = Some(InnerState::new(
+        *self.state.0.lock() = Some(InnerState::new(
             local_player_index,
             input_pairs,
             commit_time,
@@ -220,7 +259,7 @@ impl Fastforwarder {
         while self
             .state
             .0
-            .borrow()
+            .lock()
             .as_ref()
             .unwrap()
             .committed_state
@@ -228,33 +267,30 @@ impl Fastforwarder {
             || self
                 .state
                 .0
-                .borrow()
+                .lock()
                 .as_ref()
                 .expect("state")
                 .dirty_state
                 .is_none()
         {
-            self.state.0.borrow_mut().as_mut().expect("state").result = Ok(());
+            self.state.0.lock().as_mut().expect("state").result = Ok(());
             self.core.as_mut().run_frame();
-            if self
-                .state
-                .0
-                .borrow()
-                .as_ref()
-                .expect("state")
-                .result
-                .is_err()
-            {
-                let state = self.state.0.take().expect("state");
+            if self.state.0.lock().as_ref().expect("state").result.is_err() {
+                let state = self.state.0.lock().take().expect("state");
                 return Err(state.result.expect_err("state result err"));
             }
         }
 
-        let state = self.state.0.take().expect("state");
+        let mut state = self.state.0.lock().take().expect("state");
+        self.state_hashes.append(&mut state.state_hashes);
         Ok((
             state.committed_state.expect("committed state"),
             state.dirty_state.expect("dirty state"),
             last_input,
         ))
     }
+
+    pub fn take_state_hashes(&mut self) -> Vec<(u32, Vec<u8>)> {
+        std::mem::take(&mut self.state_hashes)
+    }
 }
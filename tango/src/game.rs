@@ -1,4 +1,4 @@
-use crate::{compat, config, current_input, gui, loaded, tps};
+use crate::{compat, config, current_input, gui, lan, loaded, lobby, tps};
 use cpal::traits::{DeviceTrait, HostTrait};
 use parking_lot::Mutex;
 use std::sync::Arc;
@@ -49,7 +49,10 @@ impl Game {
                 if let Err(e) = (move || async {
                     let listener = tokio::net::TcpListener::bind(bind_addr).await?;
                     log::info!("bound local matchmaking server on {}", listener.local_addr()?);
-                    let mut server = tango_matchmaking::server::Server::new(listener);
+                    let mut server = tango_matchmaking::server::Server::new(
+                        listener,
+                        tango_matchmaking::server::Settings::default(),
+                    );
                     server.run().await;
                     Result::<(), anyhow::Error>::Ok(())
                 })()
@@ -60,6 +63,18 @@ impl Game {
             });
         }
 
+        {
+            let discovery_port = config.lan.discovery_port;
+            rt.spawn(async move {
+                if let Err(e) = lan::DISCOVERY.run(discovery_port).await {
+                    log::info!(
+                        "lan discovery stopped, lan play will not be available: {}",
+                        e
+                    );
+                }
+            });
+        }
+
         let handle = rt.handle().clone();
 
         let event_loop = Some(winit::event_loop::EventLoop::new());
@@ -89,6 +104,13 @@ impl Game {
 
         let config = Arc::new(Mutex::new(config));
 
+        {
+            let config = config.clone();
+            rt.spawn(async move {
+                lobby::LOBBY.run(config).await;
+            });
+        }
+
         let fps_counter = Arc::new(Mutex::new(tps::Counter::new(30)));
         let emu_tps_counter = Arc::new(Mutex::new(tps::Counter::new(10)));
 
@@ -162,6 +184,9 @@ impl Game {
                                         local_delay: battle.local_delay(),
                                         remote_delay: battle.remote_delay(),
                                         tps_adjustment: battle.tps_adjustment(),
+                                        connection: m.connection_stats(),
+                                        candidate_pair: m.candidate_pair(),
+                                        telemetry: battle.telemetry_summary().clone(),
                                     }),
                                     None => None,
                                 }
@@ -1,4 +1,4 @@
-use crate::{battle, config, current_input, locales};
+use crate::{battle, config, current_input, lan, lobby, locales, telemetry, transport};
 use egui::{ClippedMesh, Context, TexturesDelta};
 use egui_wgpu_backend::{BackendError, RenderPass, ScreenDescriptor};
 use fluent_templates::Loader;
@@ -174,6 +174,21 @@ pub struct ConnectRequest {
     pub code: String,
     pub replay_folder_name: std::path::PathBuf,
     pub input_delay: u32,
+    pub auto_input_delay: bool,
+    pub renegotiate_input_delay: bool,
+    pub allow_spectators: bool,
+    pub random_opponent: bool,
+    /// List the session as an open room on the matchmaking server. A link code is made up if none is given.
+    pub advertise: bool,
+    pub lan: bool,
+    /// Who to dial on the local network. If unset, we host instead.
+    pub lan_host: Option<lan::Host>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Desync {
+    pub battle_number: u8,
+    pub tick: u32,
 }
 
 #[derive(Clone, Debug)]
@@ -190,6 +205,7 @@ pub struct State {
     show_keymapping_config: std::sync::atomic::AtomicBool,
     rom_list: parking_lot::Mutex<Vec<ROMInfo>>,
     debug_stats_getter: parking_lot::Mutex<Option<Box<dyn Fn() -> Option<DebugStats>>>>,
+    desync: parking_lot::Mutex<Option<Desync>>,
     config: std::sync::Arc<parking_lot::Mutex<config::Config>>,
     unfiltered_current_input: std::rc::Rc<std::cell::RefCell<current_input::CurrentInput>>,
 }
@@ -201,6 +217,9 @@ pub struct BattleDebugStats {
     pub local_delay: u32,
     pub remote_delay: u32,
     pub tps_adjustment: i32,
+    pub connection: telemetry::ConnectionStats,
+    pub candidate_pair: Option<transport::CandidatePair>,
+    pub telemetry: telemetry::Summary,
 }
 
 pub struct DebugStats {
@@ -237,6 +256,26 @@ fn keybinder(
     egui::InnerResponse::new(bound, response)
 }
 
+fn matchmaking_error_key(code: tango_matchmaking::protocol::ErrorCode) -> &'static str {
+    match code {
+        tango_matchmaking::protocol::ErrorCode::ProtocolVersionMismatch => {
+            "connect.description-error-matchmaking-protocol-version-mismatch"
+        }
+        tango_matchmaking::protocol::ErrorCode::SessionFull => {
+            "connect.description-error-session-full"
+        }
+        tango_matchmaking::protocol::ErrorCode::TooManySessions
+        | tango_matchmaking::protocol::ErrorCode::RateLimited => {
+            "connect.description-error-server-busy"
+        }
+        tango_matchmaking::protocol::ErrorCode::TimedOut => "connect.description-error-timed-out",
+        tango_matchmaking::protocol::ErrorCode::BadRequest
+        | tango_matchmaking::protocol::ErrorCode::NoSuchSession => {
+            "connect.description-error-matchmaking-rejected"
+        }
+    }
+}
+
 pub enum ConnectStatus {
     None,
     NotReady,
@@ -256,6 +295,7 @@ impl State {
             show_keymapping_config: false.into(),
             rom_list: parking_lot::Mutex::new(vec![]),
             debug_stats_getter: parking_lot::Mutex::new(None),
+            desync: parking_lot::Mutex::new(None),
             config,
             unfiltered_current_input,
         }
@@ -282,6 +322,13 @@ impl State {
                 code: "".to_owned(),
                 replay_folder_name: std::path::PathBuf::new(),
                 input_delay: 3,
+                auto_input_delay: true,
+                renegotiate_input_delay: false,
+                allow_spectators: false,
+                random_opponent: false,
+                advertise: false,
+                lan: false,
+                lan_host: None,
             });
         }
         let status = match &*connect_state {
@@ -309,6 +356,10 @@ impl State {
         status
     }
 
+    pub fn submit_connect_request(&self, s: ConnectRequest) {
+        *self.connect_state.lock() = ConnectDialogState::InputComplete(s);
+    }
+
     pub fn connect_dialog_is_open(&self) -> bool {
         !matches!(&*self.connect_state.lock(), ConnectDialogState::None)
     }
@@ -332,6 +383,10 @@ impl State {
         *self.debug_stats_getter.lock() = getter;
     }
 
+    pub fn report_desync(&self, desync: Desync) {
+        *self.desync.lock() = Some(desync);
+    }
+
     pub fn toggle_menu(&self) {
         self.show_menu
             .fetch_xor(true, std::sync::atomic::Ordering::Relaxed);
@@ -421,6 +476,11 @@ impl State {
 
             let mut open = !matches!(&*maybe_connect_state, ConnectDialogState::None);
 
+            lobby::LOBBY.watch(matches!(
+                &*maybe_connect_state,
+                ConnectDialogState::PendingInput(s) if !s.lan && !s.random_opponent && !s.advertise
+            ));
+
             egui::Window::new(locales::LOCALES.lookup(&locales::SYSTEM_LOCALE, "connect"))
                 .id(egui::Id::new("connect-window"))
                 .collapsible(false)
@@ -434,23 +494,141 @@ impl State {
                             locales::LOCALES.lookup(&locales::SYSTEM_LOCALE, "connect.description"),
                         );
 
-                        let response = ui.add(
-                            egui::TextEdit::singleline(&mut s.code).hint_text(
+                        if !s.lan {
+                            ui.checkbox(
+                                &mut s.random_opponent,
+                                locales::LOCALES.lookup(
+                                    &locales::SYSTEM_LOCALE,
+                                    "connect.input-random-opponent",
+                                ),
+                            );
+                        }
+                        if !s.random_opponent {
+                            ui.checkbox(
+                                &mut s.lan,
                                 locales::LOCALES
-                                    .lookup(&locales::SYSTEM_LOCALE, "connect.input-link-code"),
-                            ),
-                        );
-                        s.code = s.code.to_lowercase().trim().to_string();
-                        if s.code.is_empty() {
-                            response.request_focus();
+                                    .lookup(&locales::SYSTEM_LOCALE, "connect.input-lan"),
+                            );
                         }
-
-                        ui.add(
-                            egui::Slider::new(&mut s.input_delay, 3..=10).text(
+                        if s.lan {
+                            let hosts = lan::DISCOVERY.hosts();
+                            if s.lan_host
+                                .as_ref()
+                                .map(|lan_host| {
+                                    !hosts.iter().any(|host| host.addr == lan_host.addr)
+                                })
+                                .unwrap_or(false)
+                            {
+                                s.lan_host = None;
+                            }
+                            if ui
+                                .selectable_label(
+                                    s.lan_host.is_none(),
+                                    locales::LOCALES
+                                        .lookup(&locales::SYSTEM_LOCALE, "connect.input-lan-host"),
+                                )
+                                .clicked()
+                            {
+                                s.lan_host = None;
+                            }
+                            for host in hosts {
+                                let selected = s
+                                    .lan_host
+                                    .as_ref()
+                                    .map(|lan_host| lan_host.addr == host.addr)
+                                    .unwrap_or(false);
+                                if ui
+                                    .selectable_label(
+                                        selected,
+                                        format!(
+                                            "{} ({}, {})",
+                                            host.nickname, host.game_title, host.addr
+                                        ),
+                                    )
+                                    .clicked()
+                                {
+                                    s.lan_host = Some(host);
+                                }
+                            }
+                        } else if !s.random_opponent {
+                            ui.checkbox(
+                                &mut s.advertise,
                                 locales::LOCALES
-                                    .lookup(&locales::SYSTEM_LOCALE, "connect.input-input-delay"),
-                            ),
+                                    .lookup(&locales::SYSTEM_LOCALE, "connect.input-advertise"),
+                            );
+
+                            let response = ui.add(
+                                egui::TextEdit::singleline(&mut s.code).hint_text(
+                                    locales::LOCALES
+                                        .lookup(&locales::SYSTEM_LOCALE, "connect.input-link-code"),
+                                ),
+                            );
+                            s.code = s.code.to_lowercase().trim().to_string();
+                            if s.code.is_empty() && !s.advertise {
+                                response.request_focus();
+                            }
+
+                            if !s.advertise {
+                                ui.label(
+                                    locales::LOCALES
+                                        .lookup(&locales::SYSTEM_LOCALE, "connect.rooms"),
+                                );
+                                let rooms = lobby::LOBBY.rooms();
+                                if rooms.is_empty() {
+                                    ui.label(
+                                        egui::RichText::new(locales::LOCALES.lookup(
+                                            &locales::SYSTEM_LOCALE,
+                                            "connect.rooms-empty",
+                                        ))
+                                        .weak(),
+                                    );
+                                }
+                                for room in rooms {
+                                    if ui
+                                        .selectable_label(
+                                            s.code == room.session_id,
+                                            format!(
+                                                "{} ({}, {}, {})",
+                                                room.advertisement.nickname,
+                                                room.advertisement.compat_group,
+                                                room.advertisement.match_type,
+                                                room.advertisement
+                                                    .input_delay
+                                                    .map(|input_delay| input_delay.to_string())
+                                                    .unwrap_or_else(|| "auto".to_string())
+                                            ),
+                                        )
+                                        .clicked()
+                                    {
+                                        s.code = room.session_id;
+                                    }
+                                }
+                            }
+                        }
+
+                        ui.checkbox(
+                            &mut s.auto_input_delay,
+                            locales::LOCALES
+                                .lookup(&locales::SYSTEM_LOCALE, "connect.input-auto-input-delay"),
                         );
+                        if s.auto_input_delay {
+                            ui.checkbox(
+                                &mut s.renegotiate_input_delay,
+                                locales::LOCALES.lookup(
+                                    &locales::SYSTEM_LOCALE,
+                                    "connect.input-renegotiate-input-delay",
+                                ),
+                            );
+                        } else {
+                            ui.add(
+                                egui::Slider::new(&mut s.input_delay, 3..=10).text(
+                                    locales::LOCALES.lookup(
+                                        &locales::SYSTEM_LOCALE,
+                                        "connect.input-input-delay",
+                                    ),
+                                ),
+                            );
+                        }
 
                         let mut replay_folder_name =
                             s.replay_folder_name.to_string_lossy().to_string();
@@ -463,8 +641,20 @@ impl State {
                             ),
                         );
                         s.replay_folder_name = std::path::PathBuf::from(replay_folder_name);
+
+                        // Spectators go through the matchmaking server, which LAN play doesn't use.
+                        if !s.lan {
+                            ui.checkbox(
+                                &mut s.allow_spectators,
+                                locales::LOCALES.lookup(
+                                    &locales::SYSTEM_LOCALE,
+                                    "connect.input-allow-spectators",
+                                ),
+                            );
+                        }
+
                         let text_ok = ui.input().key_pressed(egui::Key::Enter)
-                            && !s.code.is_empty()
+                            && (s.random_opponent || s.lan || s.advertise || !s.code.is_empty())
                             && !s.replay_folder_name.as_os_str().is_empty();
 
                         ui.separator();
@@ -515,6 +705,11 @@ impl State {
                                                 &locales::SYSTEM_LOCALE,
                                                 "connect.description-not-started",
                                             ),
+                                        battle::NegotiationProgress::Queueing => locales::LOCALES
+                                            .lookup(
+                                                &locales::SYSTEM_LOCALE,
+                                                "connect.description-queueing",
+                                            ),
                                         battle::NegotiationProgress::Signalling => locales::LOCALES
                                             .lookup(
                                                 &locales::SYSTEM_LOCALE,
@@ -559,6 +754,12 @@ impl State {
                                             "connect.description-error-protocol-version-mismatch",
                                         )
                                     }
+                                    battle::NegotiationFailure::Matchmaking(code) => {
+                                        locales::LOCALES.lookup(
+                                            &locales::SYSTEM_LOCALE,
+                                            matchmaking_error_key(code),
+                                        )
+                                    }
                                     battle::NegotiationFailure::Unknown => locales::LOCALES.lookup(
                                         &locales::SYSTEM_LOCALE,
                                         "connect.description-error-unknown",
@@ -735,6 +936,39 @@ impl State {
                 .store(show_keymapping_config, std::sync::atomic::Ordering::Relaxed);
         }
 
+        {
+            let mut maybe_desync = self.desync.lock();
+            let mut open = maybe_desync.is_some();
+            let mut dismissed = false;
+            egui::Window::new(locales::LOCALES.lookup(&locales::SYSTEM_LOCALE, "desync"))
+                .id(egui::Id::new("desync-window"))
+                .collapsible(false)
+                .fixed_size(egui::vec2(300.0, 0.0))
+                .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 8.0))
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    let desync = maybe_desync.as_ref().unwrap();
+                    ui.label(locales::LOCALES.lookup_with_args(
+                        &locales::SYSTEM_LOCALE,
+                        "desync.description",
+                        &std::collections::HashMap::from([
+                            ("battle_number", desync.battle_number.into()),
+                            ("tick", desync.tick.into()),
+                        ]),
+                    ));
+                    ui.separator();
+                    if ui
+                        .button(locales::LOCALES.lookup(&locales::SYSTEM_LOCALE, "desync.dismiss"))
+                        .clicked()
+                    {
+                        dismissed = true;
+                    }
+                });
+            if !open || dismissed {
+                *maybe_desync = None;
+            }
+        }
+
         let mut show_debug = self.show_debug.load(std::sync::atomic::Ordering::Relaxed);
         egui::Window::new(locales::LOCALES.lookup(&locales::SYSTEM_LOCALE, "debug"))
             .id(egui::Id::new("debug-window"))
@@ -775,6 +1009,53 @@ impl State {
                                     battle_debug_stats.remote_delay,
                                 ));
                                 ui.end_row();
+
+                                let connection = &battle_debug_stats.connection;
+                                ui.label("RTT");
+                                ui.label(match connection.rtt {
+                                    Some(rtt) => format!(
+                                        "{:.0}ms (jitter {:.1}ms)",
+                                        rtt.as_secs_f32() * 1000.0,
+                                        connection.jitter.as_secs_f32() * 1000.0
+                                    ),
+                                    None => "?".to_owned(),
+                                });
+                                ui.end_row();
+
+                                ui.label("Packet loss");
+                                ui.label(format!("{:.1}%", connection.packet_loss * 100.0));
+                                ui.end_row();
+
+                                ui.label("Candidate pair");
+                                ui.label(match &battle_debug_stats.candidate_pair {
+                                    Some(candidate_pair) => format!(
+                                        "{} <-> {}",
+                                        candidate_pair.local, candidate_pair.remote
+                                    ),
+                                    None => "n/a".to_owned(),
+                                });
+                                ui.end_row();
+
+                                let telemetry = &battle_debug_stats.telemetry;
+                                ui.label("Rollback depth");
+                                ui.label(format!(
+                                    "{:.1} (max {})",
+                                    telemetry.mean_rollback_depth(),
+                                    telemetry.max_rollback_depth
+                                ));
+                                ui.end_row();
+
+                                ui.label("Fastforward time");
+                                ui.label(format!(
+                                    "{:.2}ms (max {:.2}ms)",
+                                    telemetry.mean_fastforward_time().as_secs_f32() * 1000.0,
+                                    telemetry.max_fastforward_time.as_secs_f32() * 1000.0
+                                ));
+                                ui.end_row();
+
+                                ui.label("Stalls");
+                                ui.label(format!("{}", telemetry.stalls));
+                                ui.end_row();
                             }
                         });
                     }
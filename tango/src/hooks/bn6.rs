@@ -544,6 +544,10 @@ impl hooks::Hooks for BN6 {
                             );
                         }
 
+                        if ff_state.wants_state_hash(current_tick) {
+                            ff_state.add_state_hash(current_tick, munger.state_hash(core));
+                        }
+
                         let ip = match ff_state.peek_input_pair() {
                             Some(ip) => ip,
                             None => {
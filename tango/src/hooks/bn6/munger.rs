@@ -1,3 +1,7 @@
+use sha3::digest::ExtendableOutput;
+use std::io::Read;
+use std::io::Write;
+
 #[derive(Clone)]
 pub(super) struct Munger {
     pub(super) offsets: super::offsets::Offsets,
@@ -74,4 +78,38 @@ impl Munger {
     pub(super) fn current_tick(&self, mut core: mgba::core::CoreMutRef) -> u32 {
         core.raw_read_32(self.offsets.ewram.battle_state + 0x60, -1)
     }
+
+    /// Hashes only the parts of battle state that both sides lay out identically, i.e. indexed by player and not by perspective.
+    ///
+    /// The inputs and turns are written by the hooks themselves, so they only catch a bad input exchange: the random number generators are what catch the simulation itself going its own way, since every random event in battle draws from them.
+    pub(super) fn state_hash(&self, mut core: mgba::core::CoreMutRef) -> Vec<u8> {
+        let mut shake128 = sha3::Shake128::default();
+        shake128
+            .write_all(&core.raw_read_range::<0x10>(self.offsets.ewram.player_input_data_arr, -1))
+            .expect("hash player input data");
+        shake128
+            .write_all(
+                &core.raw_read_range::<0x200>(self.offsets.ewram.player_marshaled_state_arr, -1),
+            )
+            .expect("hash player marshaled state");
+        shake128
+            .write_all(&core.raw_read_range::<0x2>(self.offsets.ewram.battle_state + 0x14, -1))
+            .expect("hash custom screen states");
+        shake128
+            .write_all(&core.raw_read_range::<0x4>(self.offsets.ewram.rng1_state, -1))
+            .expect("hash rng1 state");
+        shake128
+            .write_all(&core.raw_read_range::<0x4>(self.offsets.ewram.rng2_state, -1))
+            .expect("hash rng2 state");
+        shake128
+            .write_all(&self.current_tick(core).to_le_bytes())
+            .expect("hash tick");
+
+        let mut hash = vec![0u8; 16];
+        shake128
+            .finalize_xof()
+            .read_exact(hash.as_mut_slice())
+            .expect("read hash");
+        hash
+    }
 }
@@ -5,6 +5,9 @@ pub(super) struct EWRAMOffsets {
     pub(super) local_marshaled_battle_state: u32,
     pub(super) player_marshaled_state_arr: u32,
     pub(super) menu_control: u32,
+    /// The game's two random number generators, which both sides must agree on for the battle to play out the same.
+    pub(super) rng1_state: u32,
+    pub(super) rng2_state: u32,
 }
 
 #[derive(Clone, Copy)]
@@ -35,6 +38,8 @@ static EWRAM_OFFSETS: EWRAMOffsets = EWRAMOffsets {
     local_marshaled_battle_state: 0x0203cbe0,
     player_marshaled_state_arr: 0x0203f4a0,
     menu_control: 0x02009a30,
+    rng1_state: 0x020013f0,
+    rng2_state: 0x02001120,
 };
 
 #[derive(Clone, Copy)]
@@ -1,4 +1,4 @@
-#[derive(Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Input {
     pub local_tick: u32,
     pub remote_tick: u32,
@@ -17,7 +17,7 @@ where
     local_delay: u32,
 }
 
-#[derive(Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Pair<T>
 where
     T: Clone,
@@ -59,6 +59,10 @@ where
         self.local_delay
     }
 
+    pub fn set_local_delay(&mut self, local_delay: u32) {
+        self.local_delay = local_delay;
+    }
+
     pub fn local_queue_length(&self) -> usize {
         self.local_queue.len()
     }
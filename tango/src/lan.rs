@@ -0,0 +1,239 @@
+//! Finds other tango instances on the local network by UDP broadcast, so players can connect to each other directly over TCP without any servers involved.
+
+use crate::{protocol, transport};
+use bincode::Options;
+
+const MAGIC: &[u8; 8] = b"tangolan";
+const BEACON_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+/// How long a host stays listed after its last beacon.
+const HOST_TTL: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+struct Beacon {
+    magic: [u8; 8],
+    protocol_version: u8,
+    instance_id: u64,
+    nickname: String,
+    game_title: String,
+    match_type: u16,
+    session_id: String,
+    port: u16,
+}
+
+impl Beacon {
+    fn serialize(&self) -> bincode::Result<Vec<u8>> {
+        bincode::DefaultOptions::new()
+            .with_limit(1024)
+            .serialize(self)
+    }
+
+    fn deserialize(d: &[u8]) -> bincode::Result<Self> {
+        bincode::DefaultOptions::new()
+            .with_limit(1024)
+            .deserialize(d)
+    }
+}
+
+/// Someone on the local network waiting for an opponent.
+#[derive(Clone, Debug)]
+pub struct Host {
+    pub nickname: String,
+    pub game_title: String,
+    pub match_type: u16,
+    pub session_id: String,
+    /// Where to dial them.
+    pub addr: std::net::SocketAddr,
+}
+
+pub struct Discovery {
+    instance_id: u64,
+    hosts: parking_lot::Mutex<std::collections::HashMap<u64, (Host, std::time::Instant)>>,
+    beacon: parking_lot::Mutex<Option<Beacon>>,
+}
+
+lazy_static! {
+    pub static ref DISCOVERY: std::sync::Arc<Discovery> = std::sync::Arc::new(Discovery::new());
+}
+
+impl Discovery {
+    fn new() -> Self {
+        Self {
+            instance_id: rand::random(),
+            hosts: parking_lot::Mutex::new(std::collections::HashMap::new()),
+            beacon: parking_lot::Mutex::new(None),
+        }
+    }
+
+    /// Listens for beacons from other instances on `port`, and sends ours there while we're hosting.
+    pub async fn run(&self, port: u16) -> anyhow::Result<()> {
+        let socket = match tokio::net::UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, port))
+            .await
+        {
+            Ok(socket) => socket,
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                // We don't set SO_REUSEADDR, so only one instance on a machine can listen for beacons.
+                log::warn!(
+                    "lan discovery port {} is already in use, probably by another tango: this one will neither see lan hosts nor be seen by them",
+                    port
+                );
+                return Err(e.into());
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        };
+        socket.set_broadcast(true)?;
+        log::info!("listening for lan hosts on {}", socket.local_addr()?);
+
+        let mut interval = tokio::time::interval(BEACON_INTERVAL);
+        let mut buf = [0u8; 1024];
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    self.hosts
+                        .lock()
+                        .retain(|_, (_, last_seen)| last_seen.elapsed() < HOST_TTL);
+
+                    let beacon = self.beacon.lock().clone();
+                    if let Some(beacon) = beacon {
+                        if let Err(e) = socket
+                            .send_to(
+                                &beacon.serialize()?,
+                                (std::net::Ipv4Addr::BROADCAST, port),
+                            )
+                            .await
+                        {
+                            log::warn!("failed to send lan beacon: {}", e);
+                        }
+                    }
+                }
+                r = socket.recv_from(&mut buf) => {
+                    let (n, addr) = match r {
+                        Ok(r) => r,
+                        Err(e) => {
+                            // e.g. Windows reporting that an earlier beacon went unanswered, which shouldn't stop us listening.
+                            log::warn!("failed to receive lan beacon: {}", e);
+                            continue;
+                        }
+                    };
+                    let beacon = match Beacon::deserialize(&buf[..n]) {
+                        Ok(beacon) if &beacon.magic == MAGIC => beacon,
+                        _ => {
+                            continue;
+                        }
+                    };
+                    if beacon.instance_id == self.instance_id
+                        || beacon.protocol_version != protocol::VERSION
+                    {
+                        continue;
+                    }
+                    self.hosts.lock().insert(
+                        beacon.instance_id,
+                        (
+                            Host {
+                                nickname: beacon.nickname,
+                                game_title: beacon.game_title,
+                                match_type: beacon.match_type,
+                                session_id: beacon.session_id,
+                                addr: std::net::SocketAddr::new(addr.ip(), beacon.port),
+                            },
+                            std::time::Instant::now(),
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Sends our beacon until the returned guard is dropped.
+    fn advertise(&self, beacon: Beacon) -> Advertising<'_> {
+        *self.beacon.lock() = Some(beacon);
+        Advertising(self)
+    }
+
+    /// Everyone we've heard from recently, by nickname.
+    pub fn hosts(&self) -> Vec<Host> {
+        let mut hosts = self
+            .hosts
+            .lock()
+            .values()
+            .map(|(host, _)| host.clone())
+            .collect::<Vec<_>>();
+        hosts.sort_by(|a, b| a.nickname.cmp(&b.nickname));
+        hosts
+    }
+}
+
+struct Advertising<'a>(&'a Discovery);
+
+impl<'a> Drop for Advertising<'a> {
+    fn drop(&mut self) {
+        *self.0.beacon.lock() = None;
+    }
+}
+
+/// Waits for a peer to dial us over TCP, announcing ourselves on the local network until one does.
+pub struct Connector {
+    discovery: std::sync::Arc<Discovery>,
+    bind_addr: String,
+    nickname: String,
+    game_title: String,
+    match_type: u16,
+}
+
+impl Connector {
+    pub fn new(
+        discovery: std::sync::Arc<Discovery>,
+        bind_addr: String,
+        nickname: String,
+        game_title: String,
+        match_type: u16,
+    ) -> Self {
+        Self {
+            discovery,
+            bind_addr,
+            nickname,
+            game_title,
+            match_type,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl transport::Connector for Connector {
+    async fn connect(
+        &self,
+        session_id: &str,
+    ) -> anyhow::Result<std::sync::Arc<dyn transport::Transport>> {
+        let listener = tokio::net::TcpListener::bind(&self.bind_addr).await?;
+        log::info!("hosting on lan at {}", listener.local_addr()?);
+
+        let advertising = self.discovery.advertise(Beacon {
+            magic: *MAGIC,
+            protocol_version: protocol::VERSION,
+            instance_id: self.discovery.instance_id,
+            nickname: self.nickname.clone(),
+            game_title: self.game_title.clone(),
+            match_type: self.match_type,
+            session_id: session_id.to_string(),
+            port: listener.local_addr()?.port(),
+        });
+        let (stream, addr) = listener.accept().await?;
+        drop(advertising);
+        log::info!("accepted lan peer from {}", addr);
+        Ok(std::sync::Arc::new(
+            transport::tcp::Transport::new(stream, session_id).await?,
+        ))
+    }
+}
+
+/// A session ID for hosting, since there's no link code to agree on.
+pub fn make_session_id() -> String {
+    use rand::Rng;
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect::<String>()
+        .to_lowercase()
+}
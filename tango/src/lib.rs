@@ -13,9 +13,14 @@ pub mod game;
 pub mod gui;
 pub mod hooks;
 pub mod input;
+pub mod lan;
 pub mod loaded;
+pub mod lobby;
 pub mod locales;
 pub mod protocol;
 pub mod replay;
+pub mod sim;
+pub mod spectator;
+pub mod telemetry;
 pub mod tps;
 pub mod transport;
@@ -0,0 +1,55 @@
+//! Keeps the list of open rooms on the matchmaking server up to date while someone is looking at it.
+
+use crate::config;
+
+const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3);
+
+pub struct Lobby {
+    rooms: parking_lot::Mutex<Vec<tango_matchmaking::protocol::Room>>,
+    watching: std::sync::atomic::AtomicBool,
+}
+
+lazy_static! {
+    pub static ref LOBBY: std::sync::Arc<Lobby> = std::sync::Arc::new(Lobby::new());
+}
+
+impl Lobby {
+    fn new() -> Self {
+        Self {
+            rooms: parking_lot::Mutex::new(vec![]),
+            watching: false.into(),
+        }
+    }
+
+    /// Polls the matchmaking server for open rooms whenever someone is watching.
+    pub async fn run(&self, config: std::sync::Arc<parking_lot::Mutex<config::Config>>) {
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if !self.watching.load(std::sync::atomic::Ordering::Relaxed) {
+                continue;
+            }
+
+            let connect_addr = config.lock().matchmaking.connect_addr.clone();
+            match tango_matchmaking::client::list_rooms(&connect_addr).await {
+                Ok(rooms) => {
+                    *self.rooms.lock() = rooms;
+                }
+                Err(e) => {
+                    log::warn!("failed to list rooms: {}", e);
+                    self.rooms.lock().clear();
+                }
+            }
+        }
+    }
+
+    /// Sets whether the room list is being shown, so we only poll while it is.
+    pub fn watch(&self, watching: bool) {
+        self.watching
+            .store(watching, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn rooms(&self) -> Vec<tango_matchmaking::protocol::Room> {
+        self.rooms.lock().clone()
+    }
+}
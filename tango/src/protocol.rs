@@ -1,6 +1,6 @@
 use bincode::Options;
 
-pub const VERSION: u8 = 0x0e;
+pub const VERSION: u8 = 0x15;
 
 lazy_static! {
     static ref BINCODE_OPTIONS: bincode::config::WithOtherLimit<
@@ -18,8 +18,18 @@ lazy_static! {
 pub enum Packet {
     Hello(Hello),
     Hola(Hola),
+    Ping(Ping),
+    Pong(Pong),
+    InputDelayProposal(InputDelayProposal),
+    Resume(Resume),
     Init(Init),
-    Input(Input),
+    Inputs(Inputs),
+    StateHash(StateHash),
+    InputsHash(InputsHash),
+    SpectatorState(SpectatorState),
+    SpectatorInputs(SpectatorInputs),
+    SpectatorEndBattle(SpectatorEndBattle),
+    Bye(Bye),
 }
 
 impl Packet {
@@ -35,6 +45,7 @@ impl Packet {
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Hello {
     pub protocol_version: u8,
+    pub nickname: String,
     pub game_title: String,
     pub game_crc32: u32,
     pub match_type: u16,
@@ -46,6 +57,30 @@ pub struct Hola {
     pub rng_nonce: Vec<u8>,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Ping {
+    pub ts: u64,
+}
+
+/// Echoes the timestamp of a ping back to its sender.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Pong {
+    pub ts: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct InputDelayProposal {
+    pub input_delay: u32,
+}
+
+/// Sent first over a transport that replaces one that dropped, so the other side knows what it needs to send again.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Resume {
+    pub last_init_battle_number: Option<u8>,
+    /// Battle number and local tick of the last input received.
+    pub last_input: Option<(u8, u32)>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Init {
     pub battle_number: u8,
@@ -62,3 +97,52 @@ pub struct Input {
     pub custom_screen_state: u8,
     pub turn: Vec<u8>,
 }
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Inputs {
+    /// Battle number and local tick of the last input received from the other side, acknowledging it and every input before it.
+    pub ack: Option<(u8, u32)>,
+    /// Local tick of the first input of the battle these inputs are from, so the other side can tell when it's missing the start of a battle.
+    pub first_tick: u32,
+    /// Oldest first, and all from the same battle. Over unreliable transports, this repeats every input the other side hasn't acknowledged yet, so losing a packet doesn't lose inputs.
+    pub inputs: Vec<Input>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct StateHash {
+    pub battle_number: u8,
+    pub tick: u32,
+    pub hash: Vec<u8>,
+}
+
+/// Sent at the end of a battle, so both sides can record in their replays that they saw the same inputs.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct InputsHash {
+    pub battle_number: u8,
+    pub hash: [u8; 32],
+}
+
+/// A chunk of the state a battle starts from: states are too big for a single packet, so they are sent in pieces.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SpectatorState {
+    pub battle_number: u8,
+    pub local_player_index: u8,
+    pub offset: u32,
+    pub total_length: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SpectatorInputs {
+    pub battle_number: u8,
+    pub input_pairs: Vec<crate::input::Pair<crate::input::Input>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SpectatorEndBattle {
+    pub battle_number: u8,
+}
+
+/// Sent right before closing the connection on purpose, e.g. because the player left the match, so the other side ends the match instead of trying to reconnect.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Bye {}
@@ -1,125 +1,1039 @@
 use crate::input;
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
+use ed25519_dalek::{Signer, Verifier};
+use sha3::Digest;
 use std::io::Read;
+use std::io::Seek;
 use std::io::Write;
 
+pub mod interchange;
+
 pub struct Writer {
-    encoder: zstd::stream::write::AutoFinishEncoder<'static, Box<dyn std::io::Write + Send>>,
+    encoder: Hashing<FrameWriter>,
+    metadata: Metadata,
+    first_input_tick: Option<u32>,
+    last_keyframe_tick: Option<u32>,
+    keyframe_index: Vec<KeyframeIndexEntry>,
+    inputs_since_flush: usize,
+    inputs_hasher: sha3::Sha3_256,
 }
 
 const HEADER: &[u8] = b"TOOT";
-const VERSION: u8 = 0x0a;
+
+// Every version we can still read, newest first:
+//
+// - 0x0b: metadata after the local player index, then the initial state and records tagged by type, compressed in independent frames so a replay that was cut short can be read up to its last frame. Every keyframe starts a frame of its own, and the replay ends with an index of where they are.
+// - 0x0a: the initial state followed by untagged inputs until the end, in one zstd stream.
+const VERSION: u8 = 0x0b;
+const VERSION_UNTAGGED: u8 = 0x0a;
+
+const RECORD_INPUT: u8 = 0x00;
+const RECORD_KEYFRAME: u8 = 0x01;
+const RECORD_OUTCOME: u8 = 0x02;
+const RECORD_INPUTS_HASHES: u8 = 0x03;
+/// Always the last record: it signs everything before it.
+const RECORD_SIGNATURE: u8 = 0x04;
+const RECORD_KEYFRAME_INDEX: u8 = 0x05;
+const RECORD_STATE_HASH: u8 = 0x06;
+
+/// Ends the file after the last frame, so the keyframe index can be found without reading anything else: an empty frame, which marks the end of the frames, followed by the offset of the frame holding the keyframe index and this magic.
+const TRAILER_MAGIC: &[u8] = b"KIDX";
+const TRAILER_LENGTH: usize = 4 + 8 + 4;
+
+/// Passes everything written or read through to a hash, so the replay can be signed as a whole.
+struct Hashing<T> {
+    inner: T,
+    hasher: sha3::Sha3_256,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T, hasher: sha3::Sha3_256) -> Self {
+        Self { inner, hasher }
+    }
+
+    fn digest(&self) -> [u8; 32] {
+        self.hasher.clone().finalize().into()
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// How many inputs to write between flushes: at most this many are lost if tango crashes mid-battle.
+const INPUTS_PER_FRAME: usize = 60;
+
+/// Frames are much smaller than this, so anything bigger is garbage.
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Compresses everything written between flushes into its own length-prefixed zstd frame. Each frame can be decompressed on its own, so everything up to the last flush can be read back even if the rest never makes it to disk.
+struct FrameWriter {
+    inner: Box<dyn std::io::Write + Send>,
+    buf: Vec<u8>,
+    /// Where in the file the next frame will start.
+    offset: u64,
+}
+
+impl FrameWriter {
+    fn new(inner: Box<dyn std::io::Write + Send>, offset: u64) -> Self {
+        Self {
+            inner,
+            buf: vec![],
+            offset,
+        }
+    }
+
+    /// Writes the trailer pointing at the frame holding the keyframe index. Nothing may be written after this.
+    fn write_trailer(&mut self, keyframe_index_offset: u64) -> std::io::Result<()> {
+        self.flush()?;
+        self.inner.write_u32::<byteorder::LittleEndian>(0)?;
+        self.inner
+            .write_u64::<byteorder::LittleEndian>(keyframe_index_offset)?;
+        self.inner.write_all(TRAILER_MAGIC)?;
+        self.offset += TRAILER_LENGTH as u64;
+        self.inner.flush()
+    }
+}
+
+impl std::io::Write for FrameWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buf.is_empty() {
+            let frame = zstd::bulk::compress(&self.buf, 3)?;
+            self.inner
+                .write_u32::<byteorder::LittleEndian>(frame.len() as u32)?;
+            self.inner.write_all(&frame)?;
+            self.offset += 4 + frame.len() as u64;
+            self.buf.clear();
+        }
+        self.inner.flush()
+    }
+}
+
+impl Drop for FrameWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::error!("failed to flush replay: {}", e);
+        }
+    }
+}
+
+/// Reads what `FrameWriter` wrote back as a single stream. A frame that was cut short ends the stream early and marks it as truncated, and a stream that ends without the empty frame is only as far as `Writer` got before it was stopped.
+struct FrameReader<R> {
+    inner: R,
+    buf: std::io::Cursor<Vec<u8>>,
+    truncated: bool,
+    /// Set once the empty frame before the trailer is read.
+    ended: bool,
+}
+
+impl<R: std::io::Read> FrameReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: std::io::Cursor::new(vec![]),
+            truncated: false,
+            ended: false,
+        }
+    }
+
+    /// Reads as much of `buf` as there is, returning how much that was.
+    fn read_up_to(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            match self.inner.read(&mut buf[n..]) {
+                Ok(0) => break,
+                Ok(m) => n += m,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(n)
+    }
+
+    /// Returns false once there are no more complete frames.
+    fn next_frame(&mut self) -> std::io::Result<bool> {
+        if self.truncated || self.ended {
+            return Ok(false);
+        }
+
+        let mut len = [0u8; 4];
+        match self.read_up_to(&mut len)? {
+            0 => {
+                return Ok(false);
+            }
+            4 => {}
+            _ => {
+                self.truncated = true;
+                return Ok(false);
+            }
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len == 0 {
+            // Only the trailer is left, which isn't part of the stream.
+            self.ended = true;
+            return Ok(false);
+        }
+        if len > MAX_FRAME_LENGTH {
+            self.truncated = true;
+            return Ok(false);
+        }
+
+        let mut frame = vec![0u8; len];
+        if self.read_up_to(&mut frame)? < len {
+            self.truncated = true;
+            return Ok(false);
+        }
+        self.buf = std::io::Cursor::new(match zstd::stream::decode_all(&frame[..]) {
+            Ok(buf) => buf,
+            Err(_) => {
+                self.truncated = true;
+                return Ok(false);
+            }
+        });
+        Ok(true)
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for FrameReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.buf.read(buf)?;
+            if n > 0 || buf.is_empty() || !self.next_frame()? {
+                return Ok(n);
+            }
+        }
+    }
+}
+
+/// A player's key for signing the replays they record.
+pub struct SigningKey(ed25519_dalek::Keypair);
+
+impl SigningKey {
+    pub fn generate() -> Self {
+        Self::from_secret(&rand::random::<[u8; 32]>()).expect("signing key")
+    }
+
+    fn from_secret(secret: &[u8]) -> std::io::Result<Self> {
+        let secret = ed25519_dalek::SecretKey::from_bytes(secret)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        Ok(Self(ed25519_dalek::Keypair { secret, public }))
+    }
+
+    /// Keys are stored as just the 32 bytes of the secret key.
+    pub fn load(path: &std::path::Path) -> std::io::Result<Self> {
+        Self::from_secret(&std::fs::read(path)?)
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> std::io::Result<()> {
+        std::fs::write(path, self.0.secret.as_bytes())
+    }
+
+    /// Loads the key at the path, or makes a new one there if there isn't one yet.
+    pub fn load_or_generate(path: &std::path::Path) -> std::io::Result<Self> {
+        match Self::load(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key = Self::generate();
+                key.save(path)?;
+                log::info!("generated new replay signing key at {}", path.display());
+                Ok(key)
+            }
+            r => r,
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.0.public.to_bytes()
+    }
+}
+
+/// Hashes of the input stream, as seen by each side of the battle.
+///
+/// Both sides hash the same canonical form of the inputs, so they match if and only if both sides agree on what was played.
+#[derive(Clone, Debug)]
+pub struct InputsHashes {
+    pub local: [u8; 32],
+    /// None if the remote never sent theirs, e.g. because the connection dropped at the end of the battle.
+    pub remote: Option<[u8; 32]>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Signature {
+    pub public_key: [u8; 32],
+    signature: [u8; 64],
+    /// What was signed: the hash of everything in the replay before the signature.
+    digest: [u8; 32],
+}
+
+impl Signature {
+    pub fn is_valid(&self) -> bool {
+        let public_key = match ed25519_dalek::PublicKey::from_bytes(&self.public_key) {
+            Ok(public_key) => public_key,
+            Err(_) => {
+                return false;
+            }
+        };
+        let signature = match ed25519_dalek::Signature::from_bytes(&self.signature) {
+            Ok(signature) => signature,
+            Err(_) => {
+                return false;
+            }
+        };
+        public_key.verify(&self.digest, &signature).is_ok()
+    }
+}
+
+/// Feeds an input pair into an inputs hash, in the same form no matter which side is hashing.
+///
+/// Remote ticks are left out, since each side only knows its own view of when the other's inputs arrived.
+fn hash_input_pair(
+    hasher: &mut sha3::Sha3_256,
+    local_player_index: u8,
+    ip: &input::Pair<input::Input>,
+) {
+    let (p1, p2) = if local_player_index == 0 {
+        (&ip.local, &ip.remote)
+    } else {
+        (&ip.remote, &ip.local)
+    };
+    hasher.update(ip.local.local_tick.to_le_bytes());
+    for p in [p1, p2] {
+        hasher.update(p.joyflags.to_le_bytes());
+        hasher.update([p.custom_screen_state]);
+        hasher.update((p.turn.len() as u32).to_le_bytes());
+        hasher.update(&p.turn);
+    }
+}
+
+/// The hash of the input stream that `Writer::inputs_hash` would give for these input pairs.
+pub fn inputs_hash(local_player_index: u8, input_pairs: &[input::Pair<input::Input>]) -> [u8; 32] {
+    let mut hasher = sha3::Sha3_256::new();
+    for ip in input_pairs {
+        hash_input_pair(&mut hasher, local_player_index, ip);
+    }
+    hasher.finalize().into()
+}
+
+/// Formats bytes, e.g. a hash or public key, as lowercase hex.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Collects the replays at a path: the path itself if it's a file, or every `.tangoreplay` under it if it's a folder.
+pub fn find_replays(
+    path: &std::path::Path,
+    replays: &mut Vec<std::path::PathBuf>,
+) -> std::io::Result<()> {
+    if !path.is_dir() {
+        replays.push(path.to_path_buf());
+        return Ok(());
+    }
+    for dirent in std::fs::read_dir(path)? {
+        let path = dirent?.path();
+        if path.is_dir() {
+            find_replays(&path, replays)?;
+        } else if path.extension().and_then(|ext| ext.to_str()) == Some("tangoreplay") {
+            replays.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn now_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Metadata is stored as JSON: unknown fields are ignored and missing ones defaulted, so it can grow without bumping the replay version.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Metadata {
+    pub session_id: String,
+    pub battle_number: u8,
+    pub local: PlayerMetadata,
+    pub remote: PlayerMetadata,
+    /// Milliseconds since the Unix epoch.
+    pub started_at: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PlayerMetadata {
+    pub nickname: String,
+    pub game_id: String,
+    pub input_delay: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Outcome {
+    /// None if the battle ended without a winner, e.g. if it was cut short.
+    pub winner_player_index: Option<u8>,
+    /// Milliseconds since the Unix epoch.
+    pub ended_at: u64,
+}
+
+/// How often, in ticks, a savestate is embedded into the replay to seek to.
+pub const KEYFRAME_INTERVAL: u32 = 600;
+
+/// Where a keyframe's frame starts in the file, so it can be read with `Replay::decode_from_keyframe` without decoding everything before it.
+#[derive(Clone, Debug)]
+pub struct KeyframeIndexEntry {
+    pub tick: u32,
+    pub offset: u64,
+}
+
+pub struct Keyframe {
+    pub tick: u32,
+    /// Index into `Replay::input_pairs` of the first input pair to play after loading this keyframe.
+    pub input_index: usize,
+    pub state: mgba::state::State,
+}
 
 pub struct Replay {
+    /// The version the replay was read as.
+    pub version: u8,
     pub local_player_index: u8,
+    /// None for replays written before metadata was recorded.
+    pub metadata: Option<Metadata>,
+    /// None if the replay ends before the battle did.
+    pub outcome: Option<Outcome>,
     pub state: mgba::state::State,
     pub input_pairs: Vec<input::Pair<input::Input>>,
+    /// Savestates taken during the battle, ordered by tick.
+    pub keyframes: Vec<Keyframe>,
+    /// Hashes of the game state by tick, as they were computed during the battle, every `fastforwarder::STATE_HASH_INTERVAL` ticks. Empty for replays written before these were recorded.
+    pub state_hashes: Vec<(u32, Vec<u8>)>,
+    pub inputs_hashes: Option<InputsHashes>,
+    /// None if the replay wasn't signed.
+    pub signature: Option<Signature>,
+    /// Set if the replay ends partway through, e.g. because tango crashed while writing it. Everything up until then is still there.
+    pub truncated: bool,
+}
+
+/// Reads the header, returning the version.
+fn read_version(r: &mut impl std::io::Read) -> std::io::Result<u8> {
+    let mut header = [0u8; 4];
+    r.read_exact(&mut header)?;
+    if &header != HEADER {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "invalid header",
+        ));
+    }
+
+    let version = r.read_u8()?;
+    if version > VERSION {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "replay version {:02x} is newer than we can read, which is {:02x}",
+                version, VERSION
+            ),
+        ));
+    }
+    if version < VERSION_UNTAGGED {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unknown replay version: {:02x}", version),
+        ));
+    }
+    Ok(version)
+}
+
+/// Reads the keyframe index from the end of the replay, without decoding anything else.
+///
+/// Entries are ordered by tick. Returns None if there is no index, i.e. the replay is from before there were any or was cut short before it was written.
+pub fn read_keyframe_index(
+    mut r: impl std::io::Read + std::io::Seek,
+) -> std::io::Result<Option<Vec<KeyframeIndexEntry>>> {
+    r.seek(std::io::SeekFrom::Start(0))?;
+    if read_version(&mut r)? != VERSION {
+        return Ok(None);
+    }
+
+    if r.seek(std::io::SeekFrom::End(0))? < (HEADER.len() + 1 + TRAILER_LENGTH) as u64 {
+        return Ok(None);
+    }
+    r.seek(std::io::SeekFrom::End(-(TRAILER_LENGTH as i64)))?;
+    let end_of_frames = r.read_u32::<byteorder::LittleEndian>()?;
+    let offset = r.read_u64::<byteorder::LittleEndian>()?;
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if end_of_frames != 0 || &magic != TRAILER_MAGIC {
+        return Ok(None);
+    }
+
+    r.seek(std::io::SeekFrom::Start(offset))?;
+    let mut zr = FrameReader::new(r);
+    if zr.read_u8()? != RECORD_KEYFRAME_INDEX {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "trailer does not point at the keyframe index",
+        ));
+    }
+    read_keyframe_index_record(&mut zr).map(Some)
+}
+
+fn read_keyframe_index_record(
+    r: &mut impl std::io::Read,
+) -> std::io::Result<Vec<KeyframeIndexEntry>> {
+    let n = r.read_u32::<byteorder::LittleEndian>()? as usize;
+    let mut entries = Vec::with_capacity(n.min(MAX_FRAME_LENGTH / 12));
+    for _ in 0..n {
+        entries.push(KeyframeIndexEntry {
+            tick: r.read_u32::<byteorder::LittleEndian>()?,
+            offset: r.read_u64::<byteorder::LittleEndian>()?,
+        });
+    }
+    Ok(entries)
 }
 
 impl Replay {
     pub fn decode(mut r: impl std::io::Read) -> std::io::Result<Self> {
-        let mut header = [0u8; 4];
-        r.read_exact(&mut header)?;
-        if &header != HEADER {
+        let version = read_version(&mut r)?;
+
+        let mut hasher = sha3::Sha3_256::new();
+        hasher.update(HEADER);
+        hasher.update([version]);
+        if version == VERSION {
+            let mut zr = Hashing::new(FrameReader::new(r), hasher);
+            let mut replay = Self::decode_body(version, &mut zr)?;
+            // Records never span frames, so a replay cut short between two frames reads fine up until there: it's only missing the empty frame that ends a finished one.
+            replay.truncated |= zr.inner.truncated || !zr.inner.ended;
+            Ok(replay)
+        } else {
+            Self::decode_body(
+                version,
+                &mut Hashing::new(zstd::stream::read::Decoder::new(r)?, hasher),
+            )
+        }
+    }
+
+    /// Reads the replay starting from a keyframe found in its index, without decoding any of the inputs before it.
+    ///
+    /// The keyframe's state becomes the replay's state, so the input pairs and keyframes are only those after it. The signature only holds for the whole replay, so it is left out.
+    pub fn decode_from_keyframe(
+        mut r: impl std::io::Read + std::io::Seek,
+        entry: &KeyframeIndexEntry,
+    ) -> std::io::Result<Self> {
+        r.seek(std::io::SeekFrom::Start(0))?;
+        let version = read_version(&mut r)?;
+        if version != VERSION {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                "invalid header",
+                format!("replay version {:02x} has no keyframe index", version),
             ));
         }
 
-        if r.read_u8()? != VERSION {
+        // The local player index and metadata are in the first frame, along with the initial state.
+        let (local_player_index, metadata) = {
+            let mut zr = FrameReader::new(&mut r);
+            (zr.read_u8()?, read_json(&mut zr)?)
+        };
+
+        r.seek(std::io::SeekFrom::Start(entry.offset))?;
+        let mut zr = Hashing::new(FrameReader::new(r), sha3::Sha3_256::new());
+        if zr.read_u8()? != RECORD_KEYFRAME {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                "invalid version",
+                "keyframe index entry does not point at a keyframe",
             ));
         }
+        let tick = zr.read_u32::<byteorder::LittleEndian>()?;
+        if tick != entry.tick {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "keyframe index entry is for tick {} but points at tick {}",
+                    entry.tick, tick
+                ),
+            ));
+        }
+        let state = read_state(&mut zr)?;
 
-        let mut zr = zstd::stream::read::Decoder::new(r)?;
+        let mut replay = Self {
+            version,
+            local_player_index,
+            metadata: Some(metadata),
+            outcome: None,
+            state,
+            input_pairs: vec![],
+            keyframes: vec![],
+            state_hashes: vec![],
+            inputs_hashes: None,
+            signature: None,
+            truncated: false,
+        };
+        replay.read_records(&mut zr)?;
+        replay.truncated |= zr.inner.truncated || !zr.inner.ended;
+        replay.signature = None;
+        Ok(replay)
+    }
 
+    /// Reads everything after the version, up until the end or the last complete record.
+    fn decode_body(version: u8, zr: &mut Hashing<impl std::io::Read>) -> std::io::Result<Self> {
         let local_player_index = zr.read_u8()?;
 
-        let mut state = vec![0u8; zr.read_u32::<byteorder::LittleEndian>()? as usize];
-        zr.read_exact(&mut state)?;
-        let state = mgba::state::State::from_slice(&state);
+        let metadata = if version == VERSION {
+            Some(read_json(zr)?)
+        } else {
+            None
+        };
 
-        let mut input_pairs = vec![];
+        let state = read_state(zr)?;
+
+        let mut replay = Self {
+            version,
+            local_player_index,
+            metadata,
+            outcome: None,
+            state,
+            input_pairs: vec![],
+            keyframes: vec![],
+            state_hashes: vec![],
+            inputs_hashes: None,
+            signature: None,
+            truncated: false,
+        };
+        replay.read_records(zr)?;
+        Ok(replay)
+    }
 
+    /// Reads records up until the end or the last complete one.
+    fn read_records(&mut self, zr: &mut Hashing<impl std::io::Read>) -> std::io::Result<()> {
         loop {
-            let local_tick = match zr.read_u32::<byteorder::LittleEndian>() {
-                Ok(local_tick) => local_tick,
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                        break;
+            let tag = if self.version == VERSION_UNTAGGED {
+                RECORD_INPUT
+            } else {
+                match zr.read_u8() {
+                    Ok(tag) => tag,
+                    Err(e) => {
+                        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                            break;
+                        }
+                        return Err(e);
                     }
+                }
+            };
+
+            if self.signature.is_some() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "data after signature",
+                ));
+            }
+
+            let record = match read_record(tag, zr, self.local_player_index) {
+                Ok(record) => record,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    // Without record tags, there's no telling the end of the replay apart from an input cut short.
+                    self.truncated = self.version != VERSION_UNTAGGED;
+                    break;
+                }
+                Err(e) => {
                     return Err(e);
                 }
             };
-            let remote_tick = zr.read_u32::<byteorder::LittleEndian>()?;
 
-            let p1_joyflags = zr.read_u16::<byteorder::LittleEndian>()?;
-            let p2_joyflags = zr.read_u16::<byteorder::LittleEndian>()?;
+            match record {
+                Record::Input(ip) => {
+                    self.input_pairs.push(ip);
+                }
+                Record::Keyframe { tick, state } => {
+                    self.keyframes.push(Keyframe {
+                        tick,
+                        input_index: self.input_pairs.len(),
+                        state,
+                    });
+                }
+                Record::Outcome(v) => {
+                    self.outcome = Some(v);
+                }
+                Record::InputsHashes(v) => {
+                    self.inputs_hashes = Some(v);
+                }
+                Record::Signature(v) => {
+                    self.signature = Some(v);
+                }
+                Record::StateHash { tick, hash } => {
+                    self.state_hashes.push((tick, hash));
+                }
+                Record::KeyframeIndex => {}
+            }
+        }
+        Ok(())
+    }
 
-            let p1_custom_screen_state = zr.read_u8()?;
-            let p2_custom_screen_state = zr.read_u8()?;
+    pub fn is_latest_version(&self) -> bool {
+        self.version == VERSION
+    }
 
-            let mut p1_turn = vec![0u8; zr.read_u32::<byteorder::LittleEndian>()? as usize];
-            zr.read_exact(&mut p1_turn)?;
+    /// Writes the replay out again in the newest version.
+    ///
+    /// The signature can't be carried over, since it only holds for the bytes that were signed, so the result is unsigned.
+    pub fn upgrade(&self, w: Box<dyn std::io::Write + Send>) -> std::io::Result<()> {
+        let mut writer = Writer::new(
+            w,
+            self.local_player_index,
+            self.metadata.clone().unwrap_or_default(),
+        )?;
+        writer.write_state(&self.state)?;
+        let mut keyframes = self.keyframes.iter().peekable();
+        let mut state_hashes = self.state_hashes.iter().peekable();
+        for (i, ip) in self.input_pairs.iter().enumerate() {
+            while let Some(keyframe) = keyframes.next_if(|keyframe| keyframe.input_index <= i) {
+                writer.write_keyframe(keyframe.tick, &keyframe.state)?;
+            }
+            while let Some((tick, hash)) =
+                state_hashes.next_if(|(tick, _)| *tick < ip.local.local_tick)
+            {
+                writer.write_state_hash(*tick, hash)?;
+            }
+            writer.write_input(self.local_player_index, ip)?;
+        }
+        for keyframe in keyframes {
+            writer.write_keyframe(keyframe.tick, &keyframe.state)?;
+        }
+        for (tick, hash) in state_hashes {
+            writer.write_state_hash(*tick, hash)?;
+        }
+        if let Some(outcome) = &self.outcome {
+            writer.write_outcome(outcome)?;
+        }
+        writer.finish(
+            self.inputs_hashes
+                .as_ref()
+                .and_then(|inputs_hashes| inputs_hashes.remote.as_ref()),
+            None,
+        )
+    }
 
-            let mut p2_turn = vec![0u8; zr.read_u32::<byteorder::LittleEndian>()? as usize];
-            zr.read_exact(&mut p2_turn)?;
+    /// Finds the closest point at or before `tick` that playback can start from.
+    ///
+    /// Playback from the returned state must use the returned input pairs, and will need to run until `tick` to reach it exactly.
+    pub fn seek(&self, tick: u32) -> Seek<'_> {
+        let i = self
+            .keyframes
+            .partition_point(|keyframe| keyframe.tick <= tick);
+        match i.checked_sub(1).map(|i| &self.keyframes[i]) {
+            Some(keyframe) => Seek {
+                tick: keyframe.tick,
+                state: &keyframe.state,
+                input_pairs: &self.input_pairs[keyframe.input_index..],
+            },
+            None => Seek {
+                tick: self
+                    .input_pairs
+                    .first()
+                    .map(|ip| ip.local.local_tick)
+                    .unwrap_or(0),
+                state: &self.state,
+                input_pairs: &self.input_pairs,
+            },
+        }
+    }
+}
 
-            let p1_input = input::Input {
-                local_tick,
-                remote_tick,
-                joyflags: p1_joyflags,
-                custom_screen_state: p1_custom_screen_state,
-                turn: p1_turn,
-            };
+pub struct Seek<'a> {
+    pub tick: u32,
+    pub state: &'a mgba::state::State,
+    pub input_pairs: &'a [input::Pair<input::Input>],
+}
 
-            let p2_input = input::Input {
-                local_tick,
-                remote_tick: local_tick,
-                joyflags: p2_joyflags,
-                custom_screen_state: p2_custom_screen_state,
-                turn: p2_turn,
-            };
+enum Record {
+    Input(input::Pair<input::Input>),
+    Keyframe {
+        tick: u32,
+        state: mgba::state::State,
+    },
+    Outcome(Outcome),
+    InputsHashes(InputsHashes),
+    Signature(Signature),
+    /// Only of use to `read_keyframe_index`, which reads it without going through the rest of the replay.
+    KeyframeIndex,
+    StateHash {
+        tick: u32,
+        hash: Vec<u8>,
+    },
+}
 
-            let (local, remote) = if local_player_index == 0 {
-                (p1_input, p2_input)
+fn read_record(
+    tag: u8,
+    zr: &mut Hashing<impl std::io::Read>,
+    local_player_index: u8,
+) -> std::io::Result<Record> {
+    Ok(match tag {
+        RECORD_INPUT => Record::Input(read_input(zr, local_player_index)?),
+        RECORD_KEYFRAME => Record::Keyframe {
+            tick: zr.read_u32::<byteorder::LittleEndian>()?,
+            state: read_state(zr)?,
+        },
+        RECORD_OUTCOME => Record::Outcome(read_json(zr)?),
+        RECORD_INPUTS_HASHES => {
+            let mut local = [0u8; 32];
+            zr.read_exact(&mut local)?;
+            let remote = if zr.read_u8()? != 0 {
+                let mut remote = [0u8; 32];
+                zr.read_exact(&mut remote)?;
+                Some(remote)
             } else {
-                (p2_input, p1_input)
+                None
             };
-
-            input_pairs.push(input::Pair { local, remote });
+            Record::InputsHashes(InputsHashes { local, remote })
+        }
+        RECORD_STATE_HASH => {
+            let tick = zr.read_u32::<byteorder::LittleEndian>()?;
+            let mut hash = vec![0u8; zr.read_u32::<byteorder::LittleEndian>()? as usize];
+            zr.read_exact(&mut hash)?;
+            Record::StateHash { tick, hash }
+        }
+        RECORD_KEYFRAME_INDEX => {
+            read_keyframe_index_record(zr)?;
+            Record::KeyframeIndex
+        }
+        RECORD_SIGNATURE => {
+            let digest = zr.digest();
+            let mut public_key = [0u8; 32];
+            zr.read_exact(&mut public_key)?;
+            let mut signature = [0u8; 64];
+            zr.read_exact(&mut signature)?;
+            Record::Signature(Signature {
+                public_key,
+                signature,
+                digest,
+            })
+        }
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unknown record type: {:02x}", tag),
+            ));
         }
+    })
+}
 
-        Ok(Self {
-            local_player_index,
-            state,
-            input_pairs,
-        })
+fn read_state(r: &mut impl std::io::Read) -> std::io::Result<mgba::state::State> {
+    let mut state = vec![0u8; r.read_u32::<byteorder::LittleEndian>()? as usize];
+    r.read_exact(&mut state)?;
+    if state.len() != std::mem::size_of::<mgba::c::GBASerializedState>() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "invalid state length",
+        ));
     }
+    Ok(mgba::state::State::from_slice(&state))
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(r: &mut impl std::io::Read) -> std::io::Result<T> {
+    let mut buf = vec![0u8; r.read_u32::<byteorder::LittleEndian>()? as usize];
+    r.read_exact(&mut buf)?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+fn write_json(w: &mut impl std::io::Write, v: &impl serde::Serialize) -> std::io::Result<()> {
+    let buf = serde_json::to_vec(v)?;
+    w.write_u32::<byteorder::LittleEndian>(buf.len() as u32)?;
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+fn read_input(
+    r: &mut impl std::io::Read,
+    local_player_index: u8,
+) -> std::io::Result<input::Pair<input::Input>> {
+    let local_tick = r.read_u32::<byteorder::LittleEndian>()?;
+    let remote_tick = r.read_u32::<byteorder::LittleEndian>()?;
+
+    let p1_joyflags = r.read_u16::<byteorder::LittleEndian>()?;
+    let p2_joyflags = r.read_u16::<byteorder::LittleEndian>()?;
+
+    let p1_custom_screen_state = r.read_u8()?;
+    let p2_custom_screen_state = r.read_u8()?;
+
+    let mut p1_turn = vec![0u8; r.read_u32::<byteorder::LittleEndian>()? as usize];
+    r.read_exact(&mut p1_turn)?;
+
+    let mut p2_turn = vec![0u8; r.read_u32::<byteorder::LittleEndian>()? as usize];
+    r.read_exact(&mut p2_turn)?;
+
+    let p1_input = input::Input {
+        local_tick,
+        remote_tick,
+        joyflags: p1_joyflags,
+        custom_screen_state: p1_custom_screen_state,
+        turn: p1_turn,
+    };
+
+    let p2_input = input::Input {
+        local_tick,
+        remote_tick: local_tick,
+        joyflags: p2_joyflags,
+        custom_screen_state: p2_custom_screen_state,
+        turn: p2_turn,
+    };
+
+    let (local, remote) = if local_player_index == 0 {
+        (p1_input, p2_input)
+    } else {
+        (p2_input, p1_input)
+    };
+
+    Ok(input::Pair { local, remote })
 }
 
 impl Writer {
+    /// The metadata is written out along with the initial state, so it may still be filled in until then.
     pub fn new(
         mut writer: Box<dyn std::io::Write + Send>,
         local_player_index: u8,
+        metadata: Metadata,
     ) -> std::io::Result<Self> {
         writer.write_all(HEADER)?;
         writer.write_u8(VERSION)?;
-        let mut encoder = zstd::Encoder::new(writer, 3)?.auto_finish();
+        let mut hasher = sha3::Sha3_256::new();
+        hasher.update(HEADER);
+        hasher.update([VERSION]);
+        let mut encoder = Hashing::new(FrameWriter::new(writer, (HEADER.len() + 1) as u64), hasher);
         encoder.write_u8(local_player_index)?;
-        encoder.flush()?;
-        Ok(Writer { encoder })
+        Ok(Writer {
+            encoder,
+            metadata,
+            first_input_tick: None,
+            last_keyframe_tick: None,
+            keyframe_index: vec![],
+            inputs_since_flush: 0,
+            inputs_hasher: sha3::Sha3_256::new(),
+        })
+    }
+
+    /// The hash of every input written so far, to compare with the other side's.
+    pub fn inputs_hash(&self) -> [u8; 32] {
+        self.inputs_hasher.clone().finalize().into()
+    }
+
+    /// Writes the keyframe index and both sides' inputs hashes, and then signs the replay if given a key. Nothing may be written after this.
+    pub fn finish(
+        mut self,
+        remote_inputs_hash: Option<&[u8; 32]>,
+        signing_key: Option<&SigningKey>,
+    ) -> std::io::Result<()> {
+        // The trailer points at the start of a frame, so the index has to start one.
+        self.encoder.flush()?;
+        let keyframe_index_offset = self.encoder.inner.offset;
+        self.encoder.write_u8(RECORD_KEYFRAME_INDEX)?;
+        self.encoder
+            .write_u32::<byteorder::LittleEndian>(self.keyframe_index.len() as u32)?;
+        for entry in &self.keyframe_index {
+            self.encoder
+                .write_u32::<byteorder::LittleEndian>(entry.tick)?;
+            self.encoder
+                .write_u64::<byteorder::LittleEndian>(entry.offset)?;
+        }
+
+        self.encoder.write_u8(RECORD_INPUTS_HASHES)?;
+        self.encoder.write_all(&self.inputs_hash())?;
+        match remote_inputs_hash {
+            Some(remote_inputs_hash) => {
+                self.encoder.write_u8(1)?;
+                self.encoder.write_all(remote_inputs_hash)?;
+            }
+            None => {
+                self.encoder.write_u8(0)?;
+            }
+        }
+
+        if let Some(signing_key) = signing_key {
+            self.encoder.write_u8(RECORD_SIGNATURE)?;
+            let signature = signing_key.0.sign(&self.encoder.digest());
+            self.encoder.write_all(&signing_key.public_key())?;
+            self.encoder.write_all(&signature.to_bytes())?;
+        }
+        self.encoder.inner.write_trailer(keyframe_index_offset)?;
+        Ok(())
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.metadata
     }
 
     pub fn write_state(&mut self, state: &mgba::state::State) -> std::io::Result<()> {
+        write_json(&mut self.encoder, &self.metadata)?;
+        self.encoder
+            .write_u32::<byteorder::LittleEndian>(state.as_slice().len() as u32)?;
+        self.encoder.write_all(state.as_slice())?;
+        self.encoder.flush()?;
+        Ok(())
+    }
+
+    pub fn write_outcome(&mut self, outcome: &Outcome) -> std::io::Result<()> {
+        self.encoder.write_u8(RECORD_OUTCOME)?;
+        write_json(&mut self.encoder, outcome)?;
+        self.encoder.flush()?;
+        Ok(())
+    }
+
+    pub fn wants_keyframe(&self, tick: u32) -> bool {
+        match self.last_keyframe_tick.or(self.first_input_tick) {
+            Some(last_tick) => tick >= last_tick + KEYFRAME_INTERVAL,
+            None => false,
+        }
+    }
+
+    /// Writes a keyframe: `state` must be the state right before the input for `tick` is applied, i.e. after every input written so far.
+    pub fn write_keyframe(&mut self, tick: u32, state: &mgba::state::State) -> std::io::Result<()> {
+        // Start a frame of its own, so the keyframe can be read without anything before it.
+        self.encoder.flush()?;
+        self.inputs_since_flush = 0;
+        self.keyframe_index.push(KeyframeIndexEntry {
+            tick,
+            offset: self.encoder.inner.offset,
+        });
+        self.encoder.write_u8(RECORD_KEYFRAME)?;
+        self.encoder.write_u32::<byteorder::LittleEndian>(tick)?;
         self.encoder
             .write_u32::<byteorder::LittleEndian>(state.as_slice().len() as u32)?;
         self.encoder.write_all(state.as_slice())?;
         self.encoder.flush()?;
+        self.last_keyframe_tick = Some(tick);
+        Ok(())
+    }
+
+    pub fn write_state_hash(&mut self, tick: u32, hash: &[u8]) -> std::io::Result<()> {
+        self.encoder.write_u8(RECORD_STATE_HASH)?;
+        self.encoder.write_u32::<byteorder::LittleEndian>(tick)?;
+        self.encoder
+            .write_u32::<byteorder::LittleEndian>(hash.len() as u32)?;
+        self.encoder.write_all(hash)?;
         Ok(())
     }
 
@@ -133,6 +1047,11 @@ impl Writer {
         } else {
             (&ip.remote, &ip.local)
         };
+        if self.first_input_tick.is_none() {
+            self.first_input_tick = Some(ip.local.local_tick);
+        }
+        hash_input_pair(&mut self.inputs_hasher, local_player_index, ip);
+        self.encoder.write_u8(RECORD_INPUT)?;
         self.encoder
             .write_u32::<byteorder::LittleEndian>(ip.local.local_tick)?;
         self.encoder
@@ -153,6 +1072,296 @@ impl Writer {
             .write_u32::<byteorder::LittleEndian>(p2.turn.len() as u32)?;
         self.encoder.write_all(&p2.turn)?;
 
+        self.inputs_since_flush += 1;
+        if self.inputs_since_flush >= INPUTS_PER_FRAME {
+            self.encoder.flush()?;
+            self.inputs_since_flush = 0;
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICKS: u32 = 1500;
+
+    fn test_state(fill: u8) -> mgba::state::State {
+        mgba::state::State::from_slice(&vec![
+            fill;
+            std::mem::size_of::<mgba::c::GBASerializedState>()
+        ])
+    }
+
+    fn test_input_pair(tick: u32) -> input::Pair<input::Input> {
+        input::Pair {
+            local: input::Input {
+                local_tick: tick,
+                remote_tick: tick,
+                joyflags: tick as u16,
+                custom_screen_state: 0,
+                turn: vec![],
+            },
+            remote: input::Input {
+                local_tick: tick,
+                remote_tick: tick,
+                joyflags: !(tick as u16),
+                custom_screen_state: (tick % 2) as u8,
+                turn: if tick % 100 == 0 {
+                    vec![tick as u8; 4]
+                } else {
+                    vec![]
+                },
+            },
+        }
+    }
+
+    fn assert_input_pairs_eq(
+        got: &[input::Pair<input::Input>],
+        want: &[input::Pair<input::Input>],
+    ) {
+        assert_eq!(
+            serde_json::to_string(got).unwrap(),
+            serde_json::to_string(want).unwrap()
+        );
+    }
+
+    /// Writes inputs for the ticks the way a battle would, with keyframes and state hashes along the way.
+    fn write_ticks(writer: &mut Writer, ticks: std::ops::Range<u32>) {
+        for tick in ticks {
+            if writer.wants_keyframe(tick) {
+                writer
+                    .write_keyframe(tick, &test_state((tick / KEYFRAME_INTERVAL) as u8))
+                    .unwrap();
+            }
+            if tick % 60 == 0 {
+                writer.write_state_hash(tick, &tick.to_le_bytes()).unwrap();
+            }
+            writer.write_input(0, &test_input_pair(tick)).unwrap();
+        }
+    }
+
+    /// Returns a writer writing to a temporary file, along with the file to read it back from.
+    fn start_replay() -> (Writer, std::fs::File) {
+        let f = tempfile::tempfile().unwrap();
+        let mut writer = Writer::new(
+            Box::new(f.try_clone().unwrap()),
+            0,
+            Metadata {
+                session_id: "test".to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        writer.write_state(&test_state(0xff)).unwrap();
+        (writer, f)
+    }
+
+    fn write_replay(signing_key: Option<&SigningKey>) -> std::fs::File {
+        let (mut writer, mut f) = start_replay();
+        write_ticks(&mut writer, 0..TICKS);
+        writer
+            .write_outcome(&Outcome {
+                winner_player_index: Some(1),
+                ended_at: 1,
+            })
+            .unwrap();
+        writer.finish(Some(&[1u8; 32]), signing_key).unwrap();
+        f.seek(std::io::SeekFrom::Start(0)).unwrap();
+        f
+    }
+
+    fn test_input_pairs(ticks: std::ops::Range<u32>) -> Vec<input::Pair<input::Input>> {
+        ticks.map(test_input_pair).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let replay = Replay::decode(write_replay(None)).unwrap();
+        assert!(replay.is_latest_version());
+        assert!(!replay.truncated);
+        assert_eq!(replay.local_player_index, 0);
+        assert_eq!(replay.metadata.as_ref().unwrap().session_id, "test");
+        assert_eq!(
+            replay.outcome.as_ref().unwrap().winner_player_index,
+            Some(1)
+        );
+        assert_eq!(replay.state.as_slice(), test_state(0xff).as_slice());
+        assert_input_pairs_eq(&replay.input_pairs, &test_input_pairs(0..TICKS));
+        assert_eq!(
+            replay
+                .keyframes
+                .iter()
+                .map(|keyframe| (keyframe.tick, keyframe.input_index))
+                .collect::<Vec<_>>(),
+            vec![(600, 600), (1200, 1200)]
+        );
+        assert_eq!(
+            replay.keyframes[1].state.as_slice(),
+            test_state(2).as_slice()
+        );
+        assert_eq!(replay.state_hashes.len(), 25);
+        assert_eq!(replay.state_hashes[1], (60, 60u32.to_le_bytes().to_vec()));
+        let inputs_hashes = replay.inputs_hashes.as_ref().unwrap();
+        assert_eq!(inputs_hashes.local, inputs_hash(0, &replay.input_pairs));
+        assert_eq!(inputs_hashes.remote, Some([1u8; 32]));
+        assert!(replay.signature.is_none());
+
+        let seek = replay.seek(1000);
+        assert_eq!(seek.tick, 600);
+        assert_eq!(seek.state.as_slice(), test_state(1).as_slice());
+        assert_input_pairs_eq(seek.input_pairs, &test_input_pairs(600..TICKS));
+
+        let seek = replay.seek(10);
+        assert_eq!(seek.tick, 0);
+        assert_eq!(seek.input_pairs.len(), TICKS as usize);
+    }
+
+    #[test]
+    fn seeks_with_keyframe_index() {
+        let mut f = write_replay(None);
+        let index = read_keyframe_index(&mut f).unwrap().unwrap();
+        assert_eq!(
+            index.iter().map(|entry| entry.tick).collect::<Vec<_>>(),
+            vec![600, 1200]
+        );
+
+        let replay = Replay::decode_from_keyframe(&mut f, &index[0]).unwrap();
+        assert!(!replay.truncated);
+        assert_eq!(replay.metadata.as_ref().unwrap().session_id, "test");
+        assert_eq!(replay.state.as_slice(), test_state(1).as_slice());
+        assert_input_pairs_eq(&replay.input_pairs, &test_input_pairs(600..TICKS));
+        assert_eq!(
+            replay
+                .keyframes
+                .iter()
+                .map(|keyframe| (keyframe.tick, keyframe.input_index))
+                .collect::<Vec<_>>(),
+            vec![(1200, 600)]
+        );
+        assert!(replay.outcome.is_some());
+        assert!(replay.signature.is_none());
+
+        let replay = Replay::decode_from_keyframe(&mut f, &index[1]).unwrap();
+        assert_eq!(replay.state.as_slice(), test_state(2).as_slice());
+        assert_input_pairs_eq(&replay.input_pairs, &test_input_pairs(1200..TICKS));
+        assert!(replay.keyframes.is_empty());
+
+        assert!(Replay::decode_from_keyframe(
+            &mut f,
+            &KeyframeIndexEntry {
+                tick: 1200,
+                offset: index[0].offset,
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn upgrades_untagged() {
+        let mut encoder = zstd::Encoder::new(vec![], 3).unwrap();
+        encoder.write_u8(0).unwrap();
+        let state = test_state(0xff);
+        encoder
+            .write_u32::<byteorder::LittleEndian>(state.as_slice().len() as u32)
+            .unwrap();
+        encoder.write_all(state.as_slice()).unwrap();
+        for ip in test_input_pairs(0..100) {
+            encoder
+                .write_u32::<byteorder::LittleEndian>(ip.local.local_tick)
+                .unwrap();
+            encoder
+                .write_u32::<byteorder::LittleEndian>(ip.local.remote_tick)
+                .unwrap();
+            encoder
+                .write_u16::<byteorder::LittleEndian>(ip.local.joyflags)
+                .unwrap();
+            encoder
+                .write_u16::<byteorder::LittleEndian>(ip.remote.joyflags)
+                .unwrap();
+            encoder.write_u8(ip.local.custom_screen_state).unwrap();
+            encoder.write_u8(ip.remote.custom_screen_state).unwrap();
+            for turn in [&ip.local.turn, &ip.remote.turn] {
+                encoder
+                    .write_u32::<byteorder::LittleEndian>(turn.len() as u32)
+                    .unwrap();
+                encoder.write_all(turn).unwrap();
+            }
+        }
+        let mut buf = HEADER.to_vec();
+        buf.push(VERSION_UNTAGGED);
+        buf.extend(encoder.finish().unwrap());
+
+        let replay = Replay::decode(&buf[..]).unwrap();
+        assert_eq!(replay.version, VERSION_UNTAGGED);
+        assert!(!replay.is_latest_version());
+        assert!(!replay.truncated);
+        assert!(replay.metadata.is_none());
+        assert_input_pairs_eq(&replay.input_pairs, &test_input_pairs(0..100));
+
+        let mut f = tempfile::tempfile().unwrap();
+        replay.upgrade(Box::new(f.try_clone().unwrap())).unwrap();
+        f.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let upgraded = Replay::decode(&f).unwrap();
+        assert!(upgraded.is_latest_version());
+        assert!(!upgraded.truncated);
+        assert_eq!(upgraded.state.as_slice(), state.as_slice());
+        assert_input_pairs_eq(&upgraded.input_pairs, &replay.input_pairs);
+        assert_eq!(
+            upgraded.inputs_hashes.unwrap().local,
+            inputs_hash(0, &replay.input_pairs)
+        );
+        assert!(read_keyframe_index(&mut f).unwrap().is_some());
+    }
+
+    #[test]
+    fn recovers_truncated() {
+        // Stopping without finishing or even dropping the writer, as in a crash, leaves only the frames that were flushed, with no end marker after them.
+        let (mut writer, mut f) = start_replay();
+        write_ticks(&mut writer, 0..100);
+        std::mem::forget(writer);
+        f.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let replay = Replay::decode(&f).unwrap();
+        assert!(replay.truncated);
+        assert_input_pairs_eq(
+            &replay.input_pairs,
+            &test_input_pairs(0..INPUTS_PER_FRAME as u32),
+        );
+        assert!(replay.outcome.is_none());
+        assert!(replay.inputs_hashes.is_none());
+        assert!(read_keyframe_index(&mut f).unwrap().is_none());
+
+        // Cutting the last frame short loses it, but nothing before it.
+        let mut buf = vec![];
+        write_replay(None).read_to_end(&mut buf).unwrap();
+        buf.truncate(buf.len() - TRAILER_LENGTH - 1);
+        let replay = Replay::decode(&buf[..]).unwrap();
+        assert!(replay.truncated);
+        assert_input_pairs_eq(&replay.input_pairs, &test_input_pairs(0..TICKS));
+        assert!(replay.inputs_hashes.is_none());
+    }
+
+    #[test]
+    fn verifies_signature() {
+        let key = SigningKey::generate();
+        let replay = Replay::decode(write_replay(Some(&key))).unwrap();
+        assert!(!replay.truncated);
+        let signature = replay.signature.unwrap();
+        assert_eq!(signature.public_key, key.public_key());
+        assert!(signature.is_valid());
+
+        let mut other_key = signature.clone();
+        other_key.public_key = SigningKey::generate().public_key();
+        assert!(!other_key.is_valid());
+
+        let mut other_replay = signature.clone();
+        other_replay.digest[0] ^= 1;
+        assert!(!other_replay.is_valid());
+
+        let mut other_signature = signature;
+        other_signature.signature[0] ^= 1;
+        assert!(!other_signature.is_valid());
+    }
+}
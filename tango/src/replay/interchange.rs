@@ -0,0 +1,343 @@
+//! An open representation of replays, for tools that want to read or write them without linking against tango.
+//!
+//! A replay is a single document, encoded as either JSON or CBOR with the same structure. Binary data (savestates, turns and hashes) is always a base64 string with padding, in both encodings.
+//!
+//! ```text
+//! {
+//!   "format_version": 1,
+//!   "local_player_index": 0,          // which player recorded the replay: 0 is P1, 1 is P2
+//!   "metadata": {                     // null for replays recorded before metadata was
+//!     "session_id": "...",
+//!     "battle_number": 1,
+//!     "local": { "nickname": "...", "game_id": "...", "input_delay": 3 },
+//!     "remote": { "nickname": "...", "game_id": "...", "input_delay": 3 },
+//!     "started_at": 1650000000000     // milliseconds since the Unix epoch
+//!   },
+//!   "outcome": {                      // null if the replay ends before the battle did
+//!     "winner_player_index": 0,       // null if there was no winner
+//!     "ended_at": 1650000000000
+//!   },
+//!   "initial_state": "<base64>",      // mGBA savestate the battle starts from
+//!   "input_pairs": [                  // one per tick, in order
+//!     {
+//!       "local": {
+//!         "local_tick": 0,
+//!         "remote_tick": 0,
+//!         "joyflags": 1,              // GBA key bits, as mGBA numbers them
+//!         "buttons": ["A"],           // the same, by name: A B SELECT START RIGHT LEFT UP DOWN R L
+//!         "custom_screen_state": 0,
+//!         "turn": "<base64>"          // the game's marshaled turn data, empty if none this tick
+//!       },
+//!       "remote": { ... }
+//!     }
+//!   ],
+//!   "keyframes": [                    // savestates to seek to, ordered by tick
+//!     { "tick": 600, "input_index": 600, "state": "<base64>" }
+//!   ],
+//!   "state_hashes": [                 // hashes of the game state computed during the battle, ordered by tick
+//!     { "tick": 60, "hash": "<base64>" }
+//!   ],
+//!   "inputs_hashes": {                // null if not recorded
+//!     "local": "<base64>",
+//!     "remote": "<base64>"            // null if the other side never sent theirs
+//!   },
+//!   "signature": {                    // null if unsigned
+//!     "public_key": "<base64>",
+//!     "valid": true
+//!   },
+//!   "truncated": false                // true if the replay was cut short, e.g. by a crash
+//! }
+//! ```
+//!
+//! `input_index` is the index into `input_pairs` of the first pair to play after loading the keyframe. On import, `buttons` is ignored since `joyflags` is what's played, the local inputs hash is computed again from the inputs, and `signature` is ignored: the imported replay is unsigned, since it isn't byte for byte what was signed.
+
+use crate::{input, replay};
+
+/// Bumped whenever the document changes in a way that older readers can't handle.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    Json,
+    Cbor,
+}
+
+impl Format {
+    /// Guesses the format from a file's extension.
+    pub fn from_path(path: &std::path::Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(Format::Json),
+            "cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+}
+
+mod base64_bytes {
+    pub fn serialize<S: serde::Serializer>(v: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&base64::encode(v))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = <String as serde::Deserialize>::deserialize(d)?;
+        base64::decode(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+mod base64_bytes_opt {
+    pub fn serialize<S: serde::Serializer>(v: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+        match v {
+            Some(v) => s.serialize_some(&base64::encode(v)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        <Option<String> as serde::Deserialize>::deserialize(d)?
+            .map(|s| base64::decode(&s).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+const BUTTONS: &[(u32, &str)] = &[
+    (mgba::input::keys::A, "A"),
+    (mgba::input::keys::B, "B"),
+    (mgba::input::keys::SELECT, "SELECT"),
+    (mgba::input::keys::START, "START"),
+    (mgba::input::keys::RIGHT, "RIGHT"),
+    (mgba::input::keys::LEFT, "LEFT"),
+    (mgba::input::keys::UP, "UP"),
+    (mgba::input::keys::DOWN, "DOWN"),
+    (mgba::input::keys::R, "R"),
+    (mgba::input::keys::L, "L"),
+];
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Input {
+    pub local_tick: u32,
+    pub remote_tick: u32,
+    pub joyflags: u16,
+    #[serde(default)]
+    pub buttons: Vec<String>,
+    pub custom_screen_state: u8,
+    #[serde(with = "base64_bytes")]
+    pub turn: Vec<u8>,
+}
+
+impl From<&input::Input> for Input {
+    fn from(input: &input::Input) -> Self {
+        Self {
+            local_tick: input.local_tick,
+            remote_tick: input.remote_tick,
+            joyflags: input.joyflags,
+            buttons: BUTTONS
+                .iter()
+                .filter(|(key, _)| input.joyflags as u32 & key != 0)
+                .map(|(_, name)| name.to_string())
+                .collect(),
+            custom_screen_state: input.custom_screen_state,
+            turn: input.turn.clone(),
+        }
+    }
+}
+
+impl From<Input> for input::Input {
+    fn from(input: Input) -> Self {
+        Self {
+            local_tick: input.local_tick,
+            remote_tick: input.remote_tick,
+            joyflags: input.joyflags,
+            custom_screen_state: input.custom_screen_state,
+            turn: input.turn,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Keyframe {
+    pub tick: u32,
+    pub input_index: usize,
+    #[serde(with = "base64_bytes")]
+    pub state: Vec<u8>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct StateHash {
+    pub tick: u32,
+    #[serde(with = "base64_bytes")]
+    pub hash: Vec<u8>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct InputsHashes {
+    #[serde(with = "base64_bytes")]
+    pub local: Vec<u8>,
+    #[serde(default, with = "base64_bytes_opt")]
+    pub remote: Option<Vec<u8>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Signature {
+    #[serde(with = "base64_bytes")]
+    pub public_key: Vec<u8>,
+    pub valid: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Document {
+    pub format_version: u32,
+    pub local_player_index: u8,
+    pub metadata: Option<replay::Metadata>,
+    pub outcome: Option<replay::Outcome>,
+    #[serde(with = "base64_bytes")]
+    pub initial_state: Vec<u8>,
+    pub input_pairs: Vec<input::Pair<Input>>,
+    #[serde(default)]
+    pub keyframes: Vec<Keyframe>,
+    #[serde(default)]
+    pub state_hashes: Vec<StateHash>,
+    #[serde(default)]
+    pub inputs_hashes: Option<InputsHashes>,
+    #[serde(default)]
+    pub signature: Option<Signature>,
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+fn to_hash(v: &[u8]) -> anyhow::Result<[u8; 32]> {
+    v.try_into()
+        .map_err(|_| anyhow::format_err!("expected a 32 byte hash, got {} bytes", v.len()))
+}
+
+fn to_state(v: &[u8]) -> anyhow::Result<mgba::state::State> {
+    if v.len() != std::mem::size_of::<mgba::c::GBASerializedState>() {
+        anyhow::bail!("invalid state length: {}", v.len());
+    }
+    Ok(mgba::state::State::from_slice(v))
+}
+
+impl Document {
+    pub fn from_replay(replay: &replay::Replay) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            local_player_index: replay.local_player_index,
+            metadata: replay.metadata.clone(),
+            outcome: replay.outcome.clone(),
+            initial_state: replay.state.as_slice().to_vec(),
+            input_pairs: replay
+                .input_pairs
+                .iter()
+                .map(|ip| input::Pair {
+                    local: Input::from(&ip.local),
+                    remote: Input::from(&ip.remote),
+                })
+                .collect(),
+            keyframes: replay
+                .keyframes
+                .iter()
+                .map(|keyframe| Keyframe {
+                    tick: keyframe.tick,
+                    input_index: keyframe.input_index,
+                    state: keyframe.state.as_slice().to_vec(),
+                })
+                .collect(),
+            state_hashes: replay
+                .state_hashes
+                .iter()
+                .map(|(tick, hash)| StateHash {
+                    tick: *tick,
+                    hash: hash.clone(),
+                })
+                .collect(),
+            inputs_hashes: replay
+                .inputs_hashes
+                .as_ref()
+                .map(|inputs_hashes| InputsHashes {
+                    local: inputs_hashes.local.to_vec(),
+                    remote: inputs_hashes.remote.map(|remote| remote.to_vec()),
+                }),
+            signature: replay.signature.as_ref().map(|signature| Signature {
+                public_key: signature.public_key.to_vec(),
+                valid: signature.is_valid(),
+            }),
+            truncated: replay.truncated,
+        }
+    }
+
+    pub fn to_replay(&self) -> anyhow::Result<replay::Replay> {
+        if self.format_version > FORMAT_VERSION {
+            anyhow::bail!("unsupported format version: {}", self.format_version);
+        }
+
+        Ok(replay::Replay {
+            version: replay::VERSION,
+            local_player_index: self.local_player_index,
+            metadata: self.metadata.clone(),
+            outcome: self.outcome.clone(),
+            state: to_state(&self.initial_state)?,
+            input_pairs: self
+                .input_pairs
+                .iter()
+                .map(|ip| input::Pair {
+                    local: ip.local.clone().into(),
+                    remote: ip.remote.clone().into(),
+                })
+                .collect(),
+            keyframes: self
+                .keyframes
+                .iter()
+                .map(|keyframe| {
+                    Ok(replay::Keyframe {
+                        tick: keyframe.tick,
+                        input_index: keyframe.input_index,
+                        state: to_state(&keyframe.state)?,
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            state_hashes: self
+                .state_hashes
+                .iter()
+                .map(|state_hash| (state_hash.tick, state_hash.hash.clone()))
+                .collect(),
+            inputs_hashes: self
+                .inputs_hashes
+                .as_ref()
+                .map(|inputs_hashes| {
+                    Ok::<_, anyhow::Error>(replay::InputsHashes {
+                        local: to_hash(&inputs_hashes.local)?,
+                        remote: inputs_hashes
+                            .remote
+                            .as_ref()
+                            .map(|remote| to_hash(remote))
+                            .transpose()?,
+                    })
+                })
+                .transpose()?,
+            signature: None,
+            truncated: false,
+        })
+    }
+
+    /// Writes the document out as a `.tangoreplay`, in the newest version.
+    pub fn write_replay(&self, w: Box<dyn std::io::Write + Send>) -> anyhow::Result<()> {
+        self.to_replay()?.upgrade(w)?;
+        Ok(())
+    }
+
+    pub fn encode(&self, format: Format, w: impl std::io::Write) -> anyhow::Result<()> {
+        match format {
+            Format::Json => serde_json::to_writer_pretty(w, self)?,
+            Format::Cbor => serde_cbor::to_writer(w, self)?,
+        }
+        Ok(())
+    }
+
+    pub fn decode(format: Format, r: impl std::io::Read) -> anyhow::Result<Self> {
+        Ok(match format {
+            Format::Json => serde_json::from_reader(r)?,
+            Format::Cbor => serde_cbor::from_reader(r)?,
+        })
+    }
+}
@@ -0,0 +1,615 @@
+use crate::audio::Stream;
+use crate::{audio, battle, compat, config, current_input, facade, fastforwarder, gui, hooks};
+use crate::{input, loaded, replay};
+use parking_lot::Mutex;
+use rand::Rng;
+use rand::SeedableRng;
+use std::sync::Arc;
+
+const RANDOM_JOYFLAGS_MASK: u16 = (mgba::input::keys::A
+    | mgba::input::keys::B
+    | mgba::input::keys::L
+    | mgba::input::keys::R
+    | mgba::input::keys::UP
+    | mgba::input::keys::DOWN
+    | mgba::input::keys::LEFT
+    | mgba::input::keys::RIGHT) as u16;
+
+const RANDOM_JOYFLAGS_HOLD_FRAMES: u32 = 6;
+
+#[derive(Clone, Debug)]
+struct Step {
+    frames: u32,
+    joyflags: u16,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+fn parse_button(name: &str) -> anyhow::Result<u16> {
+    Ok(match name.to_uppercase().as_str() {
+        "-" => 0,
+        "A" => mgba::input::keys::A,
+        "B" => mgba::input::keys::B,
+        "SELECT" => mgba::input::keys::SELECT,
+        "START" => mgba::input::keys::START,
+        "RIGHT" => mgba::input::keys::RIGHT,
+        "LEFT" => mgba::input::keys::LEFT,
+        "UP" => mgba::input::keys::UP,
+        "DOWN" => mgba::input::keys::DOWN,
+        "R" => mgba::input::keys::R,
+        "L" => mgba::input::keys::L,
+        _ => anyhow::bail!("unknown button: {}", name),
+    } as u16)
+}
+
+impl Script {
+    /// Parses a script of the form `<frames> <button>[+<button>...]` per line, e.g. `30 A` or `2 UP+B`. `-` releases all buttons. Everything after a `#` is ignored.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut steps = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let line = match line.split_once('#') {
+                Some((line, _)) => line,
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (frames, buttons) = line.split_once(char::is_whitespace).ok_or_else(|| {
+                anyhow::format_err!("line {}: expected frames and buttons", i + 1)
+            })?;
+            let frames = frames
+                .parse::<u32>()
+                .map_err(|e| anyhow::format_err!("line {}: {}", i + 1, e))?;
+            let mut joyflags = 0u16;
+            for button in buttons.trim().split('+') {
+                joyflags |= parse_button(button.trim())
+                    .map_err(|e| anyhow::format_err!("line {}: {}", i + 1, e))?;
+            }
+            steps.push(Step { frames, joyflags });
+        }
+        Ok(Self { steps })
+    }
+
+    pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.steps.iter().map(|step| step.frames).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn joyflags_at(&self, mut frame: u32) -> Option<u16> {
+        for step in &self.steps {
+            if frame < step.frames {
+                return Some(step.joyflags);
+            }
+            frame -= step.frames;
+        }
+        None
+    }
+}
+
+pub struct Side {
+    pub rom_path: std::path::PathBuf,
+    pub save_path: std::path::PathBuf,
+    pub state_path: Option<std::path::PathBuf>,
+    pub script: Script,
+}
+
+pub struct Settings {
+    pub sides: [Side; 2],
+    pub session_id: String,
+    pub input_delay: u32,
+    pub battles: usize,
+    pub max_frames: u32,
+    pub seed: u64,
+    pub replays_path: std::path::PathBuf,
+}
+
+#[derive(Debug)]
+pub struct BattleReport {
+    pub number: u8,
+    pub committed_ticks: [usize; 2],
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub battles: Vec<BattleReport>,
+}
+
+struct BattleRecord {
+    number: u8,
+    committed_state: mgba::state::State,
+}
+
+struct NullStream;
+
+impl audio::Stream for NullStream {
+    fn fill(&self, _buf: &mut [i16]) -> usize {
+        0
+    }
+}
+
+enum Observation {
+    NotStarted,
+    Negotiating,
+    Battle {
+        number: u8,
+        committed_state: Option<mgba::state::State>,
+    },
+    BetweenBattles,
+}
+
+async fn observe(
+    match_state: &tokio::sync::Mutex<loaded::MatchState>,
+) -> anyhow::Result<Observation> {
+    let match_state = match_state.lock().await;
+    let m = match &*match_state {
+        loaded::MatchState::NoMatch => {
+            return Ok(Observation::NotStarted);
+        }
+        loaded::MatchState::Aborted => {
+            anyhow::bail!("match aborted");
+        }
+        loaded::MatchState::Match(m) => m,
+    };
+
+    match m.poll_for_ready().await {
+        battle::NegotiationStatus::Ready => {}
+        battle::NegotiationStatus::NotReady(_) => {
+            return Ok(Observation::Negotiating);
+        }
+        battle::NegotiationStatus::Failed(_) => {
+            anyhow::bail!("negotiation failed");
+        }
+    }
+
+    let battle_state = m.lock_battle_state().await;
+    Ok(match &battle_state.battle {
+        Some(battle) => Observation::Battle {
+            number: battle_state.number,
+            committed_state: battle.committed_state().clone(),
+        },
+        None => Observation::BetweenBattles,
+    })
+}
+
+struct SideContext {
+    index: usize,
+    handle: tokio::runtime::Handle,
+    compat_list: Arc<compat::CompatList>,
+    config: config::Config,
+    session_id: String,
+    replays_path: std::path::PathBuf,
+    input_delay: u32,
+    battles: usize,
+    max_frames: u32,
+    seed: u64,
+    done: Arc<[std::sync::atomic::AtomicBool; 2]>,
+    failed: Arc<std::sync::atomic::AtomicBool>,
+}
+
+fn run_side(ctx: SideContext, side: Side) -> anyhow::Result<Vec<BattleRecord>> {
+    let mut core = mgba::core::Core::new_gba("tango")?;
+    let rom_vf = mgba::vfile::VFile::open(&side.rom_path, mgba::vfile::flags::O_RDONLY)?;
+    core.as_mut().load_rom(rom_vf)?;
+
+    // Never write back to the original save: each side gets its own scratch copy.
+    let save_file = tempfile::NamedTempFile::new()?;
+    std::fs::copy(&side.save_path, save_file.path())?;
+    let save_vf = mgba::vfile::VFile::open(save_file.path(), mgba::vfile::flags::O_RDWR)?;
+    core.as_mut().load_save(save_vf)?;
+
+    let id = ctx
+        .compat_list
+        .id_by_title_and_crc32(&core.as_ref().game_title(), core.as_ref().crc32())
+        .ok_or_else(|| {
+            anyhow::format_err!(
+                "could not find compatibility data for {} where title = {}, crc32 = {:08x}",
+                side.rom_path.display(),
+                core.as_ref().game_title(),
+                core.as_ref().crc32()
+            )
+        })?
+        .to_string();
+    let hooks = hooks::HOOKS
+        .get(&ctx.compat_list.game_by_id(&id).unwrap().hooks)
+        .unwrap();
+
+    let match_state = Arc::new(tokio::sync::Mutex::new(loaded::MatchState::NoMatch));
+    let joyflags = Arc::new(std::sync::atomic::AtomicU32::new(0));
+    let audio_state_holder = Arc::new(parking_lot::Mutex::new(None));
+
+    let mut audio_core = mgba::core::Core::new_gba("tango")?;
+    let rom_vf = mgba::vfile::VFile::open(&side.rom_path, mgba::vfile::flags::O_RDONLY)?;
+    audio_core.as_mut().load_rom(rom_vf)?;
+    audio_core.as_mut().reset();
+    audio_core.set_traps(hooks.get_audio_traps(audio_state_holder.clone()));
+
+    let mut muxer = audio::mux_stream::MuxStream::new();
+    let primary_mux_handle = muxer.add(NullStream);
+    let audio_core_mux_handle = muxer.add(audio::timewarp_stream::TimewarpStream::new(
+        &audio_core,
+        cpal::SampleRate(48000),
+        2,
+    ));
+
+    let audio_core_thread = mgba::thread::Thread::new(audio_core);
+    audio_core_thread.start();
+    audio_core_thread.handle().pause();
+    audio_core_thread.handle().run_on_core(|mut core| {
+        core.gba_mut()
+            .sync_mut()
+            .as_mut()
+            .expect("sync")
+            .set_fps_target(loaded::EXPECTED_FPS as f32);
+    });
+
+    // There is no audio device to pull samples out of the audio core, so drain them ourselves.
+    let draining = Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let drain_thread = {
+        let draining = draining.clone();
+        std::thread::spawn(move || {
+            let mut buf = vec![0i16; 48000 / 100 * 2];
+            while draining.load(std::sync::atomic::Ordering::Relaxed) {
+                muxer.fill(&mut buf);
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        })
+    };
+
+    let fastforwarder = fastforwarder::Fastforwarder::new(&side.rom_path, hooks)?;
+
+    let replay_folder_name = ctx.replays_path.join(format!("p{}", ctx.index + 1));
+    std::fs::create_dir_all(&replay_folder_name)?;
+
+    let config = Arc::new(Mutex::new(ctx.config));
+    let gui_state = Arc::new(gui::State::new(
+        config.clone(),
+        std::rc::Rc::new(std::cell::RefCell::new(current_input::CurrentInput::new())),
+    ));
+    gui_state.submit_connect_request(gui::ConnectRequest {
+        code: ctx.session_id.clone(),
+        replay_folder_name,
+        input_delay: ctx.input_delay,
+        auto_input_delay: false,
+        renegotiate_input_delay: false,
+        allow_spectators: false,
+        random_opponent: false,
+        advertise: false,
+        lan: false,
+        lan_host: None,
+    });
+
+    core.set_traps(hooks.get_primary_traps(
+        ctx.handle.clone(),
+        facade::Facade::new(
+            ctx.handle.clone(),
+            ctx.compat_list.clone(),
+            match_state.clone(),
+            joyflags.clone(),
+            gui_state,
+            config,
+            audio_state_holder,
+            audio_core_thread.handle(),
+            primary_mux_handle,
+            audio_core_mux_handle,
+            Arc::new(parking_lot::Mutex::new(fastforwarder)),
+        ),
+    ));
+    core.as_mut().reset();
+
+    if let Some(state_path) = &side.state_path {
+        core.as_mut()
+            .load_state(&mgba::state::State::from_slice(&std::fs::read(state_path)?))?;
+    }
+
+    let mut rng = rand_pcg::Mcg128Xsl64::seed_from_u64(ctx.seed ^ ctx.index as u64);
+    let mut random_joyflags = 0u16;
+
+    let mut records = vec![];
+    let mut last_battle = None::<BattleRecord>;
+    let mut match_started = false;
+
+    let r = (|| {
+        for frame in 0..ctx.max_frames {
+            if ctx.failed.load(std::sync::atomic::Ordering::Relaxed) {
+                anyhow::bail!("other side failed");
+            }
+
+            if ctx
+                .done
+                .iter()
+                .all(|done| done.load(std::sync::atomic::Ordering::Relaxed))
+            {
+                return Ok(());
+            }
+
+            let keys = match side.script.joyflags_at(frame) {
+                Some(keys) => keys,
+                None => {
+                    if frame % RANDOM_JOYFLAGS_HOLD_FRAMES == 0 {
+                        random_joyflags = rng.gen::<u16>() & RANDOM_JOYFLAGS_MASK;
+                    }
+                    random_joyflags
+                }
+            };
+            joyflags.store(keys as u32, std::sync::atomic::Ordering::Relaxed);
+            core.as_mut().set_keys(keys as u32);
+            core.as_mut().run_frame();
+
+            if ctx.done[ctx.index].load(std::sync::atomic::Ordering::Relaxed) {
+                // Keep the match alive until the other side has caught up.
+                continue;
+            }
+
+            match ctx.handle.block_on(observe(&match_state))? {
+                Observation::NotStarted => {
+                    if match_started {
+                        if let Some(record) = last_battle.take() {
+                            records.push(record);
+                        }
+                        log::info!("p{}: match ended", ctx.index + 1);
+                        ctx.done[ctx.index].store(true, std::sync::atomic::Ordering::Relaxed);
+                    }
+                }
+                Observation::Negotiating => {
+                    match_started = true;
+                }
+                Observation::Battle {
+                    number,
+                    committed_state,
+                } => {
+                    match_started = true;
+                    if let Some(record) = last_battle.take() {
+                        if record.number != number {
+                            records.push(record);
+                        } else {
+                            last_battle = Some(record);
+                        }
+                    }
+                    if let Some(committed_state) = committed_state {
+                        last_battle = Some(BattleRecord {
+                            number,
+                            committed_state,
+                        });
+                    }
+                }
+                Observation::BetweenBattles => {
+                    if let Some(record) = last_battle.take() {
+                        log::info!("p{}: battle {} ended", ctx.index + 1, record.number);
+                        records.push(record);
+                    }
+                }
+            }
+
+            if records.len() >= ctx.battles {
+                ctx.done[ctx.index].store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+        anyhow::bail!("ran out of frames after {}", ctx.max_frames);
+    })();
+
+    if r.is_err() {
+        ctx.failed.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    // Tear down the match before the cores it references.
+    ctx.handle.block_on(async {
+        *match_state.lock().await = loaded::MatchState::NoMatch;
+    });
+    draining.store(false, std::sync::atomic::Ordering::Relaxed);
+    drain_thread.join().expect("join drain thread");
+
+    r.map(|_| records)
+}
+
+fn swap_pair(ip: &input::Pair<input::Input>) -> input::Pair<input::Input> {
+    input::Pair {
+        local: ip.remote.clone(),
+        remote: ip.local.clone(),
+    }
+}
+
+fn inputs_agree(x: &input::Input, y: &input::Input) -> bool {
+    x.local_tick == y.local_tick
+        && x.joyflags == y.joyflags
+        && x.custom_screen_state == y.custom_screen_state
+        && x.turn == y.turn
+}
+
+fn verify_battle(
+    rom_paths: [&std::path::Path; 2],
+    hooks: [&'static Box<dyn hooks::Hooks + Send + Sync>; 2],
+    replay_paths: [std::path::PathBuf; 2],
+    records: [&BattleRecord; 2],
+) -> anyhow::Result<BattleReport> {
+    let number = records[0].number;
+    let replays = [
+        replay::Replay::decode(std::fs::File::open(&replay_paths[0])?)?,
+        replay::Replay::decode(std::fs::File::open(&replay_paths[1])?)?,
+    ];
+
+    if replays[0].local_player_index == replays[1].local_player_index {
+        anyhow::bail!(
+            "battle {}: both sides think they are player {}",
+            number,
+            replays[0].local_player_index + 1
+        );
+    }
+
+    for (i, (ip0, ip1)) in replays[0]
+        .input_pairs
+        .iter()
+        .zip(replays[1].input_pairs.iter())
+        .enumerate()
+    {
+        if !inputs_agree(&ip0.local, &ip1.remote) || !inputs_agree(&ip0.remote, &ip1.local) {
+            anyhow::bail!(
+                "battle {}: committed inputs diverged at pair {}: {:?} vs {:?}",
+                number,
+                i,
+                ip0,
+                ip1
+            );
+        }
+    }
+
+    // The two peers each run the battle from their own side, so their states are not comparable directly. Instead, replay the other side's committed inputs from our own initial state and check that we land on exactly the state we committed live.
+    for side in 0..2 {
+        let other = 1 - side;
+        let n = replays[side].input_pairs.len();
+        if n == 0 || replays[other].input_pairs.len() < n {
+            log::warn!(
+                "battle {}: p{} committed {} inputs but p{} only committed {}, skipping state check",
+                number,
+                side + 1,
+                n,
+                other + 1,
+                replays[other].input_pairs.len()
+            );
+            continue;
+        }
+
+        let pairs = replays[other].input_pairs[..n]
+            .iter()
+            .map(swap_pair)
+            .collect::<Vec<_>>();
+        let last_committed_remote_input = pairs.last().expect("last pair").remote.clone();
+
+        let mut ff = fastforwarder::Fastforwarder::new(rom_paths[side], hooks[side])?;
+        let (committed_state, _, _) = ff.fastforward(
+            &replays[side].state,
+            replays[side].local_player_index,
+            &pairs,
+            last_committed_remote_input,
+            &[],
+        )?;
+
+        if committed_state.as_slice() != records[side].committed_state.as_slice() {
+            anyhow::bail!(
+                "battle {}: p{} committed state does not match p{}'s inputs after {} ticks",
+                number,
+                side + 1,
+                other + 1,
+                n
+            );
+        }
+    }
+
+    Ok(BattleReport {
+        number,
+        committed_ticks: [replays[0].input_pairs.len(), replays[1].input_pairs.len()],
+    })
+}
+
+pub fn run(settings: Settings) -> anyhow::Result<Report> {
+    let compat_list = Arc::new(compat::load()?);
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+
+    std::fs::create_dir_all(&settings.replays_path)?;
+    let replays_path = std::fs::canonicalize(&settings.replays_path)?;
+
+    let done = Arc::new([
+        std::sync::atomic::AtomicBool::new(false),
+        std::sync::atomic::AtomicBool::new(false),
+    ]);
+    let failed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    let [side0, side1] = settings.sides;
+    let rom_paths = [side0.rom_path.clone(), side1.rom_path.clone()];
+
+    let threads = [side0, side1]
+        .into_iter()
+        .enumerate()
+        .map(|(index, side)| {
+            let ctx = SideContext {
+                index,
+                handle: rt.handle().clone(),
+                compat_list: compat_list.clone(),
+                config: config::Config {
+                    transport: config::Transport::Memory,
+                    ..Default::default()
+                },
+                session_id: settings.session_id.clone(),
+                replays_path: replays_path.clone(),
+                input_delay: settings.input_delay,
+                battles: settings.battles,
+                max_frames: settings.max_frames,
+                seed: settings.seed,
+                done: done.clone(),
+                failed: failed.clone(),
+            };
+            std::thread::Builder::new()
+                .name(format!("sim-p{}", index + 1))
+                .spawn(move || run_side(ctx, side))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut records = vec![];
+    for thread in threads {
+        records.push(
+            thread
+                .join()
+                .map_err(|_| anyhow::anyhow!("side panicked"))??,
+        );
+    }
+
+    let hooks = rom_paths
+        .iter()
+        .map(
+            |rom_path| -> anyhow::Result<&'static Box<dyn hooks::Hooks + Send + Sync>> {
+                let mut core = mgba::core::Core::new_gba("tango")?;
+                let rom_vf = mgba::vfile::VFile::open(rom_path, mgba::vfile::flags::O_RDONLY)?;
+                core.as_mut().load_rom(rom_vf)?;
+                let id = compat_list
+                    .id_by_title_and_crc32(&core.as_ref().game_title(), core.as_ref().crc32())
+                    .ok_or_else(|| anyhow::format_err!("unknown rom: {}", rom_path.display()))?;
+                Ok(*hooks::HOOKS
+                    .get(&compat_list.game_by_id(id).unwrap().hooks)
+                    .unwrap())
+            },
+        )
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut report = Report { battles: vec![] };
+    for (r0, r1) in records[0].iter().zip(records[1].iter()) {
+        if r0.number != r1.number {
+            anyhow::bail!("battle number mismatch: {} != {}", r0.number, r1.number);
+        }
+        let replay_filename = format!("battle{}.tangoreplay", r0.number);
+        let battle_report = verify_battle(
+            [&rom_paths[0], &rom_paths[1]],
+            [hooks[0], hooks[1]],
+            [
+                replays_path.join("p1").join(&replay_filename),
+                replays_path.join("p2").join(&replay_filename),
+            ],
+            [r0, r1],
+        )?;
+        log::info!("battle {} ok: {:?}", r0.number, battle_report);
+        report.battles.push(battle_report);
+    }
+
+    if report.battles.len() < settings.battles {
+        anyhow::bail!(
+            "only {} of {} battles completed",
+            report.battles.len(),
+            settings.battles
+        );
+    }
+
+    Ok(report)
+}
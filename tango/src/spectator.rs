@@ -0,0 +1,293 @@
+use crate::{config, datachannel, input, protocol, transport};
+
+const STATE_CHUNK_LENGTH: usize = 32 * 1024;
+const MAX_INPUT_PAIRS_PER_PACKET: usize = 64;
+
+struct Feed {
+    battle_number: u8,
+    local_player_index: u8,
+    state: mgba::state::State,
+    input_pairs: Vec<input::Pair<input::Input>>,
+    ended: bool,
+}
+
+impl Feed {
+    fn catch_up_packets(&self) -> Vec<protocol::Packet> {
+        let mut packets = state_packets(self.battle_number, self.local_player_index, &self.state);
+        packets.extend(input_pairs_packets(self.battle_number, &self.input_pairs));
+        if self.ended {
+            packets.push(protocol::Packet::SpectatorEndBattle(
+                protocol::SpectatorEndBattle {
+                    battle_number: self.battle_number,
+                },
+            ));
+        }
+        packets
+    }
+}
+
+fn state_packets(
+    battle_number: u8,
+    local_player_index: u8,
+    state: &mgba::state::State,
+) -> Vec<protocol::Packet> {
+    let buf = state.as_slice();
+    buf.chunks(STATE_CHUNK_LENGTH)
+        .enumerate()
+        .map(|(i, chunk)| {
+            protocol::Packet::SpectatorState(protocol::SpectatorState {
+                battle_number,
+                local_player_index,
+                offset: (i * STATE_CHUNK_LENGTH) as u32,
+                total_length: buf.len() as u32,
+                data: chunk.to_vec(),
+            })
+        })
+        .collect()
+}
+
+fn input_pairs_packets(
+    battle_number: u8,
+    input_pairs: &[input::Pair<input::Input>],
+) -> Vec<protocol::Packet> {
+    input_pairs
+        .chunks(MAX_INPUT_PAIRS_PER_PACKET)
+        .map(|chunk| {
+            protocol::Packet::SpectatorInputs(protocol::SpectatorInputs {
+                battle_number,
+                input_pairs: chunk.to_vec(),
+            })
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct BroadcasterInner {
+    feed: Option<Feed>,
+    senders: Vec<tokio::sync::mpsc::UnboundedSender<protocol::Packet>>,
+}
+
+impl BroadcasterInner {
+    fn broadcast(&mut self, packets: Vec<protocol::Packet>) {
+        self.senders
+            .retain(|sender| packets.iter().all(|p| sender.send(p.clone()).is_ok()));
+    }
+}
+
+/// Fans the committed inputs of a match out to spectators. Spectators joining mid-battle are caught up from the start of the current battle.
+#[derive(Default)]
+pub struct Broadcaster {
+    inner: parking_lot::Mutex<BroadcasterInner>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_spectator(&self, transport: std::sync::Arc<dyn transport::Transport>) {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        {
+            let mut inner = self.inner.lock();
+            if let Some(feed) = &inner.feed {
+                for p in feed.catch_up_packets() {
+                    let _ = sender.send(p);
+                }
+            }
+            inner.senders.push(sender);
+        }
+        tokio::task::spawn(async move {
+            while let Some(p) = receiver.recv().await {
+                if let Err(e) = transport.send(&p).await {
+                    log::info!("spectator went away: {:?}", e);
+                    break;
+                }
+            }
+            let _ = transport.close().await;
+        });
+    }
+
+    pub fn start_battle(
+        &self,
+        battle_number: u8,
+        local_player_index: u8,
+        state: mgba::state::State,
+    ) {
+        let mut inner = self.inner.lock();
+        let packets = state_packets(battle_number, local_player_index, &state);
+        inner.feed = Some(Feed {
+            battle_number,
+            local_player_index,
+            state,
+            input_pairs: vec![],
+            ended: false,
+        });
+        inner.broadcast(packets);
+    }
+
+    pub fn add_input_pairs(&self, battle_number: u8, input_pairs: &[input::Pair<input::Input>]) {
+        if input_pairs.is_empty() {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        match &mut inner.feed {
+            Some(feed) if feed.battle_number == battle_number => {
+                feed.input_pairs.extend(input_pairs.iter().cloned());
+            }
+            _ => {
+                return;
+            }
+        }
+        inner.broadcast(input_pairs_packets(battle_number, input_pairs));
+    }
+
+    pub fn end_battle(&self, battle_number: u8) {
+        let mut inner = self.inner.lock();
+        match &mut inner.feed {
+            Some(feed) if feed.battle_number == battle_number => {
+                feed.ended = true;
+            }
+            _ => {
+                return;
+            }
+        }
+        inner.broadcast(vec![protocol::Packet::SpectatorEndBattle(
+            protocol::SpectatorEndBattle { battle_number },
+        )]);
+    }
+}
+
+/// Registers with the matchmaking server as the spectator host for the session and hands every spectator that connects to the broadcaster.
+pub async fn host(
+    matchmaking_connect_addr: String,
+    webrtc: config::WebRTC,
+    session_id: String,
+    spectator_host_token: String,
+    broadcaster: std::sync::Arc<Broadcaster>,
+) -> anyhow::Result<()> {
+    let api = std::sync::Arc::new(::webrtc::api::APIBuilder::new().build());
+    tango_matchmaking::client::host_spectators(&matchmaking_connect_addr, &session_id, &spectator_host_token, |offer_sdp| {
+        let api = api.clone();
+        let webrtc = webrtc.clone();
+        let broadcaster = broadcaster.clone();
+        async move {
+            let (peer_conn, dc) = transport::webrtc::new_peer_connection(&api, &webrtc).await?;
+            let dc = datachannel::DataChannel::new(dc).await;
+
+            {
+                let mut sdp = ::webrtc::peer_connection::sdp::session_description::RTCSessionDescription::default();
+                sdp.sdp_type = ::webrtc::peer_connection::sdp::sdp_type::RTCSdpType::Offer;
+                sdp.sdp = offer_sdp;
+                peer_conn.set_remote_description(sdp).await?;
+            }
+
+            let mut gather_complete = peer_conn.gathering_complete_promise().await;
+            let answer = peer_conn.create_answer(None).await?;
+            peer_conn.set_local_description(answer).await?;
+            gather_complete.recv().await;
+
+            let answer_sdp = peer_conn.local_description().await.expect("local sdp").sdp;
+            log::info!("accepted spectator");
+            broadcaster.add_spectator(std::sync::Arc::new(transport::webrtc::Transport::new(
+                peer_conn, dc,
+            )));
+            Ok(answer_sdp)
+        }
+    })
+    .await
+}
+
+/// Connects to the spectator host of a session.
+pub async fn connect(
+    matchmaking_connect_addr: &str,
+    webrtc: &config::WebRTC,
+    session_id: &str,
+) -> anyhow::Result<std::sync::Arc<dyn transport::Transport>> {
+    let api = ::webrtc::api::APIBuilder::new().build();
+    let (peer_conn, dc) = tango_matchmaking::client::spectate(
+        matchmaking_connect_addr,
+        || transport::webrtc::new_peer_connection(&api, webrtc),
+        session_id,
+    )
+    .await?;
+    let dc = datachannel::DataChannel::new(dc).await;
+    Ok(std::sync::Arc::new(transport::webrtc::Transport::new(
+        peer_conn, dc,
+    )))
+}
+
+pub enum Event {
+    StartBattle {
+        battle_number: u8,
+        local_player_index: u8,
+        state: mgba::state::State,
+    },
+    InputPairs(Vec<input::Pair<input::Input>>),
+    EndBattle,
+}
+
+/// Reassembles the spectator packet stream into battle events.
+#[derive(Default)]
+pub struct Receiver {
+    pending_state: Option<(u8, u8, Vec<u8>)>,
+    battle_number: Option<u8>,
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn handle_packet(&mut self, packet: protocol::Packet) -> anyhow::Result<Option<Event>> {
+        match packet {
+            protocol::Packet::SpectatorState(chunk) => {
+                if chunk.offset == 0 {
+                    self.pending_state = Some((
+                        chunk.battle_number,
+                        chunk.local_player_index,
+                        Vec::with_capacity(chunk.total_length as usize),
+                    ));
+                }
+                let (battle_number, local_player_index, buf) = match &mut self.pending_state {
+                    Some(s) if s.0 == chunk.battle_number && s.2.len() == chunk.offset as usize => {
+                        s
+                    }
+                    _ => {
+                        anyhow::bail!("out of order state chunk");
+                    }
+                };
+                buf.extend_from_slice(&chunk.data);
+                if buf.len() < chunk.total_length as usize {
+                    return Ok(None);
+                }
+                if buf.len() != std::mem::size_of::<mgba::c::GBASerializedState>() {
+                    anyhow::bail!("state has unexpected length: {}", buf.len());
+                }
+                let battle_number = *battle_number;
+                let local_player_index = *local_player_index;
+                let state = mgba::state::State::from_slice(buf);
+                self.pending_state = None;
+                self.battle_number = Some(battle_number);
+                Ok(Some(Event::StartBattle {
+                    battle_number,
+                    local_player_index,
+                    state,
+                }))
+            }
+            protocol::Packet::SpectatorInputs(inputs) => {
+                if self.battle_number != Some(inputs.battle_number) {
+                    return Ok(None);
+                }
+                Ok(Some(Event::InputPairs(inputs.input_pairs)))
+            }
+            protocol::Packet::SpectatorEndBattle(end_battle) => {
+                if self.battle_number != Some(end_battle.battle_number) {
+                    return Ok(None);
+                }
+                self.battle_number = None;
+                Ok(Some(Event::EndBattle))
+            }
+            p => anyhow::bail!("unexpected packet: {:?}", p),
+        }
+    }
+}
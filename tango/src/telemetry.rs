@@ -0,0 +1,218 @@
+use std::io::Write;
+
+/// How many of the most recent round-trip times to take the median of.
+const MAX_RTT_SAMPLES: usize = 16;
+
+/// How many of the most recent ticks to measure packet loss over.
+const PACKET_LOSS_WINDOW: u32 = 600;
+
+/// Round-trip time and jitter, as measured from pings, and loss, as measured from the remote's input packets.
+#[derive(Default)]
+pub struct Connection {
+    samples: std::collections::VecDeque<std::time::Duration>,
+    last_rtt: Option<std::time::Duration>,
+    jitter: std::time::Duration,
+    /// The battle the ticks below are from.
+    input_packets_battle_number: Option<u8>,
+    /// The ticks of the input packets that arrived within the window. The remote sends a packet every tick, with that tick's input last, so every tick missing from here is a lost packet.
+    input_packet_ticks: std::collections::BTreeSet<u32>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+    pub rtt: Option<std::time::Duration>,
+    pub jitter: std::time::Duration,
+    /// Fraction of the remote's recent input packets that never arrived. Always 0 over reliable transports, since those resend whatever is lost.
+    pub packet_loss: f32,
+}
+
+impl Connection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `tick` is that of the last input in the packet, i.e. the one the remote sent it for.
+    pub fn input_packet_received(&mut self, battle_number: u8, tick: u32) {
+        match self.input_packets_battle_number {
+            Some(last_battle_number) if battle_number < last_battle_number => {
+                return;
+            }
+            Some(last_battle_number) if battle_number == last_battle_number => {}
+            _ => {
+                self.input_packets_battle_number = Some(battle_number);
+                self.input_packet_ticks.clear();
+            }
+        }
+        self.input_packet_ticks.insert(tick);
+        let last_tick = *self
+            .input_packet_ticks
+            .iter()
+            .next_back()
+            .expect("last tick");
+        self.input_packet_ticks = self
+            .input_packet_ticks
+            .split_off(&(last_tick.saturating_sub(PACKET_LOSS_WINDOW - 1)));
+    }
+
+    pub fn pong_received(&mut self, rtt: std::time::Duration) {
+        // Smoothed the same way as interarrival jitter in RFC 3550.
+        if let Some(last_rtt) = self.last_rtt {
+            let d = if rtt > last_rtt {
+                rtt - last_rtt
+            } else {
+                last_rtt - rtt
+            };
+            if d > self.jitter {
+                self.jitter += (d - self.jitter) / 16;
+            } else {
+                self.jitter -= (self.jitter - d) / 16;
+            }
+        }
+        self.last_rtt = Some(rtt);
+
+        while self.samples.len() >= MAX_RTT_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+
+    pub fn median_rtt(&self) -> Option<std::time::Duration> {
+        let mut samples = self.samples.iter().cloned().collect::<Vec<_>>();
+        samples.sort();
+        samples.get(samples.len() / 2).cloned()
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            rtt: self.median_rtt(),
+            jitter: self.jitter,
+            packet_loss: match (
+                self.input_packet_ticks.iter().next(),
+                self.input_packet_ticks.iter().next_back(),
+            ) {
+                (Some(first_tick), Some(last_tick)) => {
+                    1.0 - self.input_packet_ticks.len() as f32 / (last_tick - first_tick + 1) as f32
+                }
+                _ => 0.0,
+            },
+        }
+    }
+}
+
+/// What happened on a single frame of a battle.
+pub struct Frame {
+    pub tick: u32,
+    /// How many ticks past the last committed one we had to predict the remote's input for.
+    pub rollback_depth: usize,
+    pub fastforward_time: std::time::Duration,
+    /// Whether we had to run the frame without committing any input, because the remote's inputs hadn't arrived yet.
+    pub stalled: bool,
+    pub local_qlen: usize,
+    pub remote_qlen: usize,
+    pub tps_adjustment: i32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    pub frames: u64,
+    pub max_rollback_depth: usize,
+    total_rollback_depth: u64,
+    pub max_fastforward_time: std::time::Duration,
+    total_fastforward_time: std::time::Duration,
+    pub stalls: u64,
+}
+
+impl Summary {
+    pub fn mean_rollback_depth(&self) -> f32 {
+        if self.frames == 0 {
+            return 0.0;
+        }
+        self.total_rollback_depth as f32 / self.frames as f32
+    }
+
+    pub fn mean_fastforward_time(&self) -> std::time::Duration {
+        if self.frames == 0 {
+            return std::time::Duration::ZERO;
+        }
+        self.total_fastforward_time / self.frames as u32
+    }
+}
+
+/// Keeps a running summary of a battle's frames, and optionally writes every frame out as a CSV row.
+pub struct Recorder {
+    summary: Summary,
+    csv: Option<std::io::BufWriter<std::fs::File>>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self {
+            summary: Summary::default(),
+            csv: None,
+        }
+    }
+
+    pub fn with_csv(path: &std::path::Path) -> std::io::Result<Self> {
+        let mut csv = std::io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(
+            csv,
+            "tick,rtt_ms,jitter_ms,packet_loss,rollback_depth,fastforward_us,stalled,local_qlen,remote_qlen,tps_adjustment"
+        )?;
+        Ok(Self {
+            summary: Summary::default(),
+            csv: Some(csv),
+        })
+    }
+
+    pub fn record(&mut self, frame: &Frame, connection: &ConnectionStats) -> std::io::Result<()> {
+        self.summary.frames += 1;
+        self.summary.max_rollback_depth = self.summary.max_rollback_depth.max(frame.rollback_depth);
+        self.summary.total_rollback_depth += frame.rollback_depth as u64;
+        self.summary.max_fastforward_time = self
+            .summary
+            .max_fastforward_time
+            .max(frame.fastforward_time);
+        self.summary.total_fastforward_time += frame.fastforward_time;
+        if frame.stalled {
+            self.summary.stalls += 1;
+        }
+
+        if let Some(csv) = &mut self.csv {
+            writeln!(
+                csv,
+                "{},{},{:.3},{:.4},{},{},{},{},{},{}",
+                frame.tick,
+                connection
+                    .rtt
+                    .map(|rtt| format!("{:.3}", rtt.as_secs_f64() * 1000.0))
+                    .unwrap_or_default(),
+                connection.jitter.as_secs_f64() * 1000.0,
+                connection.packet_loss,
+                frame.rollback_depth,
+                frame.fastforward_time.as_micros(),
+                frame.stalled as u8,
+                frame.local_qlen,
+                frame.remote_qlen,
+                frame.tps_adjustment,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn summary(&self) -> &Summary {
+        &self.summary
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(csv) = &mut self.csv {
+            csv.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,59 +1,68 @@
-use crate::{datachannel, protocol};
+use crate::{config, protocol};
 
-pub struct Transport {
-    dc: std::sync::Arc<datachannel::DataChannel>,
+pub mod memory;
+pub mod tcp;
+pub mod udp;
+pub mod webrtc;
+
+/// The kinds of ICE candidates (host, srflx, prflx or relay) that ended up being used on each side.
+#[derive(Clone, Debug)]
+pub struct CandidatePair {
+    pub local: String,
+    pub remote: String,
 }
 
-impl Transport {
-    pub fn new(dc: std::sync::Arc<datachannel::DataChannel>) -> Self {
-        Self { dc }
+#[async_trait::async_trait]
+pub trait Transport: Send + Sync {
+    async fn send(&self, packet: &protocol::Packet) -> anyhow::Result<()>;
+    async fn receive(&self) -> anyhow::Result<Option<protocol::Packet>>;
+    async fn close(&self) -> anyhow::Result<()>;
+
+    /// Whether send_unreliable may lose or reorder packets.
+    fn is_unreliable(&self) -> bool {
+        false
     }
 
-    pub async fn send_init(
-        &self,
-        battle_number: u8,
-        input_delay: u32,
-        marshaled: &[u8],
-    ) -> anyhow::Result<()> {
-        self.dc
-            .send(
-                protocol::Packet::Init(protocol::Init {
-                    battle_number,
-                    input_delay,
-                    marshaled: marshaled.to_vec(),
-                })
-                .serialize()
-                .expect("serialize")
-                .as_slice(),
-            )
-            .await?;
-        Ok(())
+    /// Sends a packet without waiting on retransmits of anything sent before it, for transports that can.
+    async fn send_unreliable(&self, packet: &protocol::Packet) -> anyhow::Result<()> {
+        self.send(packet).await
     }
 
-    pub async fn send_input(
-        &self,
-        battle_number: u8,
-        local_tick: u32,
-        remote_tick: u32,
-        joyflags: u16,
-        custom_screen_state: u8,
-        turn: Vec<u8>,
-    ) -> anyhow::Result<()> {
-        self.dc
-            .send(
-                protocol::Packet::Input(protocol::Input {
-                    battle_number,
-                    local_tick,
-                    remote_tick,
-                    joyflags,
-                    custom_screen_state,
-                    turn,
-                })
-                .serialize()
-                .expect("serialize")
-                .as_slice(),
-            )
-            .await?;
-        Ok(())
+    /// Only meaningful for transports that go through ICE.
+    async fn candidate_pair(&self) -> Option<CandidatePair> {
+        None
+    }
+
+    /// What the matchmaking server gave us to host the session's spectators with, for transports that go through it.
+    fn spectator_host_token(&self) -> Option<String> {
+        None
+    }
+}
+
+#[async_trait::async_trait]
+pub trait Connector: Send + Sync {
+    async fn connect(&self, session_id: &str) -> anyhow::Result<std::sync::Arc<dyn Transport>>;
+}
+
+/// `advertisement` lists the session as an open room on the matchmaking server, if the transport goes through it.
+pub fn make_connector(
+    config: &config::Config,
+    advertisement: Option<tango_matchmaking::protocol::Advertisement>,
+) -> Box<dyn Connector> {
+    match &config.transport {
+        config::Transport::WebRTC => Box::new(webrtc::Connector::new(
+            config.matchmaking.connect_addr.clone(),
+            config.webrtc.clone(),
+            advertisement,
+        )),
+        config::Transport::Udp {
+            bind_addr,
+            peer_addr,
+        } => Box::new(udp::Connector::new(bind_addr.clone(), peer_addr.clone())),
+        config::Transport::Tcp {
+            bind_addr,
+            peer_addr,
+        } => Box::new(tcp::Connector::new(bind_addr.clone(), peer_addr.clone())),
+        config::Transport::Memory => Box::new(memory::Connector::new(memory::HUB.clone())),
     }
 }
@@ -0,0 +1,136 @@
+use crate::protocol;
+use byteorder::{ByteOrder, LittleEndian};
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+
+const MAX_FRAME_LENGTH: usize = 1024 * 1024;
+
+async fn write_frame(
+    w: &mut (impl tokio::io::AsyncWrite + Unpin),
+    buf: &[u8],
+) -> std::io::Result<()> {
+    let mut header = [0u8; 4];
+    LittleEndian::write_u32(&mut header, buf.len() as u32);
+    w.write_all(&header).await?;
+    w.write_all(buf).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+async fn read_frame(
+    r: &mut (impl tokio::io::AsyncRead + Unpin),
+) -> std::io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 4];
+    match r.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Ok(None);
+        }
+        Err(e) => {
+            return Err(e);
+        }
+    }
+    let len = LittleEndian::read_u32(&header) as usize;
+    if len > MAX_FRAME_LENGTH {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame too long: {} bytes", len),
+        ));
+    }
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf).await?;
+    Ok(Some(buf))
+}
+
+pub struct Transport {
+    reader: tokio::sync::Mutex<tokio::net::tcp::OwnedReadHalf>,
+    writer: tokio::sync::Mutex<tokio::net::tcp::OwnedWriteHalf>,
+}
+
+impl Transport {
+    pub async fn new(stream: tokio::net::TcpStream, session_id: &str) -> anyhow::Result<Self> {
+        stream.set_nodelay(true)?;
+        let (mut reader, mut writer) = stream.into_split();
+
+        write_frame(&mut writer, session_id.as_bytes()).await?;
+        let their_session_id = read_frame(&mut reader)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("connection closed during handshake"))?;
+        if their_session_id != session_id.as_bytes() {
+            anyhow::bail!("session id mismatch");
+        }
+
+        Ok(Self {
+            reader: tokio::sync::Mutex::new(reader),
+            writer: tokio::sync::Mutex::new(writer),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Transport for Transport {
+    async fn send(&self, packet: &protocol::Packet) -> anyhow::Result<()> {
+        write_frame(&mut *self.writer.lock().await, &packet.serialize()?).await?;
+        Ok(())
+    }
+
+    async fn receive(&self) -> anyhow::Result<Option<protocol::Packet>> {
+        Ok(match read_frame(&mut *self.reader.lock().await).await? {
+            Some(buf) => Some(protocol::Packet::deserialize(&buf)?),
+            None => None,
+        })
+    }
+
+    async fn close(&self) -> anyhow::Result<()> {
+        self.writer.lock().await.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Connects directly over TCP: if a peer address is given we dial it, otherwise we wait for the peer to dial us.
+pub struct Connector {
+    bind_addr: String,
+    peer_addr: Option<String>,
+}
+
+impl Connector {
+    pub fn new(bind_addr: String, peer_addr: Option<String>) -> Self {
+        Self {
+            bind_addr,
+            peer_addr,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Connector for Connector {
+    async fn connect(
+        &self,
+        session_id: &str,
+    ) -> anyhow::Result<std::sync::Arc<dyn super::Transport>> {
+        let stream = match &self.peer_addr {
+            Some(peer_addr) => loop {
+                match tokio::net::TcpStream::connect(peer_addr).await {
+                    Ok(stream) => break stream,
+                    Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                        log::info!("{} is not listening yet, retrying", peer_addr);
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    }
+                    Err(e) => {
+                        return Err(e.into());
+                    }
+                }
+            },
+            None => {
+                let listener = tokio::net::TcpListener::bind(&self.bind_addr).await?;
+                log::info!("waiting for peer on {}", listener.local_addr()?);
+                let (stream, addr) = listener.accept().await?;
+                log::info!("accepted peer from {}", addr);
+                stream
+            }
+        };
+        Ok(std::sync::Arc::new(
+            Transport::new(stream, session_id).await?,
+        ))
+    }
+}
@@ -0,0 +1,305 @@
+use crate::protocol;
+use byteorder::{ByteOrder, LittleEndian};
+
+const HEADER_LENGTH: usize = 5;
+const MAX_DATAGRAM_LENGTH: usize = 64 * 1024;
+const MAX_REORDER_BUFFER_LENGTH: usize = 1024;
+const HELLO_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+const RETRANSMIT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+const PEER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Hello = 0,
+    Data = 1,
+    Ack = 2,
+}
+
+impl Kind {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Kind::Hello),
+            1 => Some(Kind::Data),
+            2 => Some(Kind::Ack),
+            _ => None,
+        }
+    }
+}
+
+fn encode_datagram(kind: Kind, seq: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; HEADER_LENGTH + payload.len()];
+    buf[0] = kind as u8;
+    LittleEndian::write_u32(&mut buf[1..HEADER_LENGTH], seq);
+    buf[HEADER_LENGTH..].copy_from_slice(payload);
+    buf
+}
+
+fn decode_datagram(buf: &[u8]) -> Option<(Kind, u32, &[u8])> {
+    if buf.len() < HEADER_LENGTH {
+        return None;
+    }
+    Some((
+        Kind::from_u8(buf[0])?,
+        LittleEndian::read_u32(&buf[1..HEADER_LENGTH]),
+        &buf[HEADER_LENGTH..],
+    ))
+}
+
+struct SendState {
+    next_seq: u32,
+    unacked: std::collections::BTreeMap<u32, Vec<u8>>,
+}
+
+struct ReceiveState {
+    next_seq: u32,
+    reorder_buffer: std::collections::BTreeMap<u32, Vec<u8>>,
+    last_received_at: std::time::Instant,
+}
+
+/// A minimal reliable, ordered channel on top of UDP: every data datagram is sequenced and retransmitted until the peer cumulatively acks it.
+pub struct Transport {
+    socket: std::sync::Arc<tokio::net::UdpSocket>,
+    send_state: std::sync::Arc<parking_lot::Mutex<SendState>>,
+    receiver: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<protocol::Packet>>,
+    cancellation_token: tokio_util::sync::CancellationToken,
+}
+
+impl Transport {
+    async fn handshake(socket: &tokio::net::UdpSocket, session_id: &str) -> anyhow::Result<()> {
+        let hello = encode_datagram(Kind::Hello, 0, session_id.as_bytes());
+        let mut buf = vec![0u8; MAX_DATAGRAM_LENGTH];
+        loop {
+            // The peer might not be up yet, in which case we'll get ICMP port unreachable errors back: just keep trying.
+            let _ = socket.send(&hello).await;
+            let n = match tokio::time::timeout(HELLO_INTERVAL, socket.recv(&mut buf)).await {
+                Ok(Ok(n)) => n,
+                Ok(Err(_)) | Err(_) => {
+                    continue;
+                }
+            };
+            if let Some((Kind::Hello, _, their_session_id)) = decode_datagram(&buf[..n]) {
+                if their_session_id != session_id.as_bytes() {
+                    anyhow::bail!("session id mismatch");
+                }
+                socket.send(&hello).await?;
+                return Ok(());
+            }
+        }
+    }
+
+    pub async fn new(socket: tokio::net::UdpSocket, session_id: &str) -> anyhow::Result<Self> {
+        Self::handshake(&socket, session_id).await?;
+
+        let socket = std::sync::Arc::new(socket);
+        let send_state = std::sync::Arc::new(parking_lot::Mutex::new(SendState {
+            next_seq: 0,
+            unacked: std::collections::BTreeMap::new(),
+        }));
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let cancellation_token = tokio_util::sync::CancellationToken::new();
+
+        {
+            let socket = socket.clone();
+            let send_state = send_state.clone();
+            let cancellation_token = cancellation_token.clone();
+            let session_id = session_id.to_string();
+            tokio::task::spawn(async move {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => {},
+                    r = Self::run_receive(socket, send_state, sender, session_id) => {
+                        if let Err(e) = r {
+                            log::info!("udp receive loop ending: {:?}", e);
+                        }
+                    },
+                }
+            });
+        }
+
+        {
+            let socket = socket.clone();
+            let send_state = send_state.clone();
+            let cancellation_token = cancellation_token.clone();
+            tokio::task::spawn(async move {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => {},
+                    _ = Self::run_retransmit(socket, send_state) => {},
+                }
+            });
+        }
+
+        Ok(Self {
+            socket,
+            send_state,
+            receiver: tokio::sync::Mutex::new(receiver),
+            cancellation_token,
+        })
+    }
+
+    async fn run_receive(
+        socket: std::sync::Arc<tokio::net::UdpSocket>,
+        send_state: std::sync::Arc<parking_lot::Mutex<SendState>>,
+        sender: tokio::sync::mpsc::UnboundedSender<protocol::Packet>,
+        session_id: String,
+    ) -> anyhow::Result<()> {
+        let mut state = ReceiveState {
+            next_seq: 0,
+            reorder_buffer: std::collections::BTreeMap::new(),
+            last_received_at: std::time::Instant::now(),
+        };
+        let mut buf = vec![0u8; MAX_DATAGRAM_LENGTH];
+        loop {
+            let timeout = PEER_TIMEOUT
+                .checked_sub(state.last_received_at.elapsed())
+                .unwrap_or_default();
+            let n = match tokio::time::timeout(timeout, socket.recv(&mut buf)).await {
+                Ok(Ok(n)) => n,
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    continue;
+                }
+                Ok(Err(e)) => {
+                    return Err(e.into());
+                }
+                Err(_) => {
+                    anyhow::bail!("peer timed out");
+                }
+            };
+            state.last_received_at = std::time::Instant::now();
+
+            let (kind, seq, payload) = match decode_datagram(&buf[..n]) {
+                Some(d) => d,
+                None => {
+                    log::warn!("dropping malformed datagram");
+                    continue;
+                }
+            };
+
+            match kind {
+                Kind::Hello => {
+                    // Our final hello may have been lost, so the peer is still waiting on one.
+                    socket
+                        .send(&encode_datagram(Kind::Hello, 0, session_id.as_bytes()))
+                        .await?;
+                }
+                Kind::Ack => {
+                    let mut send_state = send_state.lock();
+                    send_state.unacked = send_state.unacked.split_off(&seq);
+                }
+                Kind::Data => {
+                    // The next one in order is taken even if the buffer is full, since nothing else can be delivered until it is.
+                    if seq == state.next_seq
+                        || (seq > state.next_seq
+                            && state.reorder_buffer.len() < MAX_REORDER_BUFFER_LENGTH)
+                    {
+                        state.reorder_buffer.insert(seq, payload.to_vec());
+                    }
+                    while let Some(payload) = state.reorder_buffer.remove(&state.next_seq) {
+                        let seq = state.next_seq;
+                        state.next_seq += 1;
+                        let packet = match protocol::Packet::deserialize(&payload) {
+                            Ok(packet) => packet,
+                            Err(e) => {
+                                log::warn!("dropping malformed packet {}: {}", seq, e);
+                                continue;
+                            }
+                        };
+                        if sender.send(packet).is_err() {
+                            return Ok(());
+                        }
+                    }
+                    socket
+                        .send(&encode_datagram(Kind::Ack, state.next_seq, &[]))
+                        .await?;
+                }
+            }
+        }
+    }
+
+    async fn run_retransmit(
+        socket: std::sync::Arc<tokio::net::UdpSocket>,
+        send_state: std::sync::Arc<parking_lot::Mutex<SendState>>,
+    ) {
+        let mut interval = tokio::time::interval(RETRANSMIT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let datagrams = send_state
+                .lock()
+                .unacked
+                .iter()
+                .map(|(seq, payload)| encode_datagram(Kind::Data, *seq, payload))
+                .collect::<Vec<_>>();
+            for datagram in datagrams {
+                let _ = socket.send(&datagram).await;
+            }
+        }
+    }
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        self.cancellation_token.cancel();
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Transport for Transport {
+    async fn send(&self, packet: &protocol::Packet) -> anyhow::Result<()> {
+        let payload = packet.serialize()?;
+        if payload.len() + HEADER_LENGTH > MAX_DATAGRAM_LENGTH {
+            anyhow::bail!("packet too large: {} bytes", payload.len());
+        }
+        let seq = {
+            let mut send_state = self.send_state.lock();
+            let seq = send_state.next_seq;
+            send_state.next_seq += 1;
+            send_state.unacked.insert(seq, payload.clone());
+            seq
+        };
+        self.socket
+            .send(&encode_datagram(Kind::Data, seq, &payload))
+            .await?;
+        Ok(())
+    }
+
+    async fn receive(&self) -> anyhow::Result<Option<protocol::Packet>> {
+        Ok(self.receiver.lock().await.recv().await)
+    }
+
+    async fn close(&self) -> anyhow::Result<()> {
+        self.cancellation_token.cancel();
+        Ok(())
+    }
+}
+
+pub struct Connector {
+    bind_addr: String,
+    peer_addr: String,
+}
+
+impl Connector {
+    pub fn new(bind_addr: String, peer_addr: String) -> Self {
+        Self {
+            bind_addr,
+            peer_addr,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Connector for Connector {
+    async fn connect(
+        &self,
+        session_id: &str,
+    ) -> anyhow::Result<std::sync::Arc<dyn super::Transport>> {
+        let socket = tokio::net::UdpSocket::bind(&self.bind_addr).await?;
+        socket.connect(&self.peer_addr).await?;
+        log::info!(
+            "waiting for peer {} on {}",
+            self.peer_addr,
+            socket.local_addr()?
+        );
+        Ok(std::sync::Arc::new(
+            Transport::new(socket, session_id).await?,
+        ))
+    }
+}
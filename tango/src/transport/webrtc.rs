@@ -0,0 +1,202 @@
+use crate::{config, datachannel, protocol};
+
+pub struct Transport {
+    peer_conn: ::webrtc::peer_connection::RTCPeerConnection,
+    dc: std::sync::Arc<datachannel::DataChannel>,
+    unreliable_dc: Option<std::sync::Arc<datachannel::DataChannel>>,
+    send_unreliably: bool,
+    spectator_host_token: Option<String>,
+}
+
+impl Transport {
+    pub fn new(
+        peer_conn: ::webrtc::peer_connection::RTCPeerConnection,
+        dc: std::sync::Arc<datachannel::DataChannel>,
+    ) -> Self {
+        Self {
+            peer_conn,
+            dc,
+            unreliable_dc: None,
+            send_unreliably: false,
+            spectator_host_token: None,
+        }
+    }
+}
+
+/// Creates a peer connection with the negotiated data channel both ends of a tango connection expect.
+pub async fn new_peer_connection(
+    api: &::webrtc::api::API,
+    webrtc: &config::WebRTC,
+) -> anyhow::Result<(
+    ::webrtc::peer_connection::RTCPeerConnection,
+    std::sync::Arc<::webrtc::data_channel::RTCDataChannel>,
+)> {
+    let peer_conn = api.new_peer_connection(webrtc.make_webrtc_config()).await?;
+    let dc = peer_conn
+        .create_data_channel(
+            "tango",
+            Some(
+                ::webrtc::data_channel::data_channel_init::RTCDataChannelInit {
+                    id: Some(1),
+                    negotiated: Some(true),
+                    ordered: Some(true),
+                    ..Default::default()
+                },
+            ),
+        )
+        .await?;
+    Ok((peer_conn, dc))
+}
+
+/// Both ends always create this channel and listen on it, so it doesn't matter whether the other end is configured to send on it.
+async fn create_unreliable_data_channel(
+    peer_conn: &::webrtc::peer_connection::RTCPeerConnection,
+) -> anyhow::Result<std::sync::Arc<::webrtc::data_channel::RTCDataChannel>> {
+    Ok(peer_conn
+        .create_data_channel(
+            "tango-unreliable",
+            Some(
+                ::webrtc::data_channel::data_channel_init::RTCDataChannelInit {
+                    id: Some(2),
+                    negotiated: Some(true),
+                    ordered: Some(false),
+                    max_retransmits: Some(0),
+                    ..Default::default()
+                },
+            ),
+        )
+        .await?)
+}
+
+#[async_trait::async_trait]
+impl super::Transport for Transport {
+    async fn send(&self, packet: &protocol::Packet) -> anyhow::Result<()> {
+        self.dc.send(packet.serialize()?.as_slice()).await?;
+        Ok(())
+    }
+
+    async fn receive(&self) -> anyhow::Result<Option<protocol::Packet>> {
+        let buf = match &self.unreliable_dc {
+            Some(unreliable_dc) => tokio::select! {
+                buf = self.dc.receive() => buf,
+                Some(buf) = unreliable_dc.receive() => Some(buf),
+            },
+            None => self.dc.receive().await,
+        };
+        Ok(match buf {
+            Some(buf) => Some(protocol::Packet::deserialize(buf.as_slice())?),
+            None => None,
+        })
+    }
+
+    async fn close(&self) -> anyhow::Result<()> {
+        self.dc.close().await?;
+        if let Some(unreliable_dc) = &self.unreliable_dc {
+            unreliable_dc.close().await?;
+        }
+        self.peer_conn.close().await?;
+        Ok(())
+    }
+
+    fn spectator_host_token(&self) -> Option<String> {
+        self.spectator_host_token.clone()
+    }
+
+    async fn candidate_pair(&self) -> Option<super::CandidatePair> {
+        let reports = self.peer_conn.get_stats().await.reports;
+        let pair = reports.values().find_map(|report| match report {
+            ::webrtc::stats::StatsReportType::CandidatePair(pair) if pair.nominated => Some(pair),
+            _ => None,
+        })?;
+        let candidate_type = |id: &str| match reports.get(id)? {
+            ::webrtc::stats::StatsReportType::LocalCandidate(candidate)
+            | ::webrtc::stats::StatsReportType::RemoteCandidate(candidate) => {
+                Some(candidate.candidate_type.to_string())
+            }
+            _ => None,
+        };
+        Some(super::CandidatePair {
+            local: candidate_type(&pair.local_candidate_id)?,
+            remote: candidate_type(&pair.remote_candidate_id)?,
+        })
+    }
+
+    fn is_unreliable(&self) -> bool {
+        self.send_unreliably && self.unreliable_dc.is_some()
+    }
+
+    async fn send_unreliable(&self, packet: &protocol::Packet) -> anyhow::Result<()> {
+        match &self.unreliable_dc {
+            Some(unreliable_dc) if self.send_unreliably => {
+                unreliable_dc.send(packet.serialize()?.as_slice()).await?;
+                Ok(())
+            }
+            _ => self.send(packet).await,
+        }
+    }
+}
+
+pub struct Connector {
+    matchmaking_connect_addr: String,
+    webrtc: config::WebRTC,
+    advertisement: Option<tango_matchmaking::protocol::Advertisement>,
+}
+
+impl Connector {
+    pub fn new(
+        matchmaking_connect_addr: String,
+        webrtc: config::WebRTC,
+        advertisement: Option<tango_matchmaking::protocol::Advertisement>,
+    ) -> Self {
+        Self {
+            matchmaking_connect_addr,
+            webrtc,
+            advertisement,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Connector for Connector {
+    async fn connect(
+        &self,
+        session_id: &str,
+    ) -> anyhow::Result<std::sync::Arc<dyn super::Transport>> {
+        let api = ::webrtc::api::APIBuilder::new().build();
+        let (peer_conn, (dc, unreliable_dc), _, spectator_host_token) =
+            tango_matchmaking::client::connect(
+                &self.matchmaking_connect_addr,
+                || async {
+                    let (peer_conn, dc) = new_peer_connection(&api, &self.webrtc).await?;
+                    let unreliable_dc = create_unreliable_data_channel(&peer_conn).await?;
+                    Ok((peer_conn, (dc, unreliable_dc)))
+                },
+                session_id,
+                self.advertisement.clone(),
+            )
+            .await?;
+        let dc = datachannel::DataChannel::new(dc).await;
+        let unreliable_dc = datachannel::DataChannel::new(unreliable_dc).await;
+
+        log::info!(
+            "local sdp: {}",
+            peer_conn.local_description().await.expect("local sdp").sdp
+        );
+        log::info!(
+            "remote sdp: {}",
+            peer_conn
+                .remote_description()
+                .await
+                .expect("remote sdp")
+                .sdp
+        );
+
+        Ok(std::sync::Arc::new(Transport {
+            peer_conn,
+            dc,
+            unreliable_dc: Some(unreliable_dc),
+            send_unreliably: self.webrtc.unreliable_inputs,
+            spectator_host_token: Some(spectator_host_token),
+        }))
+    }
+}